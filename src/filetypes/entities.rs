@@ -0,0 +1,45 @@
+use regex::{Captures, Regex};
+
+/// Escapes the characters of `text` that are markup in HTML and XML, and the double quotes too if
+/// it is the value of an attribute.
+pub(crate) fn escape(text: &str, in_attribute: bool) -> String {
+    let text = text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    if in_attribute {
+        text.replace('"', "&quot;")
+    } else {
+        text
+    }
+}
+
+/// Replaces the character references in `text` with the characters they stand for: the numeric
+/// ones, those of the characters XML escapes and `&nbsp;`, which translation services returning
+/// HTML use too. Other references are left as they are.
+pub(crate) fn unescape(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    let reference = Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|amp|lt|gt|quot|apos|nbsp);").unwrap();
+
+    reference
+        .replace_all(text, |captures: &Captures| {
+            let name = &captures[1];
+            let character = match name {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                _ => match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => name[1..].parse().ok(),
+                }
+                .and_then(char::from_u32),
+            };
+            character.map_or_else(|| captures[0].to_string(), String::from)
+        })
+        .into_owned()
+}
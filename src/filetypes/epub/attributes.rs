@@ -0,0 +1,109 @@
+use std::{collections::HashSet, future::Future, ops::Range, sync::Arc};
+
+use eyre::Result;
+use regex::Regex;
+use tl::{Node, NodeHandle, Parser, ParserOptions};
+
+use super::{edit_snippets, escape, segments, unescape};
+use crate::options::{RequestOptions, SkipList};
+
+/// An attribute of an element whose value is text to translate, such as the `alt` text of an image
+struct Attribute {
+    /// Where the value is in the document, along with its quotes
+    range: Range<usize>,
+    value: String,
+}
+
+/// Translates the attributes named `names` of the elements of `html`, such as the `alt` text of
+/// images, and returns the document with their values replaced by their translations. The
+/// attributes of the elements `skip` matches, and of the elements within them, are left as they
+/// are.
+pub(super) async fn translate_attributes<F, Fut>(
+    html: &str,
+    names: &[String],
+    skip: &SkipList,
+    request_options: &RequestOptions,
+    edit_func: &Arc<F>,
+) -> Result<String>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let patterns: Vec<Regex> = names
+        .iter()
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            Regex::new(&format!(
+                r#"(?i)\s{}\s*=\s*("[^"]*"|'[^']*'|[^\s"'=<>`]+)"#,
+                regex::escape(name)
+            ))
+            .unwrap()
+        })
+        .collect();
+    if patterns.is_empty() {
+        return Ok(html.to_string());
+    }
+
+    let dom = tl::parse(html, ParserOptions::default())?;
+    let skipped = segments::skipped_elements(&dom, skip)?;
+    let mut attributes = vec![];
+    for &handle in dom.children() {
+        collect(handle, dom.parser(), &skipped, &patterns, &mut attributes);
+    }
+    attributes.sort_by_key(|attribute| attribute.range.start);
+
+    let values = attributes
+        .iter()
+        .map(|attribute| attribute.value.clone())
+        .collect();
+    let translations = edit_snippets(request_options, edit_func, values).await?;
+
+    // From the end of the document, so that the positions of the values before stay the same
+    let mut edited_html = html.to_string();
+    for (attribute, translation) in attributes.iter().zip(translations).rev() {
+        edited_html.replace_range(
+            attribute.range.clone(),
+            &format!(r#""{}""#, escape(&translation, true)),
+        );
+    }
+
+    Ok(edited_html)
+}
+
+fn collect(
+    handle: NodeHandle,
+    parser: &Parser,
+    skipped: &HashSet<NodeHandle>,
+    patterns: &[Regex],
+    attributes: &mut Vec<Attribute>,
+) {
+    let Some(tag) = handle.get(parser).and_then(Node::as_tag) else {
+        return;
+    };
+    if skipped.contains(&handle) {
+        return;
+    }
+
+    let (offset, _) = tag.boundaries(parser);
+    let raw = tag.raw().as_utf8_str();
+    let start_tag = segments::start_tag(&raw);
+    for pattern in patterns {
+        let Some(value) = pattern
+            .captures(start_tag)
+            .and_then(|captures| captures.get(1))
+        else {
+            continue;
+        };
+        let text = unescape(value.as_str().trim_matches(|c| c == '"' || c == '\''));
+        if !text.trim().is_empty() {
+            attributes.push(Attribute {
+                range: offset + value.start()..offset + value.end(),
+                value: text,
+            });
+        }
+    }
+
+    for &child in tag.children().top().iter() {
+        collect(child, parser, skipped, patterns, attributes);
+    }
+}
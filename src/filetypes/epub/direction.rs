@@ -0,0 +1,105 @@
+use regex::Regex;
+
+use super::escape;
+
+/// Languages written from right to left, by ISO 639 code
+const RTL_LANGUAGES: [&str; 16] = [
+    "ar", "arc", "ckb", "dv", "fa", "he", "iw", "ji", "ks", "nqo", "ps", "sd", "syr", "ug", "ur",
+    "yi",
+];
+
+/// Scripts written from right to left, by ISO 15924 code
+const RTL_SCRIPTS: [&str; 7] = ["adlm", "arab", "hebr", "nkoo", "rohg", "syrc", "thaa"];
+
+/// Styles added to translated documents in right-to-left languages, which align text to the right
+/// where it is only aligned to the left by default. They are wrapped in `:where()`, which gives
+/// them no specificity, so that every rule of the book's own stylesheets, such as one centering
+/// epigraphs, still takes precedence over them.
+const RTL_STYLE: &str = r#"<style type="text/css">
+  :where(html[dir="rtl"]) body { direction: rtl; }
+  :where(html[dir="rtl"] :is(p, li, dd, dt, blockquote, td, th)) { text-align: start; }
+</style>"#;
+
+/// Whether text in `language`, given as a tag such as `ar` or `az-Arab`, is written from right to
+/// left. A script in the tag decides it over the language.
+pub(super) fn is_rtl(language: &str) -> bool {
+    let language = language.to_ascii_lowercase();
+    let mut subtags = language.split(['-', '_']);
+    let primary = subtags.next().unwrap_or_default();
+
+    match subtags.find(|subtag| subtag.len() == 4 && subtag.chars().all(char::is_alphabetic)) {
+        Some(script) => RTL_SCRIPTS.contains(&script),
+        None => RTL_LANGUAGES.contains(&primary),
+    }
+}
+
+/// Declares `language` as the language of the XHTML document `html` on its `<html>` element, along
+/// with the direction it is written in. The direction of the `<body>` is updated too if the source
+/// gives it one.
+pub(super) fn set_document_language(html: &str, language: &str) -> String {
+    let direction = if is_rtl(language) { "rtl" } else { "ltr" };
+    let html_tag = Regex::new(r"(?i)<html\b[^>]*>").unwrap();
+    let body_tag = Regex::new(r"(?i)<body\b[^>]*>").unwrap();
+
+    let html = html_tag.replace(html, |captures: &regex::Captures| {
+        let tag = set_attribute(&captures[0], "lang", language, true);
+        let tag = set_attribute(&tag, "xml:lang", language, true);
+        set_attribute(&tag, "dir", direction, direction == "rtl")
+    });
+    body_tag
+        .replace(&html, |captures: &regex::Captures| {
+            set_attribute(&captures[0], "dir", direction, false)
+        })
+        .into_owned()
+}
+
+/// Adds styles that align the text of the XHTML document `html` for a right-to-left language to
+/// its head.
+pub(super) fn add_rtl_style(html: &str) -> String {
+    match html.to_ascii_lowercase().find("</head>") {
+        Some(end) => format!("{}{RTL_STYLE}\n{}", &html[..end], &html[end..]),
+        None => html.to_string(),
+    }
+}
+
+/// Sets the direction pages are turned in on the spine of the package document `opf`, for a book
+/// written in `language`.
+pub(super) fn set_page_progression(opf: &str, language: &str) -> String {
+    let direction = if is_rtl(language) { "rtl" } else { "ltr" };
+    let spine_tag = Regex::new(r"<(?:[\w-]+:)?spine\b[^>]*>").unwrap();
+
+    spine_tag
+        .replace(opf, |captures: &regex::Captures| {
+            set_attribute(
+                &captures[0],
+                "page-progression-direction",
+                direction,
+                direction == "rtl",
+            )
+        })
+        .into_owned()
+}
+
+/// Sets the attribute `name` of the start tag `tag` to `value`, adding it if `add` is set and the
+/// tag doesn't have it yet.
+fn set_attribute(tag: &str, name: &str, value: &str, add: bool) -> String {
+    let pattern = Regex::new(&format!(
+        r#"(\s{}\s*=\s*)("[^"]*"|'[^']*'|[^\s"'=<>`/]+)"#,
+        regex::escape(name)
+    ))
+    .unwrap();
+    let value = format!(r#""{}""#, escape(value, true));
+
+    if pattern.is_match(tag) {
+        pattern
+            .replace(tag, |captures: &regex::Captures| {
+                format!("{}{value}", &captures[1])
+            })
+            .into_owned()
+    } else if add {
+        let end = tag.len() - if tag.ends_with("/>") { 2 } else { 1 };
+        format!("{} {name}={value}{}", tag[..end].trim_end(), &tag[end..])
+    } else {
+        tag.to_string()
+    }
+}
@@ -0,0 +1,531 @@
+use std::{future::Future, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use eyre::{eyre, Result};
+use md5::{Digest, Md5};
+use rand::Rng;
+use regex::Regex;
+use xml::{name::OwnedName, reader::XmlEvent, EventReader};
+
+use super::{edit_snippets, escape};
+use crate::options::{EpubOptions, RequestOptions};
+
+const DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+const OPF_NAMESPACE: &str = "http://www.idpf.org/2007/opf";
+
+/// Dublin Core elements whose values are prose rather than names, dates or identifiers
+const TRANSLATED_ELEMENTS: [&str; 2] = ["title", "description"];
+
+/// The `<metadata>` of a package document, with every Dublin Core element and `meta` entry of the
+/// source in order
+#[derive(Debug)]
+pub(super) struct Metadata {
+    /// The prefix of the source's `metadata` element, which packages that don't declare the OPF
+    /// namespace as the default one write their elements with
+    prefix: Option<String>,
+    /// The namespaces the entries and their attributes are written with, by prefix
+    namespaces: Vec<(String, String)>,
+    entries: Vec<Entry>,
+    /// The id of the entry holding the unique identifier of the book
+    unique_identifier: Option<String>,
+    /// Whether the package is EPUB 3, which refines entries with `meta` elements rather than with
+    /// attributes
+    is_epub3: bool,
+}
+
+/// Reads the metadata of the package document `opf` and makes it that of the translation: written
+/// in the target language, with an identifier of its own and the translation engine as a
+/// translator, and with the title and description translated if `epub_options` asks for it.
+pub(super) async fn edit_metadata<F, Fut>(
+    opf: &str,
+    epub_options: &EpubOptions,
+    request_options: &RequestOptions,
+    edit_func: &Arc<F>,
+) -> Result<Metadata>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let mut metadata = Metadata::read(opf)?;
+    let language = epub_options.language.as_deref();
+
+    if epub_options.translate_metadata {
+        metadata
+            .translate(language, request_options, edit_func)
+            .await?;
+    }
+    if let Some(language) = language {
+        metadata.set_language(language);
+    }
+    metadata.derive_identifier(language.unwrap_or_default());
+    if let Some(translator) = &epub_options.translator {
+        metadata.add_translator(translator);
+    }
+
+    Ok(metadata)
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    name: OwnedName,
+    attributes: Vec<(String, String)>,
+    value: String,
+}
+
+impl Entry {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn set_attribute(&mut self, name: &str, value: &str) {
+        match self
+            .attributes
+            .iter_mut()
+            .find(|(attribute, _)| attribute == name)
+        {
+            Some((_, current)) => *current = value.to_string(),
+            None => self.attributes.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    fn is_dc(&self, name: &str) -> bool {
+        self.name.namespace.as_deref() == Some(DC_NAMESPACE) && self.name.local_name == name
+    }
+
+    /// Whether the entry is a `meta` element that refines the entry with the id `id`.
+    fn refines(&self, id: &str) -> bool {
+        self.name.local_name == "meta"
+            && self
+                .attribute("refines")
+                .is_some_and(|refines| refines.strip_prefix('#') == Some(id))
+    }
+
+    fn to_xml(&self) -> String {
+        let name = self.name.borrow().to_repr();
+        let attributes: String = self
+            .attributes
+            .iter()
+            .map(|(attribute, value)| format!(r#" {attribute}="{}""#, escape(value, true)))
+            .collect();
+
+        if self.value.is_empty() {
+            format!("<{name}{attributes}/>")
+        } else {
+            format!(
+                "<{name}{attributes}>{}</{name}>",
+                escape(&self.value, false)
+            )
+        }
+    }
+}
+
+impl Metadata {
+    /// Reads the metadata of the package document `opf`.
+    pub(super) fn read(opf: &str) -> Result<Self> {
+        let mut metadata = Self {
+            prefix: None,
+            namespaces: vec![],
+            entries: vec![],
+            unique_identifier: None,
+            is_epub3: false,
+        };
+        // The entries being read, innermost last, and whether they hold other elements, which
+        // older packages group Dublin Core elements in
+        let mut open: Vec<(Entry, bool)> = vec![];
+        let mut in_metadata = false;
+
+        for event in EventReader::new(opf.as_bytes()) {
+            match event? {
+                XmlEvent::StartElement {
+                    name,
+                    attributes,
+                    namespace,
+                } => {
+                    if in_metadata {
+                        let mut name = name;
+                        // Dublin Core elements are always written with a prefix
+                        if name.namespace.as_deref() == Some(DC_NAMESPACE) && name.prefix.is_none()
+                        {
+                            name.prefix = Some("dc".to_string());
+                        }
+                        if let Some((_, has_children)) = open.last_mut() {
+                            *has_children = true;
+                        }
+                        let attributes = attributes
+                            .iter()
+                            .map(|attribute| {
+                                (attribute.name.borrow().to_repr(), attribute.value.clone())
+                            })
+                            .collect();
+                        open.push((
+                            Entry {
+                                name,
+                                attributes,
+                                value: String::new(),
+                            },
+                            false,
+                        ));
+                    } else if name.local_name == "package" {
+                        for attribute in attributes {
+                            match attribute.name.local_name.as_str() {
+                                "unique-identifier" => {
+                                    metadata.unique_identifier = Some(attribute.value)
+                                }
+                                "version" => metadata.is_epub3 = attribute.value.starts_with('3'),
+                                _ => {}
+                            }
+                        }
+                    } else if name.local_name == "metadata" {
+                        in_metadata = true;
+                        metadata.prefix = name.prefix;
+                        metadata.namespaces = namespace
+                            .into_iter()
+                            .filter(|(prefix, _)| !matches!(*prefix, "" | "xml" | "xmlns"))
+                            .map(|(prefix, uri)| (prefix.to_string(), uri.to_string()))
+                            .collect();
+                    }
+                }
+                XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                    if let Some((entry, _)) = open.last_mut() {
+                        entry.value.push_str(&text);
+                    }
+                }
+                XmlEvent::EndElement { .. } if in_metadata => match open.pop() {
+                    Some((mut entry, false)) => {
+                        entry.value = entry.value.trim().to_string();
+                        metadata.entries.push(entry);
+                    }
+                    Some((_, true)) => {}
+                    None => break,
+                },
+                _ => {}
+            }
+        }
+
+        if !in_metadata {
+            return Err(eyre!("The package document has no metadata"));
+        }
+
+        Ok(metadata)
+    }
+
+    pub(super) fn is_epub3(&self) -> bool {
+        self.is_epub3
+    }
+
+    /// The language of the book.
+    pub(super) fn language(&self) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|entry| entry.is_dc("language"))
+            .map(|entry| entry.value.as_str())
+    }
+
+    /// The unique identifier of the book.
+    pub(super) fn identifier(&self) -> Option<&str> {
+        let id = self.unique_identifier.as_deref()?;
+        self.entries
+            .iter()
+            .find(|entry| entry.is_dc("identifier") && entry.attribute("id") == Some(id))
+            .map(|entry| entry.value.as_str())
+    }
+
+    /// The main title of the book.
+    pub(super) fn title(&self) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|entry| entry.is_dc("title"))
+            .map(|entry| entry.value.as_str())
+    }
+
+    /// Translates the titles and descriptions of the book, marking them as written in `language`
+    /// where the source gives their language.
+    async fn translate<F, Fut>(
+        &mut self,
+        language: Option<&str>,
+        request_options: &RequestOptions,
+        edit_func: &Arc<F>,
+    ) -> Result<()>
+    where
+        F: Fn(Vec<String>) -> Fut,
+        Fut: Future<Output = Result<Vec<String>>>,
+    {
+        let entries: Vec<&mut Entry> = self
+            .entries
+            .iter_mut()
+            .filter(|entry| {
+                TRANSLATED_ELEMENTS.iter().any(|name| entry.is_dc(name))
+                    && !entry.value.trim().is_empty()
+            })
+            .collect();
+
+        let values = entries.iter().map(|entry| entry.value.clone()).collect();
+        let values = edit_snippets(request_options, edit_func, values).await?;
+        for (entry, value) in entries.into_iter().zip(values) {
+            entry.value = value;
+            if let Some(language) = language.filter(|_| entry.attribute("xml:lang").is_some()) {
+                entry.set_attribute("xml:lang", language);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Declares `language` as the only language of the book.
+    fn set_language(&mut self, language: &str) {
+        let position = self
+            .entries
+            .iter()
+            .position(|entry| entry.is_dc("language"));
+        self.entries.retain(|entry| !entry.is_dc("language"));
+        self.entries.insert(
+            position.unwrap_or(self.entries.len()),
+            Entry {
+                name: self.dc_name("language"),
+                attributes: vec![],
+                value: language.to_string(),
+            },
+        );
+    }
+
+    /// Gives the translation an identifier of its own, derived from the source's unique identifier
+    /// and `language`, so that translating the same book into the same language again gives the
+    /// same identifier. The identifiers of the source, such as the ISBN of its print edition, are
+    /// kept as sources of the translation rather than as identifiers of it.
+    fn derive_identifier(&mut self, language: &str) {
+        let id = self
+            .unique_identifier
+            .clone()
+            .unwrap_or_else(|| "uid".to_string());
+        let (mut sources, entries): (Vec<Entry>, Vec<Entry>) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|entry| entry.is_dc("identifier"));
+        self.entries = entries;
+        if let Some(index) = sources
+            .iter()
+            .position(|source| source.attribute("id") == Some(&id))
+        {
+            let unique = sources.remove(index);
+            sources.insert(0, unique);
+        }
+
+        let identifier = match sources.first() {
+            Some(source) => derived_uuid(&source.value, language),
+            None => random_uuid(),
+        };
+        // Refinements such as the identifiers' schemes described the source's identifiers
+        let source_ids: Vec<&str> = sources
+            .iter()
+            .filter_map(|source| source.attribute("id"))
+            .chain([id.as_str()])
+            .collect();
+        self.entries
+            .retain(|entry| !source_ids.iter().any(|source_id| entry.refines(source_id)));
+        self.entries.insert(
+            0,
+            Entry {
+                name: self.dc_name("identifier"),
+                attributes: vec![("id".to_string(), id.clone())],
+                value: format!("urn:uuid:{identifier}"),
+            },
+        );
+        for source in sources {
+            self.entries.push(Entry {
+                name: self.dc_name("source"),
+                attributes: vec![],
+                value: source.value,
+            });
+        }
+        self.unique_identifier = Some(id);
+    }
+
+    /// Records `translator` as a contributor with the role of translator.
+    fn add_translator(&mut self, translator: &str) {
+        let mut contributor = Entry {
+            name: self.dc_name("contributor"),
+            attributes: vec![],
+            value: translator.to_string(),
+        };
+
+        if self.is_epub3 {
+            let id = (1..)
+                .map(|n| match n {
+                    1 => "translator".to_string(),
+                    n => format!("translator-{n}"),
+                })
+                .find(|id| {
+                    self.entries
+                        .iter()
+                        .all(|entry| entry.attribute("id") != Some(id))
+                })
+                .unwrap_or_default();
+            contributor.set_attribute("id", &id);
+            self.entries.push(contributor);
+            self.entries.push(Entry {
+                name: self.opf_name("meta"),
+                attributes: vec![
+                    ("refines".to_string(), format!("#{id}")),
+                    ("property".to_string(), "role".to_string()),
+                    ("scheme".to_string(), "marc:relators".to_string()),
+                ],
+                value: "trl".to_string(),
+            });
+        } else {
+            let prefix = self.prefix(OPF_NAMESPACE, "opf");
+            contributor.set_attribute(&format!("{prefix}:role"), "trl");
+            self.entries.push(contributor);
+        }
+    }
+
+    /// Sets the date the book was last modified, which EPUB 3 requires.
+    pub(super) fn set_modified(&mut self, modified: DateTime<Utc>) {
+        if !self.is_epub3 {
+            return;
+        }
+
+        let value = modified.format("%Y-%m-%dT%H:%M:%SZ").to_string();
+        match self.entries.iter_mut().find(|entry| {
+            entry.name.local_name == "meta"
+                && entry.attribute("property") == Some("dcterms:modified")
+                && entry.attribute("refines").is_none()
+        }) {
+            Some(entry) => entry.value = value,
+            None => self.entries.push(Entry {
+                name: self.opf_name("meta"),
+                attributes: vec![("property".to_string(), "dcterms:modified".to_string())],
+                value,
+            }),
+        }
+    }
+
+    /// Replaces the metadata of the package document `opf` with this one. The package keeps its
+    /// own id for the unique identifier and its own cover entry, which refer to its manifest.
+    pub(super) fn replace_in(&self, opf: &str) -> Result<String> {
+        let metadata_element =
+            Regex::new(r"(?s)<(?:[\w-]+:)?metadata\b[^>]*(?:/>|>.*?</(?:[\w-]+:)?metadata\s*>)")
+                .unwrap();
+        let unique_identifier =
+            Regex::new(r#"<(?:[\w-]+:)?package\b[^>]*\bunique-identifier\s*=\s*["']([^"']*)["']"#)
+                .unwrap();
+        let cover =
+            Regex::new(r#"<(?:[\w-]+:)?meta\b[^>]*\bname\s*=\s*["']cover["'][^>]*/>"#).unwrap();
+
+        let current = metadata_element
+            .find(opf)
+            .ok_or_else(|| eyre!("The package document has no metadata"))?;
+        let unique_identifier = unique_identifier
+            .captures(opf)
+            .and_then(|captures| captures.get(1))
+            .map(|id| id.as_str());
+        let cover = cover.find(current.as_str()).map(|cover| cover.as_str());
+
+        Ok(format!(
+            "{}{}{}",
+            &opf[..current.start()],
+            self.to_xml(unique_identifier, cover),
+            &opf[current.end()..]
+        ))
+    }
+
+    fn to_xml(&self, unique_identifier: Option<&str>, cover: Option<&str>) -> String {
+        let mut namespaces = self.namespaces.clone();
+        for (prefix, uri) in [("dc", DC_NAMESPACE), ("opf", OPF_NAMESPACE)] {
+            if namespaces.iter().all(|(_, declared)| declared != uri) {
+                namespaces.push((prefix.to_string(), uri.to_string()));
+            }
+        }
+        let declarations: Vec<String> = namespaces
+            .iter()
+            .map(|(prefix, uri)| format!(r#"xmlns:{prefix}="{}""#, escape(uri, true)))
+            .collect();
+
+        let name = self.opf_name("metadata").borrow().to_repr();
+        let mut xml = format!("<{name} {}>\n", declarations.join(" "));
+        for entry in &self.entries {
+            if cover.is_some() && entry.attribute("name") == Some("cover") {
+                continue;
+            }
+            let is_unique_identifier = entry.attribute("id").is_some()
+                && entry.attribute("id") == self.unique_identifier.as_deref();
+            let line = match unique_identifier {
+                Some(id) if is_unique_identifier => {
+                    let mut entry = entry.clone();
+                    entry.set_attribute("id", id);
+                    entry.to_xml()
+                }
+                _ => entry.to_xml(),
+            };
+            xml.push_str(&format!("    {line}\n"));
+        }
+        if let Some(cover) = cover {
+            xml.push_str(&format!("    {cover}\n"));
+        }
+        xml.push_str(&format!("  </{name}>"));
+
+        xml
+    }
+
+    /// The name of the Dublin Core element `name`, with the prefix the source uses for them.
+    fn dc_name(&self, name: &str) -> OwnedName {
+        OwnedName {
+            local_name: name.to_string(),
+            namespace: Some(DC_NAMESPACE.to_string()),
+            prefix: Some(self.prefix(DC_NAMESPACE, "dc")),
+        }
+    }
+
+    /// The name of the package element `name`, with the prefix of the source's `metadata` element.
+    fn opf_name(&self, name: &str) -> OwnedName {
+        OwnedName {
+            local_name: name.to_string(),
+            namespace: Some(OPF_NAMESPACE.to_string()),
+            prefix: self.prefix.clone(),
+        }
+    }
+
+    fn prefix(&self, uri: &str, default: &str) -> String {
+        self.namespaces
+            .iter()
+            .find(|(_, declared)| declared == uri)
+            .map_or(default, |(prefix, _)| prefix.as_str())
+            .to_string()
+    }
+}
+
+/// A name-based UUID (version 3) for the translation into `language` of the book identified by
+/// `source`.
+fn derived_uuid(source: &str, language: &str) -> String {
+    let mut bytes: [u8; 16] = Md5::new()
+        .chain_update(source.as_bytes())
+        .chain_update(b"\0")
+        .chain_update(language.as_bytes())
+        .finalize()
+        .into();
+    bytes[6] = (bytes[6] & 0x0f) | 0x30;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    format_uuid(&bytes)
+}
+
+/// A random UUID (version 4), for books without an identifier to derive one from.
+fn random_uuid() -> String {
+    let mut bytes = [0u8; 16];
+    rand::rng().fill(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    format_uuid(&bytes)
+}
+
+fn format_uuid(bytes: &[u8; 16]) -> String {
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
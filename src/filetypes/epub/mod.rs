@@ -0,0 +1,524 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    future::Future,
+    io::{BufReader, Read, Seek},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use epub::doc::{EpubDoc, NavPoint};
+use eyre::{eyre, Result};
+use regex::Regex;
+use tl::{Bytes, Node, NodeHandle, Parser, ParserOptions, VDom};
+use zip::ZipArchive;
+
+use super::{
+    edit_snippets,
+    entities::{escape, unescape},
+    protect::ProtectedSpans,
+};
+use crate::options::{EpubOptions, ProtectList, ProtectedKind, RequestOptions};
+
+mod attributes;
+mod direction;
+mod metadata;
+mod package;
+mod segments;
+mod svg;
+mod toc;
+mod validate;
+
+use metadata::Metadata;
+pub use validate::validate_epub;
+
+pub struct EditedEpub {
+    pub base: EpubDoc<BufReader<File>>,
+    pub content: HashMap<String, String>,
+    /// Spine items left out of the output
+    pub omitted: HashSet<String>,
+    /// The table of contents, with the labels of the translated chapters translated
+    pub toc: Vec<NavPoint>,
+    /// The metadata of the translation
+    metadata: Metadata,
+}
+
+pub fn read_epub(path: &str) -> Result<EpubDoc<BufReader<File>>> {
+    tracing::info!("Reading {path}...");
+    let doc = EpubDoc::new(Path::new(path))?;
+
+    Ok(doc)
+}
+
+pub async fn edit_epub<F, Fut>(
+    mut doc: EpubDoc<BufReader<File>>,
+    request_options: RequestOptions,
+    epub_options: EpubOptions,
+    edit_func: F,
+) -> Result<EditedEpub>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let mut edited_content = HashMap::new();
+    let mut omitted = HashSet::new();
+    let mut referenced = HashSet::new();
+    let edit_func = Arc::new(edit_func);
+    let opf = doc
+        .get_resource_str_by_path(doc.root_file.clone())
+        .ok_or(eyre!("Unable to read the package document"))?;
+    // the navigation document links to every chapter, and its table of contents is pruned of the
+    // omitted ones instead
+    let nav_path = package::nav_path(&opf, &doc.root_file);
+
+    for index in 0..doc.get_num_pages() {
+        let current_id = doc.get_current_id();
+        let is_selected = epub_options
+            .chapters
+            .as_ref()
+            .is_none_or(|chapters| chapters.contains(index + 1, current_id.as_deref()));
+        if !is_selected {
+            if epub_options.omit_unselected {
+                omitted.extend(current_id);
+            }
+            doc.go_next();
+            continue;
+        }
+
+        if let Some((content, mime)) = doc.get_current_str() {
+            let current_id = doc
+                .get_current_id()
+                .ok_or(eyre!("Unable to get current id"))?;
+            let path = doc.get_current_path().unwrap_or_default();
+            let name = path.display().to_string();
+            match mime.as_str() {
+                "application/xhtml+xml" => {
+                    let edited_html =
+                        edit_html(&request_options, &epub_options, &name, &content, &edit_func)
+                            .await?;
+                    if Some(&path) != nav_path.as_ref() {
+                        referenced.extend(svg::references(&content, &path));
+                    }
+                    edited_content.insert(current_id, edited_html);
+                }
+                "image/svg+xml" => {
+                    let edited_svg = svg::translate_svg_text(
+                        &name,
+                        &content,
+                        &epub_options.skip,
+                        &request_options,
+                        &edit_func,
+                    )
+                    .await?;
+                    edited_content.insert(current_id, edited_svg);
+                }
+                _ => {}
+            }
+        }
+
+        doc.go_next();
+    }
+    doc.set_current_page(0);
+
+    if !omitted.is_empty() {
+        keep_required(
+            &mut doc,
+            &opf,
+            nav_path.as_deref(),
+            &mut omitted,
+            &mut referenced,
+        );
+    }
+
+    // SVG images outside the reading order are translated along with the chapters that show them
+    let images: Vec<(String, String)> = doc
+        .resources
+        .iter()
+        .filter(|(id, (path, mime))| {
+            mime == "image/svg+xml"
+                && !edited_content.contains_key(*id)
+                && referenced.contains(&entry_name(path))
+        })
+        .map(|(id, (path, _))| (id.clone(), path.display().to_string()))
+        .collect();
+    for (id, name) in images {
+        let (svg, _) = doc
+            .get_resource_str(&id)
+            .ok_or(eyre!("Unable to read the SVG image {name}"))?;
+        let edited_svg = svg::translate_svg_text(
+            &name,
+            &svg,
+            &epub_options.skip,
+            &request_options,
+            &edit_func,
+        )
+        .await?;
+        edited_content.insert(id, edited_svg);
+    }
+
+    let translated_paths: HashSet<&Path> = edited_content
+        .keys()
+        .filter_map(|id| doc.resources.get(id))
+        .map(|(path, _)| path.as_path())
+        .collect();
+    let mut toc = doc.toc.clone();
+    toc::translate_toc(
+        &mut toc,
+        |path| translated_paths.contains(path),
+        &request_options,
+        &edit_func,
+    )
+    .await?;
+
+    let metadata =
+        metadata::edit_metadata(&opf, &epub_options, &request_options, &edit_func).await?;
+
+    Ok(EditedEpub {
+        base: doc,
+        content: edited_content,
+        omitted,
+        toc,
+        metadata,
+    })
+}
+
+/// Takes the spine items that the book can't do without off `omitted`: the navigation document,
+/// the cover, and the documents that the kept ones, whose references are in `referenced`, link to,
+/// which are kept untranslated so that those links still lead somewhere.
+fn keep_required(
+    doc: &mut EpubDoc<BufReader<File>>,
+    opf: &str,
+    nav_path: Option<&Path>,
+    omitted: &mut HashSet<String>,
+    referenced: &mut HashSet<String>,
+) {
+    let required = package::required_items(opf);
+    let mut kept = 0;
+    loop {
+        let linked: Vec<(String, PathBuf)> = omitted
+            .iter()
+            .filter_map(|id| Some((id.clone(), doc.resources.get(id)?.0.clone())))
+            .filter(|(id, path)| required.contains(id) || referenced.contains(&entry_name(path)))
+            .collect();
+        if linked.is_empty() {
+            break;
+        }
+
+        for (id, path) in linked {
+            omitted.remove(&id);
+            kept += 1;
+            if Some(path.as_path()) == nav_path {
+                continue;
+            }
+            if let Some((content, _)) = doc.get_resource_str(&id) {
+                referenced.extend(svg::references(&content, &path));
+            }
+        }
+    }
+
+    if kept > 0 {
+        tracing::info!(
+            "Keeping {kept} unselected documents that the book needs or that the selected ones link to"
+        );
+    }
+}
+
+/// Translates the text of the XHTML document at `path` a segment at a time, so that sentences are
+/// translated whole along with the inline markup within them, and then the attributes that hold
+/// text. The elements the skip list of `epub_options` matches are left as they are, and the spans
+/// its protect list matches are kept out of the text sent for translation altogether.
+async fn edit_html<F, Fut>(
+    request_options: &RequestOptions,
+    epub_options: &EpubOptions,
+    path: &str,
+    html: &str,
+    edit_func: &Arc<F>,
+) -> Result<String>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let html =
+        svg::translate_svg_text(path, html, &epub_options.skip, request_options, edit_func).await?;
+    let (html, unparsed) = ProtectedSpans::protect(&html, &[Regex::new(UNPARSED_MARKUP).unwrap()]);
+    let (html, protected) =
+        ProtectedSpans::protect(&html, &protected_patterns(&epub_options.protect)?);
+    let html = space_self_closing_tags(&html);
+    let markup = epub_options.markup;
+    let html = attributes::translate_attributes(
+        &html,
+        &epub_options.attributes,
+        &epub_options.skip,
+        request_options,
+        edit_func,
+    )
+    .await?;
+
+    let mut dom = tl::parse(&html, ParserOptions::default())?;
+    let mut segments = segments::find_segments(&dom, markup, &epub_options.skip)?;
+    segments.retain(|segment| {
+        !protected.is_opaque(&segment.text) && !unparsed.is_opaque(&segment.text)
+    });
+    let snippets = segments
+        .iter()
+        .map(|segment| segment.text.clone())
+        .collect();
+    let translations = edit_snippets(request_options, edit_func, snippets).await?;
+
+    let mut text_nodes = vec![];
+    for (segment, translation) in segments.iter().zip(translations) {
+        if !segment.restore(dom.parser_mut(), markup, &translation)? {
+            tracing::warn!(
+                "Markup came back changed in the translation of \"{}\", translating its text node by node",
+                segment.text
+            );
+            text_nodes.extend(segment.text_nodes.iter().copied());
+        }
+    }
+
+    let snippets = text_nodes
+        .iter()
+        .map(|handle| match handle.get(dom.parser()) {
+            Some(Node::Raw(bytes)) => markup.decode(&bytes.as_utf8_str()),
+            _ => String::new(),
+        })
+        .collect();
+    let translations = edit_snippets(request_options, edit_func, snippets).await?;
+    for (handle, translation) in text_nodes.iter().zip(translations) {
+        if let Some(node) = handle.get_mut(dom.parser_mut()) {
+            let mut edited_bytes = Bytes::new();
+            edited_bytes.set(markup.encode(&translation))?;
+            *node = Node::Raw(edited_bytes);
+        }
+    }
+
+    let mut edited_html = to_xhtml(&dom);
+    edited_html = unparsed.restore(&protected.restore(&edited_html, path), path);
+    if let Some(language) = &epub_options.language {
+        edited_html = direction::set_document_language(&edited_html, language);
+        if epub_options.rtl_style && direction::is_rtl(language) {
+            edited_html = direction::add_rtl_style(&edited_html);
+        }
+    }
+
+    Ok(edited_html)
+}
+
+/// Markup that `tl` can't read, which is kept away from it whenever a document is parsed and
+/// written back out: it drops document type declarations, leaving the rest of those with a public
+/// identifier behind as text, and drops the `<!` of CDATA sections.
+const UNPARSED_MARKUP: &str = r"(?is)<!DOCTYPE\b[^\[>]*(?:\[.*?\])?\s*>|<!\[CDATA\[.*?\]\]>";
+
+/// Writes `dom` back out as it was parsed, with the changes made to it. `outer_html` can't be used
+/// for XHTML, since it drops the slash of self-closing tags and takes the XML declaration for an
+/// element around the rest of the document.
+fn to_xhtml(dom: &VDom) -> String {
+    let mut xhtml = String::new();
+    for &handle in dom.children() {
+        write_node(handle, dom.parser(), &mut xhtml);
+    }
+    xhtml
+}
+
+fn write_node(handle: NodeHandle, parser: &Parser, xhtml: &mut String) {
+    match handle.get(parser) {
+        Some(Node::Raw(bytes) | Node::Comment(bytes)) => xhtml.push_str(&bytes.as_utf8_str()),
+        Some(Node::Tag(tag)) => {
+            let raw = tag.raw().as_utf8_str();
+            let start = segments::start_tag(&raw);
+            xhtml.push_str(start);
+            for &child in tag.children().top().iter() {
+                write_node(child, parser, xhtml);
+            }
+
+            // Elements without an end tag in the source, such as self-closing ones, are left
+            // without one
+            let name = tag.name().as_utf8_str();
+            if let Some(end) = raw[start.len()..]
+                .rfind("</")
+                .map(|end| &raw[start.len() + end..])
+            {
+                if end[2..]
+                    .trim_end_matches(|c: char| c == '>' || c.is_whitespace())
+                    .eq_ignore_ascii_case(&name)
+                {
+                    xhtml.push_str(end);
+                }
+            }
+        }
+        None => {}
+    }
+}
+
+/// Adds a space before the slash of self-closing tags without attributes, such as XHTML's `<br/>`.
+/// `tl` takes the slash for part of the tag name otherwise, and leaves the tag open around
+/// everything that follows it.
+fn space_self_closing_tags(html: &str) -> String {
+    let re = regex::Regex::new(r"<([A-Za-z][\w:.-]*)/>").unwrap();
+    re.replace_all(html, "<$1 />").into_owned()
+}
+
+/// The patterns of the spans of XHTML documents that `protect` keeps out of translation.
+fn protected_patterns(protect: &ProtectList) -> Result<Vec<Regex>> {
+    let kinds = protect.kinds.iter().map(|kind| match kind {
+        ProtectedKind::PageBreaks => {
+            r#"<[\w:-]+\b[^>]*\s(?:epub:type|role|class)\s*=\s*["'][^"']*\b(?:doc-)?page-?break\b[^"']*["'][^>]*?(?:/>|>[^<]*</[\w:-]+\s*>)"#
+        }
+        ProtectedKind::NoteRefs => {
+            r#"(?s)<a\b[^>]*\s(?:epub:type|role)\s*=\s*["'][^"']*\b(?:doc-)?noteref\b[^"']*["'][^>]*>.*?</a\s*>"#
+        }
+        ProtectedKind::Math => r"(?s)<(?:[\w-]+:)?math\b.*?</(?:[\w-]+:)?math\s*>",
+        ProtectedKind::Svg => r"(?s)<(?:[\w-]+:)?svg\b.*?</(?:[\w-]+:)?svg\s*>",
+    });
+
+    kinds
+        .chain(protect.patterns.iter().map(String::as_str))
+        .map(|pattern| {
+            Regex::new(pattern).map_err(|error| eyre!("Invalid pattern `{pattern}`: {error}"))
+        })
+        .collect()
+}
+
+/// Writes the translation to `to` as a copy of the source book at `source`, in which only the
+/// translated documents, the package document and the navigation documents are changed.
+pub fn write_epub(edited: EditedEpub, source: &str, to: &str) -> Result<()> {
+    tracing::info!("Writing epub...");
+    let EditedEpub {
+        base: doc,
+        content,
+        omitted,
+        toc,
+        mut metadata,
+    } = edited;
+    let mut archive = ZipArchive::new(BufReader::new(File::open(source)?))?;
+    let path_of = |id: &String| doc.resources.get(id).map(|(path, _)| path.clone());
+
+    let mut replaced: HashMap<String, String> = content
+        .into_iter()
+        .filter_map(|(id, html)| Some((entry_name(&path_of(&id)?), html)))
+        .collect();
+    let omitted_paths: HashSet<PathBuf> = omitted.iter().filter_map(path_of).collect();
+
+    let opf_path = entry_name(&doc.root_file);
+    let opf = package::read_entry(&mut archive, &opf_path)?;
+    metadata.set_modified(chrono::Utc::now());
+    let mut edited_opf = package::remove_items(&metadata.replace_in(&opf)?, &omitted);
+    // Only EPUB 3 has a direction for the spine
+    if let Some(language) = metadata.language().filter(|_| metadata.is_epub3()) {
+        edited_opf = direction::set_page_progression(&edited_opf, language);
+    }
+    replaced.insert(opf_path, edited_opf);
+
+    // The table of contents goes into both navigation documents: EPUB 3 reading systems read the
+    // navigation document, older ones the NCX. If the source's couldn't be read, their entries
+    // are left as they are.
+    let toc = (!toc.is_empty()).then(|| toc::prune(toc, &|path| omitted_paths.contains(path)));
+    let ncx_path = doc
+        .resources
+        .values()
+        .find(|(_, mime)| mime == "application/x-dtbncx+xml")
+        .map(|(path, _)| path);
+    if let Some(path) = ncx_path {
+        let ncx = current_entry(&mut archive, &replaced, path)?;
+        let ncx = toc::write_ncx(
+            &ncx,
+            path,
+            toc.as_deref(),
+            metadata.title(),
+            metadata.identifier(),
+        );
+        replaced.insert(entry_name(path), ncx);
+    }
+    if let (Some(path), Some(toc)) = (package::nav_path(&opf, &doc.root_file), &toc) {
+        let nav = current_entry(&mut archive, &replaced, &path)?;
+        let mut nav = toc::write_nav(&nav, &path, toc);
+        if let Some(language) = metadata.language() {
+            nav = direction::set_document_language(&nav, language);
+        }
+        replaced.insert(entry_name(&path), nav);
+    }
+
+    let removed = omitted_paths.iter().map(|path| entry_name(path)).collect();
+    package::write_container(&mut archive, &replaced, &removed, to)
+}
+
+/// The content of the entry at `path` of the book being written, with the changes made to it so
+/// far.
+fn current_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    replaced: &HashMap<String, String>,
+    path: &Path,
+) -> Result<String> {
+    let name = entry_name(path);
+    match replaced.get(&name) {
+        Some(content) => Ok(content.clone()),
+        None => package::read_entry(archive, &name),
+    }
+}
+
+/// The name of the entry of the container at `path`, a path of the `epub` crate, which keeps the
+/// percent-encoding of the hrefs of the manifest.
+fn entry_name(path: &Path) -> String {
+    decode(&path.to_string_lossy())
+}
+
+/// Escapes the characters of `text` that are markup in XHTML, leaving the references to entities in
+/// it, such as `&nbsp;`, as they are.
+fn escape_text(text: &str) -> String {
+    let special = Regex::new(r"&(?:[A-Za-z][\w.-]*|#[0-9]+|#[xX][0-9a-fA-F]+);|[&<>]").unwrap();
+
+    special
+        .replace_all(text, |captures: &regex::Captures| match &captures[0] {
+            "&" => "&amp;".to_string(),
+            "<" => "&lt;".to_string(),
+            ">" => "&gt;".to_string(),
+            reference => reference.to_string(),
+        })
+        .into_owned()
+}
+
+/// Whether `href` leads outside the book, such as to a web page.
+fn is_external(href: &str) -> bool {
+    let scheme = Regex::new(r"^[A-Za-z][A-Za-z0-9+.-]*:").unwrap();
+    scheme.is_match(href) || href.starts_with("//")
+}
+
+/// The path in the container of the file the relative URL `href` leads to from the directory
+/// `dir`.
+fn resolve(dir: &Path, href: &str) -> String {
+    let mut components: Vec<String> = vec![];
+    for component in dir.join(decode(href)).components() {
+        match component {
+            Component::ParentDir => {
+                components.pop();
+            }
+            Component::Normal(name) => components.push(name.to_string_lossy().into_owned()),
+            _ => {}
+        }
+    }
+    components.join("/")
+}
+
+/// Decodes the percent-encoded characters of `href`.
+fn decode(href: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = href.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
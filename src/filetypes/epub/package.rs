@@ -0,0 +1,175 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufWriter, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use eyre::Result;
+use regex::Regex;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+const MIMETYPE_PATH: &str = "mimetype";
+
+/// Writes a copy of the book in `archive` to `to`, with the entries in `replaced` given new
+/// content and the entries in `removed` left out. Every other entry is copied byte for byte, in
+/// the order of the source.
+pub(super) fn write_container<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    replaced: &HashMap<String, String>,
+    removed: &HashSet<String>,
+    to: &str,
+) -> Result<()> {
+    let mut writer = ZipWriter::new(BufWriter::new(File::create(to)?));
+
+    // Reading systems recognize the container by its first entry, which has to be stored
+    // uncompressed
+    writer.start_file(
+        MIMETYPE_PATH,
+        SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+    )?;
+    writer.write_all(b"application/epub+zip")?;
+
+    for index in 0..archive.len() {
+        let Some(name) = archive.name_for_index(index).map(str::to_string) else {
+            continue;
+        };
+        if name == MIMETYPE_PATH || removed.contains(&name) {
+            continue;
+        }
+
+        match replaced.get(&name) {
+            Some(content) => {
+                writer.start_file(
+                    name,
+                    SimpleFileOptions::default().compression_method(CompressionMethod::Deflated),
+                )?;
+                writer.write_all(content.as_bytes())?;
+            }
+            None => writer.raw_copy_file(archive.by_index_raw(index)?)?,
+        }
+    }
+    writer.finish()?.flush()?;
+
+    Ok(())
+}
+
+/// The content of the entry `path` of `archive` as text.
+pub(super) fn read_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    path: &str,
+) -> Result<String> {
+    let mut content = String::new();
+    archive.by_name(path)?.read_to_string(&mut content)?;
+
+    Ok(content)
+}
+
+/// The path of the navigation document of the package document `opf` at `opf_path`, if it has
+/// one.
+pub(super) fn nav_path(opf: &str, opf_path: &Path) -> Option<PathBuf> {
+    let item = Regex::new(r"<(?:[\w-]+:)?item\b[^>]*>").unwrap();
+
+    let nav = item
+        .find_iter(opf)
+        .map(|item| item.as_str())
+        .find(|item| {
+            attribute(item, "properties")
+                .is_some_and(|properties| properties.split_whitespace().any(|p| p == "nav"))
+        })
+        .and_then(|item| attribute(item, "href"))
+        .map(|href| opf_path.parent().unwrap_or(Path::new("")).join(href));
+    nav
+}
+
+/// The ids of the manifest items of the package document `opf` that a book can't do without: the
+/// navigation document and the cover, whether it is marked in the manifest, by the `cover` entry
+/// of the metadata or in the guide.
+pub(super) fn required_items(opf: &str) -> HashSet<String> {
+    let item = Regex::new(r"<(?:[\w-]+:)?item\b[^>]*>").unwrap();
+    let meta = Regex::new(r"<(?:[\w-]+:)?meta\b[^>]*>").unwrap();
+    let reference = Regex::new(r"<(?:[\w-]+:)?reference\b[^>]*>").unwrap();
+
+    let cover_hrefs: HashSet<&str> = reference
+        .find_iter(opf)
+        .map(|reference| reference.as_str())
+        .filter(|reference| attribute(reference, "type") == Some("cover"))
+        .filter_map(|reference| attribute(reference, "href"))
+        .map(|href| href.split_once('#').map_or(href, |(path, _)| path))
+        .collect();
+    let cover_ids: HashSet<&str> = meta
+        .find_iter(opf)
+        .map(|meta| meta.as_str())
+        .filter(|meta| attribute(meta, "name") == Some("cover"))
+        .filter_map(|meta| attribute(meta, "content"))
+        .collect();
+
+    item.find_iter(opf)
+        .map(|item| item.as_str())
+        .filter(|item| {
+            let properties = attribute(item, "properties").unwrap_or_default();
+            properties
+                .split_whitespace()
+                .any(|property| property == "nav" || property == "cover-image")
+                || attribute(item, "id").is_some_and(|id| cover_ids.contains(id))
+                || attribute(item, "href").is_some_and(|href| cover_hrefs.contains(href))
+        })
+        .filter_map(|item| attribute(item, "id"))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Removes the manifest items with the ids in `omitted` from the package document `opf`, along
+/// with their entries in the spine and the guide.
+pub(super) fn remove_items(opf: &str, omitted: &HashSet<String>) -> String {
+    if omitted.is_empty() {
+        return opf.to_string();
+    }
+    let item = Regex::new(r"[ \t]*<(?:[\w-]+:)?item\b[^>]*>(?:[ \t]*\r?\n)?").unwrap();
+    let itemref = Regex::new(r"[ \t]*<(?:[\w-]+:)?itemref\b[^>]*>(?:[ \t]*\r?\n)?").unwrap();
+    let reference = Regex::new(r"[ \t]*<(?:[\w-]+:)?reference\b[^>]*>(?:[ \t]*\r?\n)?").unwrap();
+
+    let is_omitted = |tag: &str, id_attribute| {
+        attribute(tag, id_attribute).is_some_and(|id| omitted.contains(id))
+    };
+    let hrefs: HashSet<&str> = item
+        .find_iter(opf)
+        .filter(|item| is_omitted(item.as_str(), "id"))
+        .filter_map(|item| attribute(item.as_str(), "href"))
+        .collect();
+
+    let opf = item.replace_all(opf, |captures: &regex::Captures| match &captures[0] {
+        tag if is_omitted(tag, "id") => String::new(),
+        tag => tag.to_string(),
+    });
+    let opf = itemref.replace_all(&opf, |captures: &regex::Captures| match &captures[0] {
+        tag if is_omitted(tag, "idref") => String::new(),
+        tag => tag.to_string(),
+    });
+    let opf = reference.replace_all(&opf, |captures: &regex::Captures| match &captures[0] {
+        tag if attribute(tag, "href").is_some_and(|href| {
+            hrefs.contains(href.split_once('#').map_or(href, |(path, _)| path))
+        }) =>
+        {
+            String::new()
+        }
+        tag => tag.to_string(),
+    });
+
+    opf.into_owned()
+}
+
+/// The value of the attribute `name` of the start tag `tag`.
+fn attribute<'t>(tag: &'t str, name: &str) -> Option<&'t str> {
+    let pattern = Regex::new(&format!(
+        r#"\s{}\s*=\s*(?:"([^"]*)"|'([^']*)')"#,
+        regex::escape(name)
+    ))
+    .unwrap();
+    let captures = pattern.captures(tag)?;
+
+    captures
+        .get(1)
+        .or_else(|| captures.get(2))
+        .map(|value| value.as_str())
+}
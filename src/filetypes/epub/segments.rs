@@ -0,0 +1,520 @@
+use std::collections::HashSet;
+
+use eyre::{eyre, Result};
+use regex::Regex;
+use tl::{Bytes, Node, NodeHandle, Parser, VDom};
+
+use super::{escape_text, svg, unescape};
+use crate::options::{InlineMarkup, SkipList};
+
+/// Elements that flow inline with the text around them, and so are translated as part of the
+/// sentence they are in rather than on their own
+const INLINE_ELEMENTS: [&str; 37] = [
+    "a", "abbr", "b", "bdi", "bdo", "big", "br", "cite", "code", "data", "del", "dfn", "em",
+    "font", "i", "img", "ins", "kbd", "mark", "math", "q", "rp", "rt", "ruby", "s", "samp",
+    "small", "span", "strike", "strong", "sub", "sup", "time", "tt", "u", "var", "wbr",
+];
+
+/// A run of text and inline elements within a block, such as the content of a `<p>` or `<li>`,
+/// which is translated as one snippet
+#[derive(Debug)]
+pub(super) struct Segment {
+    /// The top-level nodes of the run, which are replaced by its translation
+    nodes: Vec<NodeHandle>,
+    /// What is sent for translation: the text of the run without its surrounding whitespace, with
+    /// its inline elements replaced by placeholders
+    pub(super) text: String,
+    leading: String,
+    trailing: String,
+    /// The markup each placeholder stands for, by number
+    placeholders: Vec<Placeholder>,
+    /// The text nodes of the run, which are translated one by one instead if the placeholders don't
+    /// come back intact
+    pub(super) text_nodes: Vec<NodeHandle>,
+}
+
+#[derive(Debug)]
+enum Placeholder {
+    /// An element with content, restored as its start and end tags around the translation of it
+    Paired { start: String, end: String },
+    /// An element without content, an element that is not translated, or a comment, restored as
+    /// it was
+    Empty(String),
+}
+
+/// A placeholder found in a translation
+enum Token {
+    Open(usize),
+    /// The end of the element opened last, or of the given one if the markup numbers its ends
+    Close(Option<usize>),
+    Empty(usize),
+}
+
+/// Walks a document to split it into segments
+struct Segmenter<'p, 'a> {
+    parser: &'p Parser<'a>,
+    markup: InlineMarkup,
+    /// The elements left untranslated, content and all
+    skipped: HashSet<NodeHandle>,
+}
+
+/// Splits the content of `dom` into segments at the boundaries of its block elements, leaving out
+/// the elements `skip` matches and inline SVG images, whose labels are translated on their own.
+pub(super) fn find_segments(
+    dom: &VDom,
+    markup: InlineMarkup,
+    skip: &SkipList,
+) -> Result<Vec<Segment>> {
+    let mut skipped = skipped_elements(dom, skip)?;
+    skipped.extend(
+        dom.nodes()
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.as_tag().is_some_and(svg::is_svg))
+            .map(|(index, _)| NodeHandle::new(index as u32)),
+    );
+    let segmenter = Segmenter {
+        parser: dom.parser(),
+        markup,
+        skipped,
+    };
+    let mut segments = vec![];
+    segmenter.collect(dom.children(), &mut segments);
+
+    Ok(segments)
+}
+
+/// The elements of `dom` that `skip` matches.
+pub(super) fn skipped_elements(dom: &VDom, skip: &SkipList) -> Result<HashSet<NodeHandle>> {
+    let mut skipped: HashSet<NodeHandle> = dom
+        .nodes()
+        .iter()
+        .enumerate()
+        .filter(|(_, node)| {
+            let Some(tag) = node.as_tag() else {
+                return false;
+            };
+            let name = tag.name().as_utf8_str().to_ascii_lowercase();
+            let attributes = tag.attributes();
+
+            skip.elements.contains(&name)
+                || skip.attributes.iter().any(|(attribute, value)| {
+                    attributes
+                        .get(attribute.as_str())
+                        .flatten()
+                        .is_some_and(|actual| actual.as_utf8_str().eq_ignore_ascii_case(value))
+                })
+                || skip
+                    .classes
+                    .iter()
+                    .any(|class| attributes.is_class_member(class.as_str()))
+        })
+        .map(|(index, _)| NodeHandle::new(index as u32))
+        .collect();
+
+    for selector in &skip.selectors {
+        let matches = dom
+            .query_selector(selector)
+            .ok_or_else(|| eyre!("Invalid CSS selector `{selector}`"))?;
+        skipped.extend(matches);
+    }
+
+    Ok(skipped)
+}
+
+impl Segmenter<'_, '_> {
+    fn collect(&self, children: &[NodeHandle], segments: &mut Vec<Segment>) {
+        let mut run = vec![];
+
+        for &handle in children {
+            if self.is_inline(handle) {
+                run.push(handle);
+                continue;
+            }
+
+            segments.extend(Segment::new(std::mem::take(&mut run), self));
+            if let Some(tag) = handle
+                .get(self.parser)
+                .and_then(Node::as_tag)
+                .filter(|_| !self.skipped.contains(&handle))
+            {
+                self.collect(tag.children().top().as_slice(), segments);
+            }
+        }
+        segments.extend(Segment::new(run, self));
+    }
+
+    /// Whether the node `handle` is text, a comment, or an inline element that only holds such
+    /// nodes. What an element that is not translated holds doesn't matter, since it is kept as is.
+    fn is_inline(&self, handle: NodeHandle) -> bool {
+        match handle.get(self.parser) {
+            Some(Node::Raw(_) | Node::Comment(_)) => true,
+            Some(Node::Tag(tag)) => {
+                let name = tag.name().as_utf8_str().to_ascii_lowercase();
+                INLINE_ELEMENTS.contains(&name.as_str())
+                    && (self.skipped.contains(&handle)
+                        || tag
+                            .children()
+                            .top()
+                            .iter()
+                            .all(|&child| self.is_inline(child)))
+            }
+            None => false,
+        }
+    }
+}
+
+impl Segment {
+    /// Builds the segment of the run of inline `nodes`, or returns `None` if it has no text to
+    /// translate.
+    fn new(nodes: Vec<NodeHandle>, segmenter: &Segmenter) -> Option<Self> {
+        let mut segment = Self {
+            nodes,
+            text: String::new(),
+            leading: String::new(),
+            trailing: String::new(),
+            placeholders: vec![],
+            text_nodes: vec![],
+        };
+        let mut text = String::new();
+        for &handle in &segment.nodes.clone() {
+            segment.encode(handle, segmenter, &mut text);
+        }
+
+        if segment.text_nodes.is_empty() {
+            return None;
+        }
+        let trimmed = text.trim();
+        let start = text.len() - text.trim_start().len();
+        segment.leading = text[..start].to_string();
+        segment.trailing = text[start + trimmed.len()..].to_string();
+        segment.text = trimmed.to_string();
+
+        Some(segment)
+    }
+
+    /// Appends the text of the node `handle` to `text`, with placeholders for its markup and for
+    /// the elements that are not translated.
+    fn encode(&mut self, handle: NodeHandle, segmenter: &Segmenter, text: &mut String) {
+        let markup = segmenter.markup;
+        match handle.get(segmenter.parser) {
+            Some(Node::Raw(bytes)) => {
+                let raw = bytes.as_utf8_str();
+                if !raw.trim().is_empty() {
+                    self.text_nodes.push(handle);
+                }
+                text.push_str(&markup.decode(&raw));
+            }
+            Some(Node::Comment(bytes)) => {
+                text.push_str(&markup.empty(self.placeholders.len()));
+                self.placeholders
+                    .push(Placeholder::Empty(bytes.as_utf8_str().to_string()));
+            }
+            Some(Node::Tag(tag)) => {
+                let raw = tag.raw().as_utf8_str();
+                let children = tag.children();
+                let id = self.placeholders.len();
+                if children.top().len() == 0 || segmenter.skipped.contains(&handle) {
+                    text.push_str(&markup.empty(id));
+                    self.placeholders.push(Placeholder::Empty(raw.to_string()));
+                    return;
+                }
+
+                text.push_str(&markup.open(id));
+                self.placeholders.push(Placeholder::Paired {
+                    start: start_tag(&raw).to_string(),
+                    end: format!("</{}>", tag.name().as_utf8_str()),
+                });
+                for &child in children.top().iter() {
+                    self.encode(child, segmenter, text);
+                }
+                text.push_str(&markup.close(id));
+            }
+            None => {}
+        }
+    }
+
+    /// Puts `translation` in place of the nodes of the segment, with its placeholders replaced by
+    /// the markup they stand for. Returns `false`, leaving the nodes as they are, if the
+    /// placeholders are missing, repeated or no longer nested as they were.
+    pub(super) fn restore(
+        &self,
+        parser: &mut Parser,
+        markup: InlineMarkup,
+        translation: &str,
+    ) -> Result<bool> {
+        let Some(html) = self.restore_markup(markup, translation) else {
+            return Ok(false);
+        };
+
+        for (index, handle) in self.nodes.iter().enumerate() {
+            if let Some(node) = handle.get_mut(parser) {
+                let mut bytes = Bytes::new();
+                if index == 0 {
+                    bytes.set(format!("{}{html}{}", self.leading, self.trailing))?;
+                }
+                *node = Node::Raw(bytes);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn restore_markup(&self, markup: InlineMarkup, translation: &str) -> Option<String> {
+        let mut html = String::with_capacity(translation.len());
+        let mut open = vec![];
+        let mut seen = vec![false; self.placeholders.len()];
+        let mut last = 0;
+
+        for (range, token) in markup.tokens(translation) {
+            html.push_str(&markup.encode(&translation[last..range.start]));
+            last = range.end;
+
+            match token {
+                Token::Open(id) | Token::Empty(id) if seen.get(id) != Some(&false) => return None,
+                Token::Open(id) => {
+                    let Placeholder::Paired { start, .. } = &self.placeholders[id] else {
+                        return None;
+                    };
+                    seen[id] = true;
+                    open.push(id);
+                    html.push_str(start);
+                }
+                Token::Empty(id) => {
+                    let Placeholder::Empty(raw) = &self.placeholders[id] else {
+                        return None;
+                    };
+                    seen[id] = true;
+                    html.push_str(raw);
+                }
+                Token::Close(id) => {
+                    let opened = open.pop()?;
+                    if id.is_some_and(|id| id != opened) {
+                        return None;
+                    }
+                    let Placeholder::Paired { end, .. } = &self.placeholders[opened] else {
+                        return None;
+                    };
+                    html.push_str(end);
+                }
+            }
+        }
+        html.push_str(&markup.encode(&translation[last..]));
+
+        (open.is_empty() && seen.iter().all(|&seen| seen)).then_some(html)
+    }
+}
+
+impl InlineMarkup {
+    /// The text of the XHTML text node `raw` as it is sent for translation. Translation services
+    /// with an HTML mode take the text as it is written in the document, with its character
+    /// references; language models are given the characters themselves, since they may write
+    /// them back unescaped.
+    pub(super) fn decode(self, raw: &str) -> String {
+        match self {
+            InlineMarkup::Html => raw.to_string(),
+            InlineMarkup::Tokens => unescape(raw),
+        }
+    }
+
+    /// The translated text `text`, sent as `decode` gives it, as it is written in XHTML.
+    pub(super) fn encode(self, text: &str) -> String {
+        match self {
+            InlineMarkup::Html => text.to_string(),
+            InlineMarkup::Tokens => escape_text(text),
+        }
+    }
+
+    fn open(self, id: usize) -> String {
+        match self {
+            InlineMarkup::Html => format!(r#"<g id="{id}">"#),
+            InlineMarkup::Tokens => format!("[[{id}]]"),
+        }
+    }
+
+    fn close(self, id: usize) -> String {
+        match self {
+            InlineMarkup::Html => "</g>".to_string(),
+            InlineMarkup::Tokens => format!("[[/{id}]]"),
+        }
+    }
+
+    fn empty(self, id: usize) -> String {
+        match self {
+            InlineMarkup::Html => format!(r#"<x id="{id}"/>"#),
+            InlineMarkup::Tokens => format!("[[{id}/]]"),
+        }
+    }
+
+    /// The placeholders in `translation` and where they are. Translation services may write the
+    /// placeholder tags back with other quotes or spacing, or close an empty one with an end tag,
+    /// so those are accepted too.
+    fn tokens(self, translation: &str) -> Vec<(std::ops::Range<usize>, Token)> {
+        let pattern = match self {
+            InlineMarkup::Html => Regex::new(
+                r#"(?i)<g\s+id\s*=\s*["']?(?P<open>\d+)["']?\s*>|</g\s*>|<x\s+id\s*=\s*["']?(?P<empty>\d+)["']?\s*/?>(?:\s*</x\s*>)?"#,
+            ),
+            InlineMarkup::Tokens => {
+                Regex::new(r"\[\[(?:(?P<open>\d+)|/(?P<close>\d+)|(?P<empty>\d+)/)\]\]")
+            }
+        }
+        .unwrap();
+        let id = |captures: &regex::Captures, name| {
+            captures
+                .name(name)
+                .and_then(|id| id.as_str().parse::<usize>().ok())
+        };
+
+        pattern
+            .captures_iter(translation)
+            .filter_map(|captures| {
+                let range = captures.get(0)?.range();
+                let token = if let Some(id) = id(&captures, "open") {
+                    Token::Open(id)
+                } else if let Some(id) = id(&captures, "empty") {
+                    Token::Empty(id)
+                } else {
+                    Token::Close(id(&captures, "close"))
+                };
+                Some((range, token))
+            })
+            .collect()
+    }
+}
+
+/// The start tag at the beginning of the source of an element, up to the first `>` outside of an
+/// attribute value.
+pub(super) fn start_tag(raw: &str) -> &str {
+    let mut quote = None;
+    for (index, c) in raw.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, '>') => return &raw[..=index],
+            _ => {}
+        }
+    }
+    raw
+}
+
+#[cfg(test)]
+mod tests {
+    use tl::ParserOptions;
+
+    use super::*;
+
+    const PARAGRAPH: &str =
+        r#"<p>Read <a href="x.xhtml">the <b>manual</b></a> first <img src="i.png" /> &amp; go</p>"#;
+
+    /// The text of the first segment of `html`, and `translation` of it restored, if possible.
+    fn restore(html: &str, markup: InlineMarkup, translation: &str) -> (String, Option<String>) {
+        let dom = tl::parse(html, ParserOptions::default()).unwrap();
+        let segments = find_segments(&dom, markup, &SkipList::default()).unwrap();
+        let segment = &segments[0];
+        (
+            segment.text.clone(),
+            segment.restore_markup(markup, translation),
+        )
+    }
+
+    #[test]
+    fn restores_placeholder_tags() {
+        let translation =
+            r#"Lies zuerst <g id="0">das <g id="1">Handbuch</g></g> <x id="2"/> &amp; geh"#;
+        let (text, restored) = restore(PARAGRAPH, InlineMarkup::Html, translation);
+        assert_eq!(
+            text,
+            r#"Read <g id="0">the <g id="1">manual</g></g> first <x id="2"/> &amp; go"#
+        );
+        assert_eq!(
+            restored.as_deref(),
+            Some(
+                r#"Lies zuerst <a href="x.xhtml">das <b>Handbuch</b></a> <img src="i.png" /> &amp; geh"#
+            )
+        );
+    }
+
+    #[test]
+    fn restores_respaced_placeholder_tags() {
+        let translation = "Lies <g id = '0'>das <G ID=1>Handbuch</g ></g> <x id='2'></x> &amp; geh";
+        let (_, restored) = restore(PARAGRAPH, InlineMarkup::Html, translation);
+        assert_eq!(
+            restored.as_deref(),
+            Some(r#"Lies <a href="x.xhtml">das <b>Handbuch</b></a> <img src="i.png" /> &amp; geh"#)
+        );
+    }
+
+    #[test]
+    fn restores_tokens_with_escaped_text() {
+        let translation = "Lies zuerst [[0]]das [[1]]Handbuch[[/1]][[/0]] [[2/]] & <geh>";
+        let (text, restored) = restore(PARAGRAPH, InlineMarkup::Tokens, translation);
+        assert_eq!(
+            text,
+            "Read [[0]]the [[1]]manual[[/1]][[/0]] first [[2/]] & go"
+        );
+        assert_eq!(
+            restored.as_deref(),
+            Some(
+                r#"Lies zuerst <a href="x.xhtml">das <b>Handbuch</b></a> <img src="i.png" /> &amp; &lt;geh&gt;"#
+            )
+        );
+    }
+
+    #[test]
+    fn restores_reordered_elements() {
+        let translation = "[[2/]] [[0]][[1]]Handbuch[[/1]] lesen[[/0]]";
+        let (_, restored) = restore(PARAGRAPH, InlineMarkup::Tokens, translation);
+        assert_eq!(
+            restored.as_deref(),
+            Some(r#"<img src="i.png" /> <a href="x.xhtml"><b>Handbuch</b> lesen</a>"#)
+        );
+    }
+
+    #[test]
+    fn rejects_missing_placeholders() {
+        let translations = [
+            "Lies zuerst [[0]]das Handbuch[[/0]] [[2/]]",
+            "Lies zuerst [[0]]das [[1]]Handbuch[[/1]] [[2/]]",
+            "Lies zuerst [[0]]das [[1]]Handbuch[[/1]][[/0]]",
+        ];
+        for translation in translations {
+            let (_, restored) = restore(PARAGRAPH, InlineMarkup::Tokens, translation);
+            assert_eq!(restored, None, "{translation}");
+        }
+    }
+
+    #[test]
+    fn rejects_mangled_placeholders() {
+        let translations = [
+            // repeated
+            "[[0]]das [[1]]Handbuch[[/1]][[/0]] [[2/]] [[2/]]",
+            "[[0]]das[[/0]] [[0]][[1]]Handbuch[[/1]][[/0]] [[2/]]",
+            // no longer nested
+            "[[0]]das [[1]]Handbuch[[/0]][[/1]] [[2/]]",
+            "[[0]]das[[/0]][[/0]] [[1]]Handbuch[[/1]] [[2/]]",
+            // of the wrong kind or unknown
+            "[[0/]] [[1]]Handbuch[[/1]] [[2/]]",
+            "[[0]]das [[1]]Handbuch[[/1]][[/0]] [[2]]x[[/2]]",
+            "[[0]]das [[1]]Handbuch[[/1]][[/0]] [[2/]] [[7/]]",
+        ];
+        for translation in translations {
+            let (_, restored) = restore(PARAGRAPH, InlineMarkup::Tokens, translation);
+            assert_eq!(restored, None, "{translation}");
+        }
+    }
+
+    #[test]
+    fn keeps_the_nodes_of_rejected_translations() {
+        let mut dom = tl::parse(PARAGRAPH, ParserOptions::default()).unwrap();
+        let source = dom.outer_html();
+        let segments = find_segments(&dom, InlineMarkup::Tokens, &SkipList::default()).unwrap();
+        let segment = &segments[0];
+        assert_eq!(segment.text_nodes.len(), 5);
+
+        let restored = segment
+            .restore(dom.parser_mut(), InlineMarkup::Tokens, "[[0]]kaputt")
+            .unwrap();
+        assert!(!restored);
+        assert_eq!(dom.outer_html(), source);
+    }
+}
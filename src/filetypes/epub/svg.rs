@@ -0,0 +1,135 @@
+use std::{collections::HashSet, future::Future, path::Path, sync::Arc};
+
+use eyre::Result;
+use regex::Regex;
+use tl::{Bytes, HTMLTag, Node, NodeHandle, Parser, ParserOptions};
+
+use super::{
+    edit_snippets, escape, is_external, resolve, segments, space_self_closing_tags, to_xhtml,
+    unescape, UNPARSED_MARKUP,
+};
+use crate::filetypes::protect::ProtectedSpans;
+use crate::options::{RequestOptions, SkipList};
+
+/// Elements of SVG images that hold the text of their labels
+const TEXT_ELEMENTS: [&str; 3] = ["text", "tspan", "textpath"];
+
+/// A text node of a label, with the whitespace around its text
+struct Label {
+    handle: NodeHandle,
+    leading: String,
+    text: String,
+    trailing: String,
+}
+
+/// Translates the labels of the SVG images in `document` at `path`, which is either an SVG image
+/// itself or an XHTML document with inline ones. Every text node of a `<text>`, `<tspan>` or
+/// `<textPath>` is translated on its own, since SVG positions each of them, such as each line of a
+/// label, separately. The labels within the elements `skip` matches are left as they are.
+pub(super) async fn translate_svg_text<F, Fut>(
+    path: &str,
+    document: &str,
+    skip: &SkipList,
+    request_options: &RequestOptions,
+    edit_func: &Arc<F>,
+) -> Result<String>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    if !document.contains("svg") {
+        return Ok(document.to_string());
+    }
+
+    let (parsed, unparsed) = ProtectedSpans::protect(
+        &space_self_closing_tags(document),
+        &[Regex::new(UNPARSED_MARKUP).unwrap()],
+    );
+    let mut dom = tl::parse(&parsed, ParserOptions::default())?;
+    let skipped = segments::skipped_elements(&dom, skip)?;
+    let mut labels = vec![];
+    for &handle in dom.children() {
+        collect(handle, dom.parser(), &skipped, false, &mut labels);
+    }
+    labels.retain(|label| !unparsed.is_opaque(&label.text));
+    if labels.is_empty() {
+        return Ok(document.to_string());
+    }
+
+    let snippets = labels.iter().map(|label| label.text.clone()).collect();
+    let translations = edit_snippets(request_options, edit_func, snippets).await?;
+    for (label, translation) in labels.iter().zip(translations) {
+        if let Some(node) = label.handle.get_mut(dom.parser_mut()) {
+            let mut bytes = Bytes::new();
+            bytes.set(format!(
+                "{}{}{}",
+                label.leading,
+                escape(&translation, false),
+                label.trailing
+            ))?;
+            *node = Node::Raw(bytes);
+        }
+    }
+
+    Ok(unparsed.restore(&to_xhtml(&dom), path))
+}
+
+fn collect(
+    handle: NodeHandle,
+    parser: &Parser,
+    skipped: &HashSet<NodeHandle>,
+    in_label: bool,
+    labels: &mut Vec<Label>,
+) {
+    match handle.get(parser) {
+        Some(Node::Raw(bytes)) if in_label => {
+            let raw = bytes.as_utf8_str();
+            let text = raw.trim();
+            if text.is_empty() {
+                return;
+            }
+            let start = raw.len() - raw.trim_start().len();
+            labels.push(Label {
+                handle,
+                leading: raw[..start].to_string(),
+                text: unescape(text),
+                trailing: raw[start + text.len()..].to_string(),
+            });
+        }
+        Some(Node::Tag(tag)) if !skipped.contains(&handle) => {
+            let in_label = in_label || TEXT_ELEMENTS.contains(&local_name(tag).as_str());
+            for &child in tag.children().top().iter() {
+                collect(child, parser, skipped, in_label, labels);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Whether `tag` is the root of an SVG image.
+pub(super) fn is_svg(tag: &HTMLTag) -> bool {
+    local_name(tag) == "svg"
+}
+
+/// The name of `tag` without its namespace prefix, in lowercase.
+fn local_name(tag: &HTMLTag) -> String {
+    let name = tag.name().as_utf8_str();
+    let local = name.rsplit_once(':').map_or(&*name, |(_, local)| local);
+    local.to_ascii_lowercase()
+}
+
+/// The paths in the container of the files the XHTML document `html` at `path` refers to, such
+/// as the images it shows.
+pub(super) fn references(html: &str, path: &Path) -> HashSet<String> {
+    let reference =
+        Regex::new(r#"\s(?:src|href|xlink:href|data)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    reference
+        .captures_iter(html)
+        .filter_map(|captures| captures.get(1).or_else(|| captures.get(2)))
+        .map(|href| href.as_str())
+        .filter(|href| !href.is_empty() && !href.starts_with('#') && !is_external(href))
+        .map(|href| resolve(dir, href.split_once('#').map_or(href, |(path, _)| path)))
+        .collect()
+}
@@ -0,0 +1,245 @@
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use epub::doc::NavPoint;
+use eyre::Result;
+use regex::Regex;
+
+use super::{edit_snippets, escape};
+use crate::options::RequestOptions;
+
+/// The document an entry of the table of contents points to, without the fragment of its anchor.
+pub(super) fn target(point: &NavPoint) -> PathBuf {
+    let content = point.content.to_string_lossy();
+    let path = content.split_once('#').map_or(&*content, |(path, _)| path);
+    PathBuf::from(path)
+}
+
+/// Translates the labels of the entries of `toc` that point into the documents `is_translated`
+/// accepts, leaving those of untranslated chapters as they are.
+pub(super) async fn translate_toc<F, Fut>(
+    toc: &mut [NavPoint],
+    is_translated: impl Fn(&Path) -> bool,
+    request_options: &RequestOptions,
+    edit_func: &Arc<F>,
+) -> Result<()>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let mut labels = vec![];
+    collect_labels(toc, &is_translated, &mut labels);
+
+    let snippets = labels.iter().map(|label| label.to_string()).collect();
+    let translations = edit_snippets(request_options, edit_func, snippets).await?;
+    for (label, translation) in labels.into_iter().zip(translations) {
+        *label = translation;
+    }
+
+    Ok(())
+}
+
+fn collect_labels<'a>(
+    points: &'a mut [NavPoint],
+    is_translated: &impl Fn(&Path) -> bool,
+    labels: &mut Vec<&'a mut String>,
+) {
+    for point in points {
+        if is_translated(&target(point)) && !point.label.trim().is_empty() {
+            labels.push(&mut point.label);
+        }
+        collect_labels(&mut point.children, is_translated, labels);
+    }
+}
+
+/// Removes the entries that point into the documents `is_omitted` accepts, moving their children
+/// up in their place.
+pub(super) fn prune(points: Vec<NavPoint>, is_omitted: &impl Fn(&Path) -> bool) -> Vec<NavPoint> {
+    points
+        .into_iter()
+        .flat_map(|mut point| {
+            point.children = prune(std::mem::take(&mut point.children), is_omitted);
+            if is_omitted(&target(&point)) {
+                point.children
+            } else {
+                vec![point]
+            }
+        })
+        .collect()
+}
+
+/// Writes `toc` into the navigation map of the NCX document `ncx` at `ncx_path`, in place of the
+/// entries it has, along with the title and unique identifier of the book. The entries are left as
+/// they are if `toc` is `None`.
+pub(super) fn write_ncx(
+    ncx: &str,
+    ncx_path: &Path,
+    toc: Option<&[NavPoint]>,
+    title: Option<&str>,
+    identifier: Option<&str>,
+) -> String {
+    let nav_map = Regex::new(r"(?s)(<navMap\b[^>]*>)(.*?)(\s*</navMap\s*>)").unwrap();
+    let doc_title = Regex::new(r"(?s)(<docTitle\b[^>]*>\s*<text\b[^>]*>).*?(</text>)").unwrap();
+    let uid =
+        Regex::new(r#"(<meta\b[^>]*\bname\s*=\s*["']dtb:uid["'][^>]*\bcontent\s*=\s*["'])[^"']*"#)
+            .unwrap();
+    let depth = Regex::new(
+        r#"(<meta\b[^>]*\bname\s*=\s*["']dtb:depth["'][^>]*\bcontent\s*=\s*["'])[^"']*"#,
+    )
+    .unwrap();
+
+    let ncx = match toc {
+        Some(toc) => {
+            let ncx = nav_map.replace(ncx, |captures: &regex::Captures| {
+                // The heading of the map, if it has one, comes before its entries
+                let heading = captures[2]
+                    .find("<navPoint")
+                    .map_or(&captures[2], |start| &captures[2][..start]);
+                let mut points = String::new();
+                ncx_points(toc, ncx_path, 2, &mut 0, &mut points);
+                format!(
+                    "{}{}\n{}{}",
+                    &captures[1],
+                    heading.trim_end(),
+                    points.trim_end(),
+                    &captures[3]
+                )
+            });
+            depth
+                .replace(&ncx, |captures: &regex::Captures| {
+                    format!("{}{}", &captures[1], toc_depth(toc).max(1))
+                })
+                .into_owned()
+        }
+        None => ncx.to_string(),
+    };
+    let ncx = match title {
+        Some(title) => doc_title.replace(&ncx, |captures: &regex::Captures| {
+            format!("{}{}{}", &captures[1], escape(title, false), &captures[2])
+        }),
+        None => ncx.into(),
+    };
+    match identifier {
+        Some(identifier) => uid
+            .replace(&ncx, |captures: &regex::Captures| {
+                format!("{}{}", &captures[1], escape(identifier, true))
+            })
+            .into_owned(),
+        None => ncx.into_owned(),
+    }
+}
+
+fn ncx_points(
+    points: &[NavPoint],
+    ncx_path: &Path,
+    depth: usize,
+    play_order: &mut usize,
+    ncx: &mut String,
+) {
+    let indent = "  ".repeat(depth);
+    for point in points {
+        *play_order += 1;
+        ncx.push_str(&format!(
+            "{indent}<navPoint id=\"navPoint-{play_order}\" playOrder=\"{play_order}\">\n\
+             {indent}  <navLabel><text>{}</text></navLabel>\n\
+             {indent}  <content src=\"{}\"/>\n",
+            escape(&point.label, false),
+            escape(&href(ncx_path, point), true),
+        ));
+        ncx_points(&point.children, ncx_path, depth + 1, play_order, ncx);
+        ncx.push_str(&format!("{indent}</navPoint>\n"));
+    }
+}
+
+/// Writes `toc` into the table of contents of the navigation document `nav` at `nav_path`, in
+/// place of the list it has.
+pub(super) fn write_nav(nav: &str, nav_path: &Path, toc: &[NavPoint]) -> String {
+    let toc_nav =
+        Regex::new(r#"<nav\b[^>]*\btype\s*=\s*["'](?:[^"']*\s)?toc(?:\s[^"']*)?["'][^>]*>"#)
+            .unwrap();
+    let list_tag = Regex::new(r"(?i)<(/?)ol\b[^>]*>").unwrap();
+
+    let Some(start) = toc_nav
+        .find(nav)
+        .and_then(|toc_nav| list_tag.find_at(nav, toc_nav.end()))
+        .map(|list| list.start())
+    else {
+        return nav.to_string();
+    };
+    let mut depth = 0;
+    let end = list_tag.captures_iter(&nav[start..]).find_map(|captures| {
+        if captures[1].is_empty() {
+            depth += 1;
+        } else {
+            depth -= 1;
+        }
+        (depth == 0).then(|| start + captures.get(0).map_or(0, |tag| tag.end()))
+    });
+    let Some(end) = end else {
+        return nav.to_string();
+    };
+
+    let mut list = String::new();
+    nav_list(toc, nav_path, 0, &mut list);
+    format!("{}{}{}", &nav[..start], list.trim_end(), &nav[end..])
+}
+
+fn nav_list(points: &[NavPoint], nav_path: &Path, depth: usize, nav: &mut String) {
+    let indent = "  ".repeat(depth);
+    nav.push_str("<ol>\n");
+    for point in points {
+        nav.push_str(&format!(
+            "{indent}  <li><a href=\"{}\">{}</a>",
+            escape(&href(nav_path, point), true),
+            escape(&point.label, false),
+        ));
+        if !point.children.is_empty() {
+            nav.push_str(&format!("\n{indent}    "));
+            nav_list(&point.children, nav_path, depth + 2, nav);
+            nav.push_str(&format!("{indent}  "));
+        }
+        nav.push_str("</li>\n");
+    }
+    nav.push_str(&format!("{indent}</ol>\n"));
+}
+
+fn toc_depth(points: &[NavPoint]) -> usize {
+    points
+        .iter()
+        .map(|point| 1 + toc_depth(&point.children))
+        .max()
+        .unwrap_or(0)
+}
+
+/// The link to the anchor an entry of the table of contents points to, relative to the document
+/// at `from`.
+fn href(from: &Path, point: &NavPoint) -> String {
+    let content = point.content.to_string_lossy();
+    let fragment = content.split_once('#').map(|(_, fragment)| fragment);
+    let base: Vec<_> = from
+        .parent()
+        .map_or(vec![], |dir| dir.components().collect());
+    let target_path = target(point);
+    let target: Vec<_> = target_path.components().collect();
+    let common = base
+        .iter()
+        .zip(&target)
+        .take_while(|(base, target)| base == target)
+        .count();
+
+    let mut href: Vec<String> = vec!["..".to_string(); base.len() - common];
+    href.extend(
+        target[common..]
+            .iter()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned()),
+    );
+    let mut href = href.join("/");
+    if let Some(fragment) = fragment {
+        href.push('#');
+        href.push_str(fragment);
+    }
+    href
+}
@@ -0,0 +1,466 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::File,
+    io::{BufReader, Read, Seek},
+    path::Path,
+};
+
+use eyre::Result;
+use regex::Regex;
+use xml::{
+    common::Position,
+    reader::{ParserConfig, XmlEvent},
+};
+use zip::{CompressionMethod, ZipArchive};
+
+use super::{decode, is_external, package::read_entry, resolve};
+
+const MIMETYPE: &str = "application/epub+zip";
+
+/// A problem with an EPUB that reading systems may reject it for
+#[derive(Debug)]
+pub struct Issue {
+    /// The entry of the container the problem is in
+    pub file: String,
+    /// The line the problem is on, counting from 1, if it is at a particular place in the file
+    pub line: Option<u64>,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{line}: {}", self.file, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+/// An item of the manifest of the package document
+struct Item {
+    id: String,
+    /// The path of the item in the container
+    path: String,
+    media_type: String,
+    line: u64,
+}
+
+/// A link from one file of the book to another, or to a place in one
+struct Link {
+    file: String,
+    line: u64,
+    href: String,
+}
+
+#[derive(Default)]
+struct Validator {
+    issues: Vec<Issue>,
+    /// The entries of the container
+    entries: HashSet<String>,
+    /// The ids in each XHTML document, which links may point to
+    ids: HashMap<String, HashSet<String>>,
+    links: Vec<Link>,
+}
+
+/// Checks the EPUB at `path` for the problems that make reading systems reject books: the
+/// `mimetype` entry, the essentials of the package document, missing manifest items, unresolved
+/// spine entries, XHTML that is not well-formed, repeated ids, and links to files or fragments
+/// that don't exist. Returns the problems found, if any.
+pub fn validate_epub(path: &str) -> Result<Vec<Issue>> {
+    tracing::info!("Validating {path}...");
+    let mut archive = ZipArchive::new(BufReader::new(File::open(path)?))?;
+    let mut validator = Validator {
+        entries: archive.file_names().map(str::to_string).collect(),
+        ..Default::default()
+    };
+
+    validator.check_mimetype(&mut archive)?;
+    let Some(opf_path) = validator.check_container(&mut archive) else {
+        return Ok(validator.issues);
+    };
+    let Some(items) = validator.check_package(&mut archive, &opf_path) else {
+        return Ok(validator.issues);
+    };
+    for item in &items {
+        match item.media_type.as_str() {
+            "application/xhtml+xml" | "application/x-dtbncx+xml" | "image/svg+xml" => {
+                validator.check_document(&mut archive, &item.path)
+            }
+            _ => {}
+        }
+    }
+    validator.check_links();
+
+    Ok(validator.issues)
+}
+
+impl Validator {
+    fn report(&mut self, file: &str, line: Option<u64>, message: String) {
+        self.issues.push(Issue {
+            file: file.to_string(),
+            line,
+            message,
+        });
+    }
+
+    /// Checks that the container starts with the `mimetype` entry, stored uncompressed and without
+    /// extra fields, so that its content can be found at a fixed place in the file.
+    fn check_mimetype<R: Read + Seek>(&mut self, archive: &mut ZipArchive<R>) -> Result<()> {
+        let mut first = archive.by_index(0)?;
+        let name = first.name().to_string();
+        if name != "mimetype" {
+            self.report(
+                &name,
+                None,
+                "the first entry of the container is not `mimetype`".to_string(),
+            );
+            return Ok(());
+        }
+
+        let is_stored = first.compression() == CompressionMethod::Stored;
+        let has_extra_field = first.extra_data().is_some_and(|extra| !extra.is_empty());
+        let mut content = String::new();
+        first.read_to_string(&mut content)?;
+        if !is_stored {
+            self.report(&name, None, "`mimetype` is compressed".to_string());
+        }
+        if has_extra_field {
+            self.report(&name, None, "`mimetype` has an extra field".to_string());
+        }
+        if content != MIMETYPE {
+            self.report(
+                &name,
+                None,
+                format!("`mimetype` holds `{content}` rather than `{MIMETYPE}`"),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the path of the package document the container file names, if it exists.
+    fn check_container<R: Read + Seek>(&mut self, archive: &mut ZipArchive<R>) -> Option<String> {
+        let container_path = "META-INF/container.xml";
+        let Ok(container) = read_entry(archive, container_path) else {
+            self.report(
+                container_path,
+                None,
+                "the container file is missing".to_string(),
+            );
+            return None;
+        };
+
+        let rootfile =
+            Regex::new(r#"<(?:[\w-]+:)?rootfile\b[^>]*\sfull-path\s*=\s*["']([^"']+)["']"#)
+                .unwrap();
+        let Some(opf_path) = rootfile
+            .captures(&container)
+            .and_then(|captures| captures.get(1))
+            .map(|path| path.as_str().to_string())
+        else {
+            self.report(
+                container_path,
+                None,
+                "no package document is named".to_string(),
+            );
+            return None;
+        };
+        if !self.entries.contains(&opf_path) {
+            self.report(
+                container_path,
+                None,
+                format!("the package document `{opf_path}` is missing"),
+            );
+            return None;
+        }
+
+        Some(opf_path)
+    }
+
+    /// Checks the package document at `opf_path` and returns the items of its manifest, if it
+    /// could be read.
+    fn check_package<R: Read + Seek>(
+        &mut self,
+        archive: &mut ZipArchive<R>,
+        opf_path: &str,
+    ) -> Option<Vec<Item>> {
+        let opf = read_entry(archive, opf_path).ok()?;
+        let dir = Path::new(opf_path).parent().unwrap_or(Path::new(""));
+
+        let mut version = None;
+        let mut unique_identifier = None;
+        let mut identifiers = HashSet::new();
+        let mut found = HashSet::new();
+        let mut ids = HashSet::new();
+        let mut items: Vec<Item> = vec![];
+        let mut itemrefs = vec![];
+        let mut spine_toc = None;
+        let mut in_metadata = false;
+        let mut modified = false;
+
+        let mut reader = ParserConfig::new().create_reader(opf.as_bytes());
+        loop {
+            let event = reader.next();
+            let line = reader.position().row + 1;
+            match event {
+                Ok(XmlEvent::StartElement {
+                    name, attributes, ..
+                }) => {
+                    let attribute = |local: &str| {
+                        attributes
+                            .iter()
+                            .find(|attribute| {
+                                attribute.name.local_name == local
+                                    && attribute.name.prefix.is_none()
+                            })
+                            .map(|attribute| attribute.value.clone())
+                    };
+                    if let Some(id) = attribute("id") {
+                        if !ids.insert(id.clone()) {
+                            self.report(opf_path, Some(line), format!("the id `{id}` is repeated"));
+                        }
+                    }
+
+                    match name.local_name.as_str() {
+                        "package" => {
+                            version = attribute("version");
+                            unique_identifier = attribute("unique-identifier");
+                        }
+                        "metadata" => in_metadata = true,
+                        "identifier" if in_metadata => {
+                            identifiers.extend(attribute("id"));
+                            found.insert("identifier");
+                        }
+                        "title" if in_metadata => {
+                            found.insert("title");
+                        }
+                        "language" if in_metadata => {
+                            found.insert("language");
+                        }
+                        "meta"
+                            if attribute("property").as_deref() == Some("dcterms:modified")
+                                && attribute("refines").is_none() =>
+                        {
+                            modified = true;
+                        }
+                        "manifest" => {
+                            found.insert("manifest");
+                        }
+                        "item" => {
+                            match (attribute("id"), attribute("href"), attribute("media-type")) {
+                                (Some(id), Some(href), Some(media_type)) => items.push(Item {
+                                    id,
+                                    path: resolve(dir, &href),
+                                    media_type,
+                                    line,
+                                }),
+                                _ => self.report(
+                                    opf_path,
+                                    Some(line),
+                                    "a manifest item lacks an id, href or media type".to_string(),
+                                ),
+                            }
+                        }
+                        "spine" => {
+                            found.insert("spine");
+                            spine_toc = attribute("toc");
+                        }
+                        "itemref" => match attribute("idref") {
+                            Some(idref) => itemrefs.push((idref, line)),
+                            None => self.report(
+                                opf_path,
+                                Some(line),
+                                "a spine entry lacks an idref".to_string(),
+                            ),
+                        },
+                        _ => {}
+                    }
+                }
+                Ok(XmlEvent::EndElement { name }) if name.local_name == "metadata" => {
+                    in_metadata = false;
+                }
+                Ok(XmlEvent::EndDocument) => break,
+                Ok(_) => {}
+                Err(error) => {
+                    self.report(
+                        opf_path,
+                        Some(error.position().row + 1),
+                        format!("not well-formed: {}", error.msg()),
+                    );
+                    return None;
+                }
+            }
+        }
+
+        if version.is_none() {
+            self.report(opf_path, None, "the package has no version".to_string());
+        }
+        match &unique_identifier {
+            Some(id) if identifiers.contains(id) => {}
+            Some(id) => self.report(
+                opf_path,
+                None,
+                format!("the unique identifier `{id}` is not the id of an identifier"),
+            ),
+            None => self.report(
+                opf_path,
+                None,
+                "the package names no unique identifier".to_string(),
+            ),
+        }
+        for element in ["identifier", "title", "language", "manifest", "spine"] {
+            if !found.contains(element) {
+                self.report(opf_path, None, format!("the package has no {element}"));
+            }
+        }
+        let is_epub3 = version.is_some_and(|version| version.starts_with('3'));
+        if is_epub3 && !modified {
+            self.report(
+                opf_path,
+                None,
+                "the package has no modification date (`dcterms:modified`)".to_string(),
+            );
+        }
+
+        let mut paths = HashSet::new();
+        for item in &items {
+            if !self.entries.contains(&item.path) {
+                self.report(
+                    opf_path,
+                    Some(item.line),
+                    format!(
+                        "the file `{}` of manifest item `{}` is missing",
+                        item.path, item.id
+                    ),
+                );
+            }
+            if !paths.insert(item.path.as_str()) {
+                self.report(
+                    opf_path,
+                    Some(item.line),
+                    format!("the file `{}` is in the manifest more than once", item.path),
+                );
+            }
+        }
+        let item_ids: HashSet<&str> = items.iter().map(|item| item.id.as_str()).collect();
+        for (idref, line) in &itemrefs {
+            if !item_ids.contains(idref.as_str()) {
+                self.report(
+                    opf_path,
+                    Some(*line),
+                    format!("the spine entry `{idref}` is not in the manifest"),
+                );
+            }
+        }
+        if itemrefs.is_empty() {
+            self.report(opf_path, None, "the spine is empty".to_string());
+        }
+        if let Some(toc) = spine_toc.filter(|toc| !item_ids.contains(toc.as_str())) {
+            self.report(
+                opf_path,
+                None,
+                format!("the NCX `{toc}` of the spine is not in the manifest"),
+            );
+        }
+
+        Some(items)
+    }
+
+    /// Checks that the XML document at `path` is well-formed and has no repeated ids, and collects
+    /// its ids and links.
+    fn check_document<R: Read + Seek>(&mut self, archive: &mut ZipArchive<R>, path: &str) {
+        let Ok(document) = read_entry(archive, path) else {
+            return;
+        };
+        let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+
+        // Documents with a document type may use the entities it declares, such as `&nbsp;`
+        let has_doctype = document.contains("<!DOCTYPE") || document.contains("<!doctype");
+        let mut reader = ParserConfig::new()
+            .replace_unknown_entity_references(has_doctype)
+            .create_reader(document.as_bytes());
+        let mut ids = HashSet::new();
+        let mut links = vec![];
+        loop {
+            let event = reader.next();
+            let line = reader.position().row + 1;
+            match event {
+                Ok(XmlEvent::StartElement { attributes, .. }) => {
+                    for attribute in attributes {
+                        match attribute.name.local_name.as_str() {
+                            "id" if !ids.insert(attribute.value.clone()) => self.report(
+                                path,
+                                Some(line),
+                                format!("the id `{}` is repeated", attribute.value),
+                            ),
+                            "href" | "src" => links.push(Link {
+                                file: path.to_string(),
+                                line,
+                                href: attribute.value,
+                            }),
+                            _ => {}
+                        }
+                    }
+                }
+                Ok(XmlEvent::EndDocument) => break,
+                Ok(_) => {}
+                Err(error) => {
+                    self.report(
+                        path,
+                        Some(error.position().row + 1),
+                        format!("not well-formed: {}", error.msg()),
+                    );
+                    return;
+                }
+            }
+        }
+
+        // Links are resolved against the document they are in
+        for link in &mut links {
+            if !is_external(&link.href) {
+                let (target, fragment) = link.href.split_once('#').unwrap_or((&link.href, ""));
+                let target = match target {
+                    "" => path.to_string(),
+                    target => resolve(dir, target),
+                };
+                link.href = match fragment {
+                    "" => target,
+                    fragment => format!("{target}#{fragment}"),
+                };
+            }
+        }
+        self.ids.insert(path.to_string(), ids);
+        self.links.extend(links);
+    }
+
+    /// Checks that the links between the files of the book lead to files and fragments that exist.
+    fn check_links(&mut self) {
+        for link in std::mem::take(&mut self.links) {
+            if is_external(&link.href) {
+                continue;
+            }
+            let (target, fragment) = link.href.split_once('#').unwrap_or((&link.href, ""));
+
+            if !self.entries.contains(target) {
+                self.report(
+                    &link.file,
+                    Some(link.line),
+                    format!("the link to `{target}` leads to a file that is missing"),
+                );
+            } else if let Some(ids) = self.ids.get(target) {
+                let fragment = decode(fragment);
+                if !fragment.is_empty()
+                    && !fragment.starts_with("epubcfi(")
+                    && !ids.contains(&fragment)
+                {
+                    self.report(
+                        &link.file,
+                        Some(link.line),
+                        format!("the link to `{target}#{fragment}` leads to an id that is missing"),
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,2 +1,54 @@
+use std::{future::Future, sync::Arc};
+
+use eyre::Result;
+use futures::{
+    stream::{self, StreamExt},
+    TryStreamExt,
+};
+use tokio::sync::Semaphore;
+
+use crate::options::RequestOptions;
+
+mod entities;
 pub mod epub;
 pub mod pdf;
+mod protect;
+
+/// Sends `snippets` through `edit_func` in concurrent batches and returns the edited snippets in
+/// their original order.
+pub(crate) async fn edit_snippets<F, Fut>(
+    request_options: &RequestOptions,
+    edit_func: &Arc<F>,
+    snippets: Vec<String>,
+) -> Result<Vec<String>>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    if snippets.is_empty() {
+        return Ok(snippets);
+    }
+
+    let semaphore = Arc::new(Semaphore::new(request_options.max_concurrency));
+    let batches: Vec<Vec<String>> = snippets
+        .chunks(request_options.batch_size)
+        .map(<[String]>::to_vec)
+        .collect();
+
+    // `buffered` rather than `buffer_unordered`: callers zip the results back onto pages, outline
+    // items and segments of HTML, so the batches have to come back in the order they were sent
+    let results: Vec<Vec<String>> = stream::iter(batches)
+        .map(|batch| {
+            let edit_func = Arc::clone(edit_func);
+            let semaphore = Arc::clone(&semaphore);
+            async move {
+                let _permit = semaphore.acquire().await.unwrap();
+                edit_func(batch).await
+            }
+        })
+        .buffered(request_options.max_concurrency)
+        .try_collect()
+        .await?;
+
+    Ok(results.into_iter().flatten().collect())
+}
@@ -0,0 +1,395 @@
+use std::{future::Future, sync::Arc};
+
+use eyre::Result;
+use lopdf::{
+    content::{Content, Operation},
+    dictionary, text_string, Dictionary, Document, Object, ObjectId, Stream,
+};
+
+use super::{
+    edit_snippets, encode_win_ansi,
+    layout::{self, Rect, Region},
+    ocr, paragraphs, string_width,
+    table::TABLE_FONT_SIZE,
+    text, wrap_text, BODY_FONT_SIZE,
+};
+use crate::options::{PdfOptions, RequestOptions};
+
+/// Resource name of the font in the annotations' appearance streams and default appearance
+const FONT_NAME: &str = "Helv";
+
+/// Translations that only fit the block below this font size become sticky notes instead
+const MIN_FONT_SIZE: f64 = 5.0;
+
+/// Each step of fitting the translation into its block shrinks the font by this factor
+const FONT_SHRINK_FACTOR: f64 = 0.9;
+
+/// Height of Helvetica's capitals and ascenders, in ems
+const ASCENT: f64 = 0.8;
+
+/// Distance between baselines, in ems
+const LINE_SPACING: f64 = 1.15;
+
+/// Margin the annotations add around their blocks
+const PADDING: f64 = 1.0;
+
+/// Annotation flag that makes viewers print the annotation
+const PRINT_FLAG: i64 = 1 << 2;
+
+/// A region of a page whose translation is added as an annotation
+struct Target {
+    page_id: ObjectId,
+    bbox: Rect,
+    font_size: f64,
+    kind: TargetKind,
+}
+
+enum TargetKind {
+    /// A text block, translated as a whole
+    Block(String),
+    /// A table, whose cells are translated one by one and joined row by row
+    Table(Vec<Vec<String>>),
+}
+
+/// Translates `doc` without touching its pages: every text block and table found on a page gets an
+/// annotation over it holding the translation. The annotations belong to an optional content
+/// group, so viewers can show and hide the translation as a layer.
+///
+/// Translations are set as `/FreeText` annotations sized to the block. One that would have to be
+/// shrunk below a readable size to fit becomes a `/Text` annotation (a sticky note) at the top left
+/// corner of the block instead.
+pub(super) async fn annotate_pdf<F, Fut>(
+    mut doc: Document,
+    request_options: &RequestOptions,
+    pdf_options: &PdfOptions,
+    edit_func: &Arc<F>,
+) -> Result<Document>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let mut targets = vec![];
+    let mut page_regions = vec![];
+
+    for (page_num, page_id) in doc.get_pages() {
+        if !pdf_options.is_page_selected(page_num) {
+            continue;
+        }
+
+        let regions = match text::extract_content(&doc, page_id) {
+            Ok(mut content) => {
+                if let Some(backend) = &pdf_options.ocr {
+                    ocr::recognize_scanned_page(&doc, &mut content, backend.as_ref(), page_num);
+                }
+                layout::analyze_page(content)
+            }
+            Err(err) => {
+                tracing::warn!("Unable to analyze the layout of page {page_num}: {err}");
+                vec![]
+            }
+        };
+
+        targets.extend(regions.iter().filter_map(|region| target(page_id, region)));
+        page_regions.push((page_id, regions));
+    }
+
+    if let Some(path) = &pdf_options.layout_debug {
+        layout::write_debug_overlay(&doc, &page_regions, path)?;
+    }
+
+    let snippets = targets
+        .iter()
+        .flat_map(|target| match &target.kind {
+            TargetKind::Block(text) => vec![text.clone()],
+            TargetKind::Table(rows) => rows
+                .iter()
+                .flatten()
+                .filter(|cell| !cell.is_empty())
+                .cloned()
+                .collect(),
+        })
+        .collect();
+    let mut translations = edit_snippets(request_options, edit_func, snippets)
+        .await?
+        .into_iter();
+
+    let font_id = doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica",
+        "Encoding" => "WinAnsiEncoding",
+    });
+    let layer_id = add_translation_layer(&mut doc, pdf_options.language.as_deref())?;
+
+    for target in targets {
+        let translation = match target.kind {
+            TargetKind::Block(text) => translations.next().unwrap_or(text),
+            TargetKind::Table(rows) => rows
+                .into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .filter(|cell| !cell.is_empty())
+                        .map(|cell| translations.next().unwrap_or(cell))
+                        .collect::<Vec<_>>()
+                        .join(" | ")
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+
+        let mut annotation = match fit_text(&translation, &target.bbox, target.font_size) {
+            Some((font_size, lines)) => {
+                let rect = padded(&target.bbox);
+                let appearance = appearance_stream(&rect, font_size, &lines, font_id)?;
+                let appearance_id = doc.add_object(appearance);
+                free_text_annotation(&rect, font_size, &translation, appearance_id)
+            }
+            None => sticky_note_annotation(&target.bbox, &translation),
+        };
+        annotation.set("P", target.page_id);
+        annotation.set("OC", layer_id);
+        let annotation_id = doc.add_object(annotation);
+        add_page_annotation(&mut doc, target.page_id, annotation_id)?;
+    }
+
+    Ok(doc)
+}
+
+fn target(page_id: ObjectId, region: &Region) -> Option<Target> {
+    let (font_size, kind) = match region {
+        Region::Text(block) => {
+            let mut text = String::new();
+            for line in &block.lines {
+                paragraphs::join_line(&mut text, &line.text());
+            }
+            let font_size = block
+                .lines
+                .iter()
+                .map(|line| line.font_size)
+                .fold(0.0, f64::max);
+            (font_size, TargetKind::Block(text.replace('\n', " ")))
+        }
+        Region::Table(table) => {
+            let row_height = table.bbox.height() / table.rows.len().max(1) as f64;
+            let font_size = (row_height / LINE_SPACING).min(TABLE_FONT_SIZE);
+            (font_size, TargetKind::Table(table.rows.clone()))
+        }
+        Region::Image(_) => return None,
+    };
+
+    Some(Target {
+        page_id,
+        bbox: region.bbox(),
+        font_size,
+        kind,
+    })
+}
+
+/// Finds the largest font size up to `font_size` at which `text` fits into `bbox`, and the lines
+/// it wraps into at that size. Returns `None` if it only fits below [`MIN_FONT_SIZE`].
+fn fit_text(text: &str, bbox: &Rect, font_size: f64) -> Option<(f64, Vec<String>)> {
+    let (width, height) = (bbox.width(), bbox.height());
+    let mut font_size = font_size.max(MIN_FONT_SIZE);
+
+    while font_size >= MIN_FONT_SIZE {
+        let lines = wrap_text(text, font_size, width);
+        let fits_width = lines
+            .iter()
+            .all(|line| string_width(line) * font_size / BODY_FONT_SIZE <= width);
+        // the last line only needs room for its own height
+        let text_height = (lines.len().max(1) - 1) as f64 * font_size * LINE_SPACING + font_size;
+        if fits_width && text_height <= height {
+            return Some((font_size, lines));
+        }
+
+        font_size *= FONT_SHRINK_FACTOR;
+    }
+
+    None
+}
+
+fn padded(bbox: &Rect) -> Rect {
+    Rect {
+        x0: bbox.x0 - PADDING,
+        y0: bbox.y0 - PADDING,
+        x1: bbox.x1 + PADDING,
+        y1: bbox.y1 + PADDING,
+    }
+}
+
+/// Draws the translation on an opaque background, so that it hides the original text under it.
+fn appearance_stream(
+    rect: &Rect,
+    font_size: f64,
+    lines: &[String],
+    font_id: ObjectId,
+) -> Result<Stream> {
+    let mut operations = vec![
+        Operation::new("q", vec![]),
+        Operation::new("g", vec![1.into()]),
+        Operation::new(
+            "re",
+            vec![
+                0.into(),
+                0.into(),
+                rect.width().into(),
+                rect.height().into(),
+            ],
+        ),
+        Operation::new("f", vec![]),
+        Operation::new("BT", vec![]),
+        Operation::new("g", vec![0.into()]),
+        Operation::new("Tf", vec![FONT_NAME.into(), font_size.into()]),
+        Operation::new("TL", vec![(font_size * LINE_SPACING).into()]),
+        Operation::new(
+            "Td",
+            vec![
+                PADDING.into(),
+                (rect.height() - PADDING - font_size * ASCENT).into(),
+            ],
+        ),
+    ];
+    for (index, line) in lines.iter().enumerate() {
+        if index > 0 {
+            operations.push(Operation::new("T*", vec![]));
+        }
+        operations.push(Operation::new(
+            "Tj",
+            vec![Object::string_literal(encode_win_ansi(line))],
+        ));
+    }
+    operations.extend([Operation::new("ET", vec![]), Operation::new("Q", vec![])]);
+
+    let mut stream = Stream::new(
+        dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Form",
+            "BBox" => vec![0.into(), 0.into(), rect.width().into(), rect.height().into()],
+            "Resources" => dictionary! {
+                "Font" => dictionary! { FONT_NAME => font_id },
+            },
+        },
+        Content { operations }.encode()?,
+    );
+    let _ = stream.compress();
+
+    Ok(stream)
+}
+
+fn free_text_annotation(
+    rect: &Rect,
+    font_size: f64,
+    translation: &str,
+    appearance_id: ObjectId,
+) -> Dictionary {
+    dictionary! {
+        "Type" => "Annot",
+        "Subtype" => "FreeText",
+        "Rect" => rect_array(rect),
+        "Contents" => text_string(translation),
+        "DA" => Object::string_literal(format!("/{FONT_NAME} {font_size:.2} Tf 0 g")),
+        "F" => PRINT_FLAG,
+        "BS" => dictionary! { "W" => 0 },
+        "AP" => dictionary! { "N" => appearance_id },
+    }
+}
+
+/// A note icon at the top left corner of the block, which opens the translation in a popup.
+fn sticky_note_annotation(bbox: &Rect, translation: &str) -> Dictionary {
+    let icon = Rect {
+        x0: bbox.x0,
+        y0: bbox.y1 - 20.0,
+        x1: bbox.x0 + 20.0,
+        y1: bbox.y1,
+    };
+
+    dictionary! {
+        "Type" => "Annot",
+        "Subtype" => "Text",
+        "Rect" => rect_array(&icon),
+        "Contents" => text_string(translation),
+        "Name" => "Comment",
+        "F" => PRINT_FLAG,
+    }
+}
+
+fn rect_array(rect: &Rect) -> Vec<Object> {
+    vec![
+        rect.x0.into(),
+        rect.y0.into(),
+        rect.x1.into(),
+        rect.y1.into(),
+    ]
+}
+
+/// Adds an optional content group for the translation annotations to the document, visible by
+/// default, and returns its id.
+fn add_translation_layer(doc: &mut Document, language: Option<&str>) -> Result<ObjectId> {
+    let name = match language {
+        Some(language) => format!("Translation ({language})"),
+        None => "Translation".to_string(),
+    };
+    let layer_id = doc.add_object(dictionary! {
+        "Type" => "OCG",
+        "Name" => text_string(&name),
+    });
+
+    let mut properties = doc
+        .catalog()?
+        .get(b"OCProperties")
+        .and_then(|properties| doc.dereference(properties))
+        .and_then(|(_, properties)| properties.as_dict())
+        .cloned()
+        .unwrap_or_default();
+    let mut layers = properties
+        .get(b"OCGs")
+        .and_then(Object::as_array)
+        .cloned()
+        .unwrap_or_default();
+    layers.push(layer_id.into());
+    properties.set("OCGs", layers);
+
+    let mut config = properties
+        .get(b"D")
+        .and_then(|config| doc.dereference(config))
+        .and_then(|(_, config)| config.as_dict())
+        .cloned()
+        .unwrap_or_default();
+    // without an `/Order` entry viewers don't list the group in their layers panel
+    if let Ok(Object::Array(order)) = config.get_mut(b"Order") {
+        order.push(layer_id.into());
+    } else {
+        config.set("Order", vec![layer_id.into()]);
+    }
+    properties.set("D", config);
+
+    doc.catalog_mut()?.set("OCProperties", properties);
+
+    Ok(layer_id)
+}
+
+fn add_page_annotation(
+    doc: &mut Document,
+    page_id: ObjectId,
+    annotation_id: ObjectId,
+) -> Result<()> {
+    let annotations = doc.get_dictionary(page_id)?.get(b"Annots").ok().cloned();
+
+    match annotations {
+        Some(Object::Reference(array_id)) => {
+            if let Object::Array(annotations) = doc.get_object_mut(array_id)? {
+                annotations.push(annotation_id.into());
+            }
+        }
+        Some(Object::Array(mut annotations)) => {
+            annotations.push(annotation_id.into());
+            doc.get_dictionary_mut(page_id)?.set("Annots", annotations);
+        }
+        _ => {
+            doc.get_dictionary_mut(page_id)?
+                .set("Annots", vec![annotation_id.into()]);
+        }
+    }
+
+    Ok(())
+}
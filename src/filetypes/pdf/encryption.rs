@@ -0,0 +1,342 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use eyre::{eyre, Result};
+use lopdf::{
+    encryption::{
+        crypt_filters::{Aes256CryptFilter, CryptFilter},
+        PasswordAlgorithm,
+    },
+    Document, EncryptionState, EncryptionVersion, Object, Permissions,
+};
+use md5::{Digest, Md5};
+use rand::Rng;
+
+/// Name of the crypt filter that encrypted output uses for both strings and streams
+const CRYPT_FILTER_NAME: &[u8] = b"StdCF";
+
+/// Length of the random owner password given to output whose owner password isn't known
+const RANDOM_PASSWORD_LENGTH: usize = 32;
+
+/// Padding that the legacy security handlers append to passwords shorter than 32 bytes
+const PASSWORD_PADDING: [u8; 32] = [
+    0x28, 0xbf, 0x4e, 0x5e, 0x4e, 0x75, 0x8a, 0x41, 0x64, 0x00, 0x4e, 0x56, 0xff, 0xfa, 0x01, 0x08,
+    0x2e, 0x2e, 0x00, 0xb6, 0xd0, 0x68, 0x3e, 0x80, 0x2f, 0x0c, 0xa9, 0xfe, 0x64, 0x53, 0x69, 0x7a,
+];
+
+/// Passwords and permissions to encrypt a written PDF with
+#[derive(Clone, Debug)]
+pub struct PdfEncryption {
+    /// Password needed to open the document, which may be empty
+    pub user_password: String,
+    /// Password needed to lift the permissions
+    pub owner_password: String,
+    pub permissions: Permissions,
+}
+
+impl PdfEncryption {
+    /// Encryption for output that should be as protected as `source` was. `password` is the one
+    /// `source` was opened with, if any: the output opens with it if it was the user password, and
+    /// its permissions can be lifted with it if it was the owner password. Without an owner
+    /// password, the output gets a random one, so that its permissions hold.
+    pub fn like_source(source: &Document, password: Option<&str>) -> Self {
+        let state = source.encryption_state.as_ref();
+        let permissions = state.map_or_else(Permissions::all, EncryptionState::permissions);
+
+        let is_owner_password = match (state, password) {
+            (Some(state), Some(password)) => is_owner_password(source, state, password),
+            _ => false,
+        };
+        let (user_password, owner_password) = match password {
+            Some(password) if is_owner_password => (String::new(), password.to_string()),
+            Some(password) => (password.to_string(), random_password()),
+            None => (String::new(), random_password()),
+        };
+
+        Self {
+            user_password,
+            owner_password,
+            permissions,
+        }
+    }
+}
+
+/// Whether `password` is the owner password of `source`, which has already been decrypted. The
+/// decryption removes the encryption dictionary the password is checked against, so it is rebuilt
+/// from `state` in an otherwise empty document.
+fn is_owner_password(source: &Document, state: &EncryptionState, password: &str) -> bool {
+    let Ok(encryption_dict) = state.encode() else {
+        return false;
+    };
+
+    let mut probe = Document::with_version(source.version.clone());
+    if let Ok(file_id) = source.trailer.get(b"ID") {
+        probe.trailer.set("ID", file_id.clone());
+    }
+    let encryption_id = probe.add_object(encryption_dict);
+    probe.trailer.set("Encrypt", encryption_id);
+
+    probe.authenticate_owner_password(password).is_ok()
+}
+
+/// Parses a comma separated list of permissions, such as `print,copy`, for encrypted output.
+pub fn parse_permissions(value: &str) -> Result<Permissions, String> {
+    let mut permissions = Permissions::empty();
+
+    for name in value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        permissions |= match name {
+            "print" => Permissions::PRINTABLE,
+            "print-high" => Permissions::PRINTABLE | Permissions::PRINTABLE_IN_HIGH_QUALITY,
+            "modify" => Permissions::MODIFIABLE,
+            "copy" => Permissions::COPYABLE,
+            "annotate" => Permissions::ANNOTABLE,
+            "fill" => Permissions::FILLABLE,
+            "assemble" => Permissions::ASSEMBLABLE,
+            "all" => Permissions::all(),
+            "none" => Permissions::empty(),
+            name => return Err(format!("unknown permission `{name}`")),
+        };
+    }
+
+    // readers have to allow extraction for assistive technology whatever this says
+    Ok(permissions | Permissions::COPYABLE_FOR_ACCESSIBILITY)
+}
+
+/// Decrypts `doc` if it is still encrypted after loading, which happens when it can't be opened
+/// with an empty user password.
+pub(super) fn decrypt(doc: &mut Document, password: Option<&str>) -> Result<()> {
+    if !doc.is_encrypted() {
+        return Ok(());
+    }
+
+    let password = password
+        .ok_or_else(|| eyre!("The PDF is encrypted and can't be opened without its password"))?;
+
+    // lopdf derives the key of the RC4 and AES-128 handlers from any password as if it were the
+    // user password, so the user password is recovered from the owner password first
+    let result = match legacy_user_password(doc, password) {
+        Some(user_password) => doc.decrypt_raw(user_password),
+        None => doc.decrypt(password),
+    };
+    result.map_err(|err| eyre!("Unable to decrypt the PDF: {err}"))?;
+
+    Ok(())
+}
+
+/// The padded user password of a document encrypted by a security handler of revision 2 to 4, if
+/// `password` is its owner password rather than its user password.
+///
+/// This is algorithm 7 of ISO 32000-2, up to where the recovered user password is checked.
+fn legacy_user_password(doc: &Document, password: &str) -> Option<Vec<u8>> {
+    let encryption_dict = doc.get_encrypted().ok()?;
+    let revision = encryption_dict.get(b"R").and_then(Object::as_i64).ok()?;
+    if !(2..=4).contains(&revision)
+        || doc.authenticate_user_password(password).is_ok()
+        || doc.authenticate_owner_password(password).is_err()
+    {
+        return None;
+    }
+
+    let owner_value = encryption_dict.get(b"O").and_then(Object::as_str).ok()?;
+    let key_length = match revision {
+        2 => 5,
+        _ => encryption_dict
+            .get(b"Length")
+            .and_then(Object::as_i64)
+            .map_or(5, |bits| bits as usize / 8)
+            .min(16),
+    };
+
+    let password = PasswordAlgorithm::try_from(doc)
+        .ok()?
+        .sanitize_password(password)
+        .ok()?;
+    let password = &password[..password.len().min(PASSWORD_PADDING.len())];
+    let mut hash = Md5::new()
+        .chain_update(password)
+        .chain_update(&PASSWORD_PADDING[..PASSWORD_PADDING.len() - password.len()])
+        .finalize();
+    if revision >= 3 {
+        for _ in 0..50 {
+            hash = Md5::digest(hash);
+        }
+    }
+    let key = &hash[..key_length];
+
+    let mut user_password = owner_value.to_vec();
+    if revision >= 3 {
+        for counter in (1..=19).rev() {
+            let round_key: Vec<u8> = key.iter().map(|byte| byte ^ counter).collect();
+            user_password = rc4(&round_key, &user_password);
+        }
+    }
+
+    Some(rc4(key, &user_password))
+}
+
+/// Encrypts or decrypts `data` with the RC4 stream cipher.
+fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut state: [u8; 256] = std::array::from_fn(|i| i as u8);
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+        state.swap(i, j as usize);
+    }
+
+    let (mut i, mut j) = (0u8, 0u8);
+    data.iter()
+        .map(|byte| {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(state[i as usize]);
+            state.swap(i as usize, j as usize);
+            byte ^ state[state[i as usize].wrapping_add(state[j as usize]) as usize]
+        })
+        .collect()
+}
+
+/// Encrypts `doc` with AES-256, the standard security handler of PDF 2.0.
+pub(super) fn encrypt(doc: &mut Document, encryption: &PdfEncryption) -> Result<()> {
+    let mut file_encryption_key = [0u8; 32];
+    rand::rng().fill(&mut file_encryption_key);
+
+    let crypt_filter: Arc<dyn CryptFilter> = Arc::new(Aes256CryptFilter);
+    let version = EncryptionVersion::V5 {
+        encrypt_metadata: true,
+        crypt_filters: BTreeMap::from([(CRYPT_FILTER_NAME.to_vec(), crypt_filter)]),
+        file_encryption_key: &file_encryption_key,
+        stream_filter: CRYPT_FILTER_NAME.to_vec(),
+        string_filter: CRYPT_FILTER_NAME.to_vec(),
+        owner_password: &encryption.owner_password,
+        user_password: &encryption.user_password,
+        permissions: encryption.permissions,
+    };
+    let state = EncryptionState::try_from(version)?;
+
+    // AES-256 was introduced with PDF 2.0, though readers accept it in 1.7 files
+    if doc.version.as_str() < "1.7" {
+        doc.version = "1.7".to_string();
+    }
+    doc.encrypt(&state)?;
+
+    Ok(())
+}
+
+fn random_password() -> String {
+    rand::rng()
+        .sample_iter(rand::distr::Alphanumeric)
+        .take(RANDOM_PASSWORD_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use lopdf::ObjectId;
+
+    use super::*;
+
+    const USER_PASSWORD: &str = "reader";
+    const OWNER_PASSWORD: &str = "author";
+
+    /// A document with a single string, encrypted by the RC4 security handler of `revision` 2 or 3.
+    fn encrypted(revision: i64) -> (Document, ObjectId) {
+        let mut doc = Document::with_version("1.5");
+        let file_id = Object::string_literal("0123456789abcdef");
+        doc.trailer.set("ID", vec![file_id.clone(), file_id]);
+        let text_id = doc.add_object(Object::string_literal("Secret"));
+
+        let version = match revision {
+            2 => EncryptionVersion::V1 {
+                document: &doc,
+                owner_password: OWNER_PASSWORD,
+                user_password: USER_PASSWORD,
+                permissions: Permissions::PRINTABLE,
+            },
+            _ => EncryptionVersion::V2 {
+                document: &doc,
+                owner_password: OWNER_PASSWORD,
+                user_password: USER_PASSWORD,
+                key_length: 128,
+                permissions: Permissions::PRINTABLE,
+            },
+        };
+        let state = EncryptionState::try_from(version).unwrap();
+        doc.encrypt(&state).unwrap();
+
+        (doc, text_id)
+    }
+
+    fn padded(password: &str) -> Vec<u8> {
+        let mut padded = password.as_bytes().to_vec();
+        padded.extend_from_slice(&PASSWORD_PADDING[..PASSWORD_PADDING.len() - password.len()]);
+        padded
+    }
+
+    #[test]
+    fn recovers_the_user_password_from_the_owner_password() {
+        for revision in [2, 3] {
+            let (doc, _) = encrypted(revision);
+            assert_eq!(
+                legacy_user_password(&doc, OWNER_PASSWORD),
+                Some(padded(USER_PASSWORD)),
+                "revision {revision}"
+            );
+            assert_eq!(legacy_user_password(&doc, USER_PASSWORD), None);
+            assert_eq!(legacy_user_password(&doc, "wrong"), None);
+        }
+    }
+
+    #[test]
+    fn decrypts_with_either_password() {
+        for password in [USER_PASSWORD, OWNER_PASSWORD] {
+            let (mut doc, text_id) = encrypted(3);
+            decrypt(&mut doc, Some(password)).unwrap();
+            assert_eq!(
+                doc.get_object(text_id).and_then(Object::as_str).unwrap(),
+                b"Secret"
+            );
+        }
+
+        let (mut doc, _) = encrypted(3);
+        assert!(decrypt(&mut doc, Some("wrong")).is_err());
+        assert!(decrypt(&mut doc, None).is_err());
+    }
+
+    #[test]
+    fn rc4_matches_known_ciphertexts() {
+        assert_eq!(
+            rc4(b"Key", b"Plaintext"),
+            [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]
+        );
+        assert_eq!(
+            rc4(b"Secret", b"Attack at dawn"),
+            [0x45, 0xa0, 0x1f, 0x64, 0x5f, 0xc3, 0x5b, 0x38, 0x35, 0x52, 0x54, 0x4b, 0x9b, 0xf5]
+        );
+        assert_eq!(rc4(b"Key", &rc4(b"Key", b"round trip")), b"round trip");
+    }
+
+    #[test]
+    fn parses_permissions() {
+        assert_eq!(
+            parse_permissions("print, copy").unwrap(),
+            Permissions::PRINTABLE
+                | Permissions::COPYABLE
+                | Permissions::COPYABLE_FOR_ACCESSIBILITY
+        );
+        assert_eq!(
+            parse_permissions("print-high").unwrap(),
+            Permissions::PRINTABLE
+                | Permissions::PRINTABLE_IN_HIGH_QUALITY
+                | Permissions::COPYABLE_FOR_ACCESSIBILITY
+        );
+        assert_eq!(
+            parse_permissions("").unwrap(),
+            Permissions::COPYABLE_FOR_ACCESSIBILITY
+        );
+        assert_eq!(parse_permissions("all").unwrap(), Permissions::all());
+        assert!(parse_permissions("print,fly").is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use lopdf::content::Operation;
+use regex::Regex;
+
+use super::{
+    create_new_page,
+    layout::{Block, Line, Region},
+    line_operations,
+    linebreak::{self, BrokenLine},
+    paragraphs::join_line,
+    structure,
+    styles::{self, Emphasis, Word},
+    PagesState, BODY_FONT_SIZE, LEFT_MARGIN,
+};
+use crate::options::PdfOptions;
+
+/// Text set at most this fraction of the size of the body text may be a footnote
+const FOOTNOTE_SIZE_RATIO: f64 = 0.9;
+
+/// Size footnotes are set in on reflowed pages
+const FONT_SIZE: f64 = 9.0;
+
+/// Width of the rule above the footnotes of a page, as a fraction of the width of the text
+const SEPARATOR_RATIO: f64 = 1.0 / 3.0;
+
+/// A footnote or endnote taken out of the text of a source page
+#[derive(Clone, Debug)]
+pub(super) struct Footnote {
+    /// The mark that refers to the note in the text, such as `1` or `*`
+    pub(super) marker: String,
+    /// The text of the note, marked up by [`styles`]
+    pub(super) text: String,
+}
+
+/// A footnote broken into lines, waiting for a place at the bottom of an output page
+#[derive(Debug)]
+pub(super) struct NoteLayout {
+    marker: String,
+    lines: Vec<BrokenLine>,
+}
+
+/// A footnote placed at the bottom of an output page
+#[derive(Debug)]
+pub(super) struct PlacedNote {
+    page: usize,
+    element: usize,
+    lines: Vec<BrokenLine>,
+}
+
+/// Takes the footnotes out of the regions of a page: blocks set smaller than the body text, below
+/// all of it, whose lines start with a marker such as a superscript number or an asterisk. Each
+/// marker starts a note, which runs until the next one.
+pub(super) fn take_footnotes(regions: &mut Vec<Region>, body_font_size: f64) -> Vec<Footnote> {
+    let is_small = |block: &Block| {
+        block
+            .lines
+            .iter()
+            .all(|line| line.font_size <= body_font_size * FOOTNOTE_SIZE_RATIO)
+    };
+    let lowest_body = regions
+        .iter()
+        .filter_map(|region| match region {
+            Region::Text(block) if !is_small(block) => Some(block.bbox.y0),
+            Region::Table(table) => Some(table.bbox.y0),
+            _ => None,
+        })
+        .reduce(f64::min);
+    // a page without body text has nothing for notes to be below
+    let Some(lowest_body) = lowest_body else {
+        return vec![];
+    };
+
+    let mut notes = vec![];
+    regions.retain(|region| match region {
+        Region::Text(block) if is_small(block) && block.bbox.y1 <= lowest_body => {
+            match split_notes(block) {
+                Some(block_notes) => {
+                    notes.extend(block_notes);
+                    false
+                }
+                None => true,
+            }
+        }
+        _ => true,
+    });
+
+    notes
+}
+
+/// Splits a block of footnotes into notes at the lines that start with a marker, or returns `None`
+/// if the block doesn't start with one.
+fn split_notes(block: &Block) -> Option<Vec<Footnote>> {
+    let marker_pattern = Regex::new(r"^(\d{1,3}|[*†‡§¶]{1,3})[.)]?\s").unwrap();
+    let mut notes: Vec<Footnote> = vec![];
+
+    for line in &block.lines {
+        match (line_marker(line, &marker_pattern), notes.last_mut()) {
+            (Some((marker, prefix_len)), _) => notes.push(Footnote {
+                marker,
+                text: styles::mark_up_line(&without_prefix(line, prefix_len)),
+            }),
+            (None, Some(note)) => join_line(&mut note.text, &styles::mark_up_line(line)),
+            (None, None) => return None,
+        }
+    }
+
+    for note in &mut notes {
+        note.text = styles::join_spans(&note.text);
+    }
+
+    Some(notes)
+}
+
+/// The marker a footnote line starts with, either set as a superscript or followed by a space,
+/// and how many characters other than whitespace it and any punctuation after it take up.
+fn line_marker(line: &Line, marker_pattern: &Regex) -> Option<(String, usize)> {
+    if let Some(run) = line
+        .runs
+        .first()
+        .filter(|run| styles::is_superscript(run, line))
+    {
+        let marker = run.text.trim().to_string();
+        let prefix_len = marker.chars().filter(|c| !c.is_whitespace()).count();
+        return Some((marker, prefix_len));
+    }
+
+    let text = line.text();
+    let captures = marker_pattern.captures(&text)?;
+    Some((
+        captures[1].to_string(),
+        captures[0].trim_end().chars().count(),
+    ))
+}
+
+/// A copy of `line` without its first `count` characters other than whitespace.
+fn without_prefix(line: &Line, mut count: usize) -> Line {
+    let mut line = line.clone();
+
+    for run in &mut line.runs {
+        if count == 0 {
+            break;
+        }
+        let mut start = run.text.len();
+        for (index, c) in run.text.char_indices() {
+            if count == 0 {
+                start = index;
+                break;
+            }
+            if !c.is_whitespace() {
+                count -= 1;
+            }
+        }
+        run.text = run.text[start..].to_string();
+    }
+    line.runs.retain(|run| !run.text.is_empty());
+
+    line
+}
+
+/// Breaks `notes` into lines, each starting with its marker.
+pub(super) fn lay_out_notes(notes: Vec<Footnote>, options: &PdfOptions) -> Vec<NoteLayout> {
+    let language = linebreak::hyphenation_language(options.language.as_deref());
+
+    notes
+        .into_iter()
+        .map(|note| {
+            let marker = Word {
+                pieces: vec![(
+                    note.marker.clone(),
+                    Emphasis {
+                        superscript: true,
+                        ..Default::default()
+                    },
+                )],
+            };
+            let mut words = vec![marker];
+            words.extend(styles::parse_paragraph(&note.text).words);
+
+            NoteLayout {
+                marker: note.marker,
+                lines: linebreak::break_lines(
+                    &words,
+                    options.max_width,
+                    FONT_SIZE / BODY_FONT_SIZE,
+                    options.justify,
+                    language,
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Takes the pending footnotes that the words of a paragraph refer to by their superscript
+/// markers, and breaks them into lines.
+pub(super) fn referenced_notes(
+    pages_state: &mut PagesState,
+    words: &[Word],
+    options: &PdfOptions,
+) -> Vec<NoteLayout> {
+    let mut referenced = vec![];
+    for (text, _) in words
+        .iter()
+        .flat_map(|word| &word.pieces)
+        .filter(|(_, emphasis)| emphasis.superscript)
+    {
+        let marker = text.trim();
+        if let Some(index) = pages_state
+            .pending_notes
+            .iter()
+            .position(|note| note.marker == marker)
+        {
+            referenced.push(pages_state.pending_notes.remove(index));
+        }
+    }
+
+    lay_out_notes(referenced, options)
+}
+
+/// Height the footnotes `notes` take up at the bottom of a page, including the space above them if
+/// they are the first on the page.
+pub(super) fn notes_height(
+    pages_state: &PagesState,
+    notes: &[NoteLayout],
+    options: &PdfOptions,
+) -> f64 {
+    let lines: usize = notes.iter().map(|note| note.lines.len()).sum();
+    if lines == 0 {
+        return 0.0;
+    }
+
+    let separation = if pages_state.notes_height == 0.0 {
+        options.line_height
+    } else {
+        0.0
+    };
+    separation + lines as f64 * line_height(options)
+}
+
+/// Reserves room for `notes` at the bottom of the output page with index `page`, which has to be
+/// the last page, and adds them to the structure tree after the content so far.
+pub(super) fn place_notes(
+    pages_state: &mut PagesState,
+    page: usize,
+    notes: Vec<NoteLayout>,
+    options: &PdfOptions,
+) {
+    if page + 1 == pages_state.pages.len() {
+        pages_state.notes_height += notes_height(pages_state, &notes, options);
+    }
+
+    for note in notes {
+        tracing::debug!("Placing footnote {} on page {}", note.marker, page + 1);
+        let element = pages_state.structure.add_element(None, "Note");
+        pages_state.placed_notes.push(PlacedNote {
+            page,
+            element,
+            lines: note.lines,
+        });
+    }
+}
+
+/// Places the footnotes of the source page that no paragraph referred to after its text, on a new
+/// page if there is no room left for them.
+pub(super) fn place_remaining_notes(pages_state: &mut PagesState, options: &PdfOptions) {
+    let notes = lay_out_notes(std::mem::take(&mut pages_state.pending_notes), options);
+    if notes.is_empty() {
+        return;
+    }
+
+    let height = notes_height(pages_state, &notes, options);
+    if pages_state.y_pos - height < pages_state.bottom(options) {
+        create_new_page(pages_state, options.max_y_pos);
+    }
+    place_notes(pages_state, pages_state.pages.len() - 1, notes, options);
+}
+
+/// Draws the placed footnotes at the bottom of their pages, under a short rule.
+pub(super) fn draw_notes(pages_state: &mut PagesState, options: &PdfOptions) {
+    let line_height = line_height(options);
+    let placed = std::mem::take(&mut pages_state.placed_notes);
+
+    for page in 0..pages_state.pages.len() {
+        let notes: Vec<&PlacedNote> = placed.iter().filter(|note| note.page == page).collect();
+        let lines: usize = notes.iter().map(|note| note.lines.len()).sum();
+        if lines == 0 {
+            continue;
+        }
+
+        let mut y_pos = options.min_y_pos + (lines - 1) as f64 * line_height;
+        let rule_y = y_pos + line_height;
+        let mut operations = structure::artifact(vec![
+            Operation::new("q", vec![]),
+            Operation::new("w", vec![0.5.into()]),
+            Operation::new("m", vec![LEFT_MARGIN.into(), rule_y.into()]),
+            Operation::new(
+                "l",
+                vec![
+                    (LEFT_MARGIN + options.max_width * SEPARATOR_RATIO).into(),
+                    rule_y.into(),
+                ],
+            ),
+            Operation::new("S", vec![]),
+            Operation::new("Q", vec![]),
+        ]);
+
+        for note in notes {
+            operations.push(
+                pages_state
+                    .structure
+                    .begin_marked_content(note.element, page),
+            );
+            operations.push(Operation::new("BT", vec![]));
+            operations.push(Operation::new("Td", vec![LEFT_MARGIN.into(), y_pos.into()]));
+            for line in &note.lines {
+                let gaps = line.words.len().saturating_sub(1);
+                let word_spacing = if options.justify && !line.last && gaps > 0 {
+                    (options.max_width - line.width) / gaps as f64
+                } else {
+                    0.0
+                };
+                operations.extend(line_operations(&line.words, FONT_SIZE, false, word_spacing));
+                operations.push(Operation::new("Td", vec![0.into(), (-line_height).into()]));
+                y_pos -= line_height;
+            }
+            operations.push(Operation::new("ET", vec![]));
+            operations.push(structure::end_marked_content());
+        }
+
+        pages_state.pages[page].operations.extend(operations);
+    }
+}
+
+fn line_height(options: &PdfOptions) -> f64 {
+    options.line_height * FONT_SIZE / BODY_FONT_SIZE
+}
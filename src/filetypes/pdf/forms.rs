@@ -0,0 +1,239 @@
+use std::{future::Future, sync::Arc};
+
+use eyre::Result;
+use lopdf::{decode_text_string, text_string, Dictionary, Document, Object, ObjectId};
+
+use super::edit_snippets;
+use crate::options::RequestOptions;
+
+/// Fields nested deeper than this are not translated
+const MAX_FIELD_DEPTH: usize = 32;
+
+/// Bit of the `/Ff` field flags that makes a button a push button
+const PUSH_BUTTON_FLAG: i64 = 1 << 16;
+
+/// A piece of text in a form field, and where to put its translation
+enum FieldText {
+    /// `/TU`, the name shown to the user, usually as a tooltip
+    Tooltip,
+    /// An entry of a choice field's `/Opt` list
+    Option(usize),
+    /// `/V` or `/DV` of a text field
+    Value(&'static [u8]),
+    /// `/CA` in the `/MK` dictionary of a push button
+    Caption,
+}
+
+/// Translates the text the user sees in the interactive form of `doc`: tooltips, the options of
+/// list and combo boxes, the values of text fields and the captions of push buttons.
+///
+/// Field names, export values and actions are left as they are, so the form keeps working. Options
+/// that only have a display text get their original text as export value, and viewers are asked to
+/// redraw the fields, since their appearance streams still show the original values.
+pub(super) async fn translate_form<F, Fut>(
+    doc: &mut Document,
+    request_options: &RequestOptions,
+    edit_func: &Arc<F>,
+) -> Result<()>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let Some(fields) = form_fields(doc) else {
+        return Ok(());
+    };
+
+    let mut targets = vec![];
+    let mut snippets = vec![];
+    for field_id in fields {
+        let inherited = Inherited {
+            field_type: None,
+            push_button: false,
+        };
+        collect_field_text(doc, field_id, inherited, 0, &mut targets, &mut snippets);
+    }
+    if snippets.is_empty() {
+        return Ok(());
+    }
+
+    tracing::info!("Translating {} form field texts...", snippets.len());
+    let translations = edit_snippets(request_options, edit_func, snippets).await?;
+
+    for ((field_id, target), translation) in targets.into_iter().zip(translations) {
+        if matches!(target, FieldText::Option(_) | FieldText::Caption) {
+            inline_entries(doc, field_id)?;
+        }
+        let field = doc.get_dictionary_mut(field_id)?;
+        match target {
+            FieldText::Tooltip => field.set("TU", text_string(&translation)),
+            FieldText::Option(index) => {
+                if let Ok(Object::Array(options)) = field.get_mut(b"Opt") {
+                    if let Some(option) = options.get_mut(index) {
+                        // an option without a separate export value exports its original text
+                        let export = match &*option {
+                            Object::Array(pair) if pair.len() == 2 => pair[0].clone(),
+                            export => export.clone(),
+                        };
+                        *option = Object::Array(vec![export, text_string(&translation)]);
+                    }
+                }
+            }
+            FieldText::Value(key) => field.set(key, text_string(&translation)),
+            FieldText::Caption => {
+                if let Ok(Object::Dictionary(characteristics)) = field.get_mut(b"MK") {
+                    characteristics.set("CA", text_string(&translation));
+                }
+            }
+        }
+    }
+
+    // the widgets' appearance streams were drawn with the original values, options and captions
+    if let Some(form) = acro_form_mut(doc) {
+        form.set("NeedAppearances", true);
+    }
+
+    Ok(())
+}
+
+/// `object`, or the object it refers to if it is a reference.
+fn deref<'a>(doc: &'a Document, object: &'a Object) -> &'a Object {
+    doc.dereference(object).map_or(object, |(_, object)| object)
+}
+
+/// Copies the option list and the appearance characteristics of the field `field_id` into it where
+/// they are indirect objects, along with the options within them, so that their translations can
+/// be set in place without changing other fields that share them.
+fn inline_entries(doc: &mut Document, field_id: ObjectId) -> Result<()> {
+    let field = doc.get_dictionary(field_id)?;
+    let mut inlined = vec![];
+    if let Ok(options) = field.get_deref(b"Opt", doc).and_then(Object::as_array) {
+        let options = options
+            .iter()
+            .map(|option| deref(doc, option).clone())
+            .collect();
+        inlined.push(("Opt", Object::Array(options)));
+    }
+    if let Ok(characteristics) = field.get_deref(b"MK", doc).and_then(Object::as_dict) {
+        inlined.push(("MK", Object::Dictionary(characteristics.clone())));
+    }
+
+    let field = doc.get_dictionary_mut(field_id)?;
+    for (key, value) in inlined {
+        field.set(key, value);
+    }
+    Ok(())
+}
+
+fn form_fields(doc: &Document) -> Option<Vec<ObjectId>> {
+    let form = doc
+        .catalog()
+        .ok()?
+        .get_deref(b"AcroForm", doc)
+        .and_then(Object::as_dict)
+        .ok()?;
+    let fields = form
+        .get_deref(b"Fields", doc)
+        .and_then(Object::as_array)
+        .ok()?;
+
+    Some(
+        fields
+            .iter()
+            .filter_map(|field| field.as_reference().ok())
+            .collect(),
+    )
+}
+
+fn acro_form_mut(doc: &mut Document) -> Option<&mut Dictionary> {
+    let form = doc.catalog().ok()?.get(b"AcroForm").ok()?;
+
+    match form {
+        Object::Reference(id) => {
+            let id = *id;
+            doc.get_dictionary_mut(id).ok()
+        }
+        _ => match doc.catalog_mut().ok()?.get_mut(b"AcroForm").ok()? {
+            Object::Dictionary(form) => Some(form),
+            _ => None,
+        },
+    }
+}
+
+/// The attributes a field inherits from its parents
+#[derive(Clone, Copy)]
+struct Inherited<'a> {
+    field_type: Option<&'a [u8]>,
+    push_button: bool,
+}
+
+/// Collects the translatable text of a field and its descendants, which include its widgets.
+fn collect_field_text(
+    doc: &Document,
+    field_id: ObjectId,
+    inherited: Inherited,
+    depth: usize,
+    targets: &mut Vec<(ObjectId, FieldText)>,
+    snippets: &mut Vec<String>,
+) {
+    if depth > MAX_FIELD_DEPTH {
+        return;
+    }
+    let Ok(field) = doc.get_dictionary(field_id) else {
+        return;
+    };
+
+    let inherited = Inherited {
+        field_type: field
+            .get(b"FT")
+            .and_then(Object::as_name)
+            .ok()
+            .or(inherited.field_type),
+        push_button: field
+            .get(b"Ff")
+            .and_then(Object::as_i64)
+            .map_or(inherited.push_button, |flags| flags & PUSH_BUTTON_FLAG != 0),
+    };
+    let mut push = |text: Option<String>, target: FieldText| {
+        if let Some(text) = text.filter(|text| !text.trim().is_empty()) {
+            targets.push((field_id, target));
+            snippets.push(text);
+        }
+    };
+    let text = |key: &[u8]| field.get_deref(key, doc).and_then(decode_text_string).ok();
+
+    push(text(b"TU"), FieldText::Tooltip);
+
+    if inherited.field_type == Some(b"Tx") {
+        for key in [b"V".as_slice(), b"DV".as_slice()] {
+            push(text(key), FieldText::Value(key));
+        }
+    }
+
+    if let Ok(options) = field.get_deref(b"Opt", doc).and_then(Object::as_array) {
+        for (index, option) in options.iter().enumerate() {
+            let display = match deref(doc, option) {
+                Object::Array(pair) if pair.len() == 2 => deref(doc, &pair[1]),
+                option => option,
+            };
+            push(decode_text_string(display).ok(), FieldText::Option(index));
+        }
+    }
+
+    if inherited.field_type == Some(b"Btn") && inherited.push_button {
+        let caption = field
+            .get_deref(b"MK", doc)
+            .and_then(Object::as_dict)
+            .and_then(|characteristics| characteristics.get_deref(b"CA", doc))
+            .and_then(decode_text_string)
+            .ok();
+        push(caption, FieldText::Caption);
+    }
+
+    if let Ok(kids) = field.get_deref(b"Kids", doc).and_then(Object::as_array) {
+        for kid in kids {
+            if let Ok(kid_id) = kid.as_reference() {
+                collect_field_text(doc, kid_id, inherited, depth + 1, targets, snippets);
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use eyre::Result;
+use lopdf::{content::Operation, Object, ObjectId};
+
+use super::{
+    edit_snippets, encode_win_ansi, font_name, paragraphs::RunningLines, string_width, structure,
+    styles::Emphasis, BODY_FONT_SIZE, LEFT_MARGIN,
+};
+use crate::options::{PdfOptions, RequestOptions};
+
+/// Size of the header and footer text
+const FONT_SIZE: f64 = 9.0;
+
+/// Baselines of the header and footer on the A4 pages of reflowed documents, clear of the text
+const HEADER_BASELINE: f64 = 800.0;
+const FOOTER_BASELINE: f64 = 25.0;
+
+/// Placeholders in the header and footer templates for the running header and footer of the
+/// source page, which are only translated if one of them is used
+const RUNNING_HEADER: &str = "{running_header}";
+const RUNNING_FOOTER: &str = "{running_footer}";
+
+/// What the header and footer templates are filled in with on each reflowed page
+#[derive(Debug, Default)]
+pub(super) struct PageHeaders {
+    header: Option<String>,
+    footer: Option<String>,
+    max_width: f64,
+    title: String,
+    page_count: usize,
+    /// The chapter and the running lines of the source text on each output page, by index
+    pages: Vec<(String, RunningLines)>,
+}
+
+impl PageHeaders {
+    /// Collects the values of the placeholders of the templates in `options` for `page_count`
+    /// output pages. `source_pages` is the source page whose text each output page holds,
+    /// `chapters` the output page each chapter starts on with its title, and `running_lines` the
+    /// translated running lines of the source pages.
+    pub(super) fn new(
+        options: &PdfOptions,
+        title: Option<String>,
+        page_count: usize,
+        source_pages: &[Option<ObjectId>],
+        mut chapters: Vec<(usize, String)>,
+        running_lines: &HashMap<ObjectId, RunningLines>,
+    ) -> Self {
+        chapters.sort_by_key(|&(index, _)| index);
+        let mut chapters = chapters.into_iter().peekable();
+        let mut chapter = String::new();
+
+        let pages = (0..page_count)
+            .map(|index| {
+                while let Some((_, title)) = chapters.next_if(|&(start, _)| start <= index) {
+                    chapter = title;
+                }
+                let running = source_pages
+                    .get(index)
+                    .copied()
+                    .flatten()
+                    .and_then(|page_id| running_lines.get(&page_id))
+                    .cloned()
+                    .unwrap_or_default();
+                (chapter.clone(), running)
+            })
+            .collect();
+
+        Self {
+            header: options.header.clone(),
+            footer: options.footer.clone(),
+            max_width: options.max_width,
+            title: title.unwrap_or_default(),
+            page_count,
+            pages,
+        }
+    }
+
+    /// The operations that draw the header and footer of the output page with index `index`,
+    /// centered over the text and marked as artifacts.
+    pub(super) fn operations(&self, index: usize) -> Vec<Operation> {
+        let mut operations = vec![];
+        let templates = [
+            (&self.header, HEADER_BASELINE),
+            (&self.footer, FOOTER_BASELINE),
+        ];
+
+        for (template, baseline) in templates {
+            let Some(text) = template
+                .as_ref()
+                .map(|template| self.expand(template, index))
+            else {
+                continue;
+            };
+            if text.trim().is_empty() {
+                continue;
+            }
+
+            let width = string_width(&text) * FONT_SIZE / BODY_FONT_SIZE;
+            let x = LEFT_MARGIN + ((self.max_width - width) / 2.0).max(0.0);
+            operations.extend(structure::artifact(vec![
+                Operation::new("BT", vec![]),
+                Operation::new(
+                    "Tf",
+                    vec![font_name(Emphasis::default()).into(), FONT_SIZE.into()],
+                ),
+                Operation::new("Td", vec![x.into(), baseline.into()]),
+                Operation::new(
+                    "Tj",
+                    vec![Object::string_literal(encode_win_ansi(text.trim()))],
+                ),
+                Operation::new("ET", vec![]),
+            ]));
+        }
+
+        operations
+    }
+
+    fn expand(&self, template: &str, index: usize) -> String {
+        let (chapter, running) = self.pages.get(index).cloned().unwrap_or_default();
+
+        template
+            .replace("{title}", &self.title)
+            .replace("{chapter}", &chapter)
+            .replace("{page}", &(index + 1).to_string())
+            .replace("{pages}", &self.page_count.to_string())
+            .replace(RUNNING_HEADER, &running.header)
+            .replace(RUNNING_FOOTER, &running.footer)
+    }
+}
+
+/// Whether the header or footer templates use the running lines of the source pages.
+pub(super) fn uses_running_lines(options: &PdfOptions) -> bool {
+    [&options.header, &options.footer]
+        .into_iter()
+        .flatten()
+        .any(|template| template.contains(RUNNING_HEADER) || template.contains(RUNNING_FOOTER))
+}
+
+/// Translates the running lines of the source pages, each distinct line once.
+pub(super) async fn translate_running_lines<F, Fut>(
+    running_lines: &mut HashMap<ObjectId, RunningLines>,
+    request_options: &RequestOptions,
+    edit_func: &Arc<F>,
+) -> Result<()>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let mut lines: Vec<String> = running_lines
+        .values()
+        .flat_map(|running| [running.header.clone(), running.footer.clone()])
+        .filter(|line| !line.is_empty())
+        .collect();
+    lines.sort_unstable();
+    lines.dedup();
+
+    let translations = edit_snippets(request_options, edit_func, lines.clone()).await?;
+    let translations: HashMap<String, String> = lines.into_iter().zip(translations).collect();
+
+    for running in running_lines.values_mut() {
+        for line in [&mut running.header, &mut running.footer] {
+            if let Some(translation) = translations.get(line) {
+                *line = translation.clone();
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,487 @@
+use std::path::Path;
+
+use eyre::Result;
+use lopdf::{
+    content::{Content, Operation},
+    dictionary, Document, Object, ObjectId, Stream,
+};
+
+use super::{
+    add_page_font, encode_win_ansi,
+    paragraphs::OBJECT_MARKER,
+    styles,
+    table::{self, Table},
+    text::{ImagePlacement, PageContent, TextRun},
+};
+
+/// Runs on the same baseline further apart than this many ems belong to different columns
+const COLUMN_GAP_RATIO: f64 = 1.5;
+
+/// A horizontal gap between runs wider than this many ems is a word break
+const WORD_GAP_RATIO: f64 = 0.15;
+
+/// An empty vertical band at least this many ems wide separates two columns
+const GUTTER_RATIO: f64 = 1.0;
+
+/// An empty horizontal band at least this many ems tall separates two blocks
+const BLOCK_GAP_RATIO: f64 = 0.6;
+
+/// Resource name of the font the block numbers of the debug overlay are set in
+const DEBUG_FONT_NAME: &str = "ZgDbg";
+
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Rect {
+    pub(super) x0: f64,
+    pub(super) y0: f64,
+    pub(super) x1: f64,
+    pub(super) y1: f64,
+}
+
+impl Rect {
+    pub(super) fn union(&self, other: &Rect) -> Rect {
+        Rect {
+            x0: self.x0.min(other.x0),
+            y0: self.y0.min(other.y0),
+            x1: self.x1.max(other.x1),
+            y1: self.y1.max(other.y1),
+        }
+    }
+
+    pub(super) fn width(&self) -> f64 {
+        self.x1 - self.x0
+    }
+
+    pub(super) fn height(&self) -> f64 {
+        self.y1 - self.y0
+    }
+}
+
+/// Runs sharing a baseline, without any column gap between them
+#[derive(Clone, Debug)]
+pub(super) struct Line {
+    pub(super) runs: Vec<TextRun>,
+    pub(super) bbox: Rect,
+    pub(super) font_size: f64,
+}
+
+impl Line {
+    fn new(runs: Vec<TextRun>) -> Self {
+        let font_size = runs.iter().map(|run| run.font_size).fold(0.0, f64::max);
+        let bbox = runs
+            .iter()
+            .map(run_bbox)
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or(Rect {
+                x0: 0.0,
+                y0: 0.0,
+                x1: 0.0,
+                y1: 0.0,
+            });
+
+        Self {
+            runs,
+            bbox,
+            font_size,
+        }
+    }
+
+    pub(super) fn text(&self) -> String {
+        self.join_runs(|text, run| text.push_str(&run.text))
+    }
+
+    /// Joins the runs with `push`, putting a space between those that are a word gap apart.
+    pub(super) fn join_runs(&self, mut push: impl FnMut(&mut String, &TextRun)) -> String {
+        let mut text = String::new();
+        let mut previous: Option<&TextRun> = None;
+
+        for run in &self.runs {
+            if let Some(previous) = previous {
+                let gap = run.x - previous.end_x();
+                let separated = text.ends_with(char::is_whitespace)
+                    || run.text.starts_with(char::is_whitespace);
+                if gap > run.font_size.max(previous.font_size) * WORD_GAP_RATIO && !separated {
+                    text.push(' ');
+                }
+            }
+            push(&mut text, run);
+            previous = Some(run);
+        }
+
+        text.trim().to_string()
+    }
+}
+
+/// Lines that belong together, such as a paragraph or a column without paragraph gaps
+#[derive(Clone, Debug)]
+pub(super) struct Block {
+    pub(super) lines: Vec<Line>,
+    pub(super) bbox: Rect,
+}
+
+impl Block {
+    fn new(mut lines: Vec<Line>) -> Self {
+        lines.sort_by(|a, b| {
+            b.bbox
+                .y1
+                .total_cmp(&a.bbox.y1)
+                .then(a.bbox.x0.total_cmp(&b.bbox.x0))
+        });
+        let bbox = lines
+            .iter()
+            .map(|line| line.bbox)
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or(Rect {
+                x0: 0.0,
+                y0: 0.0,
+                x1: 0.0,
+                y1: 0.0,
+            });
+
+        Self { lines, bbox }
+    }
+
+    pub(super) fn text(&self) -> String {
+        self.lines
+            .iter()
+            .map(Line::text)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// A part of the page that is read as a unit
+#[derive(Clone, Debug)]
+pub(super) enum Region {
+    Text(Block),
+    Table(Table),
+    Image(ImagePlacement),
+}
+
+impl Region {
+    pub(super) fn bbox(&self) -> Rect {
+        match self {
+            Region::Text(block) => block.bbox,
+            Region::Table(table) => table.bbox,
+            Region::Image(image) => image.bbox(),
+        }
+    }
+
+    /// The text of the region for paragraph reconstruction, with its emphasis and headings marked
+    /// up relative to `body_font_size`. Tables and images are represented by [`OBJECT_MARKER`],
+    /// since they are laid out separately.
+    pub(super) fn text(&self, body_font_size: f64) -> String {
+        match self {
+            Region::Text(block) => styles::mark_up_block(block, body_font_size),
+            Region::Table(_) | Region::Image(_) => OBJECT_MARKER.to_string(),
+        }
+    }
+}
+
+/// What the XY-cut divides: lines, and tables that have already been recognized as a whole
+enum Item {
+    Line(Line),
+    Table(Table),
+}
+
+impl Item {
+    fn bbox(&self) -> &Rect {
+        match self {
+            Item::Line(line) => &line.bbox,
+            Item::Table(table) => &table.bbox,
+        }
+    }
+}
+
+fn run_bbox(run: &TextRun) -> Rect {
+    Rect {
+        x0: run.x,
+        y0: run.y - run.font_size * 0.25,
+        x1: run.end_x(),
+        y1: run.y + run.font_size * 0.75,
+    }
+}
+
+/// Groups the text runs of a page into blocks and tables and orders them the way the page is read,
+/// so that multi-column pages come out column by column instead of interleaved line by line.
+///
+/// Tables are recognized first, from grids of rules and then from rows whose cells line up. The
+/// remaining runs are joined into lines, which are split wherever a column gap runs through them.
+/// The lines and tables are then divided recursively (XY-cut): at the empty vertical bands between
+/// columns, left to right, or failing that at the empty horizontal bands between blocks, top to
+/// bottom. Finally each image is placed next to the block or table nearest to it, before it if the
+/// image is higher up on the page and after it otherwise, so figures stay with their captions.
+pub(super) fn analyze_page(content: PageContent) -> Vec<Region> {
+    let (mut tables, runs) = table::detect_ruled_tables(content.runs, &content.rulings);
+    let (aligned_tables, lines) = table::detect_aligned_tables(build_lines(runs));
+    tables.extend(aligned_tables);
+
+    let items = lines
+        .into_iter()
+        .map(Item::Line)
+        .chain(tables.into_iter().map(Item::Table))
+        .collect();
+    let mut regions = vec![];
+
+    xy_cut(items, &mut regions);
+    anchor_images(&mut regions, content.images);
+
+    regions
+}
+
+pub(super) fn build_lines(mut runs: Vec<TextRun>) -> Vec<Line> {
+    runs.sort_by(|a, b| b.y.total_cmp(&a.y).then(a.x.total_cmp(&b.x)));
+
+    let mut baselines: Vec<Vec<TextRun>> = vec![];
+    for run in runs {
+        match baselines.last_mut() {
+            Some(baseline)
+                if (baseline[0].y - run.y).abs()
+                    < baseline[0].font_size.max(run.font_size) * 0.5 =>
+            {
+                baseline.push(run)
+            }
+            _ => baselines.push(vec![run]),
+        }
+    }
+
+    let mut lines = vec![];
+    for mut baseline in baselines {
+        baseline.sort_by(|a, b| a.x.total_cmp(&b.x));
+
+        let mut current: Vec<TextRun> = vec![];
+        for run in baseline {
+            if let Some(previous) = current.last() {
+                let gap = run.x - previous.end_x();
+                if gap > run.font_size.max(previous.font_size) * COLUMN_GAP_RATIO {
+                    lines.push(Line::new(std::mem::take(&mut current)));
+                }
+            }
+            current.push(run);
+        }
+        if !current.is_empty() {
+            lines.push(Line::new(current));
+        }
+    }
+
+    lines
+}
+
+#[derive(Clone, Copy)]
+enum Axis {
+    X,
+    Y,
+}
+
+fn xy_cut(items: Vec<Item>, regions: &mut Vec<Region>) {
+    if items.len() <= 1 {
+        push_leaf(items, regions);
+        return;
+    }
+
+    let font_size = median_font_size(&items);
+    let groups = [(Axis::X, GUTTER_RATIO), (Axis::Y, BLOCK_GAP_RATIO)]
+        .into_iter()
+        .map(|(axis, ratio)| split(&items, axis, font_size * ratio))
+        .find(|groups| groups.len() > 1);
+
+    let Some(groups) = groups else {
+        push_leaf(items, regions);
+        return;
+    };
+
+    let mut items: Vec<Option<Item>> = items.into_iter().map(Some).collect();
+    for group in groups {
+        let group = group
+            .into_iter()
+            .filter_map(|index| items[index].take())
+            .collect();
+        xy_cut(group, regions);
+    }
+}
+
+/// Turns the items the XY-cut could not divide any further into regions: the lines into one block,
+/// and each table on its own, ordered from the top.
+fn push_leaf(items: Vec<Item>, regions: &mut Vec<Region>) {
+    let mut lines = vec![];
+    let mut leaf = vec![];
+    for item in items {
+        match item {
+            Item::Line(line) => lines.push(line),
+            Item::Table(table) => leaf.push(Region::Table(table)),
+        }
+    }
+    if !lines.is_empty() {
+        leaf.push(Region::Text(Block::new(lines)));
+    }
+
+    leaf.sort_by(|a, b| b.bbox().y1.total_cmp(&a.bbox().y1));
+    regions.extend(leaf);
+}
+
+fn anchor_images(regions: &mut Vec<Region>, mut images: Vec<ImagePlacement>) {
+    images.sort_by(|a, b| b.bbox().y1.total_cmp(&a.bbox().y1));
+
+    for image in images {
+        let bbox = image.bbox();
+        let nearest = regions
+            .iter()
+            .enumerate()
+            .filter(|(_, region)| !matches!(region, Region::Image(_)))
+            .min_by(|(_, a), (_, b)| {
+                distance(&bbox, &a.bbox()).total_cmp(&distance(&bbox, &b.bbox()))
+            });
+
+        let index = match nearest {
+            Some((index, region)) => {
+                let anchor = region.bbox();
+                if bbox.y0 + bbox.y1 > anchor.y0 + anchor.y1 {
+                    index
+                } else {
+                    // after the anchor and any images already placed after it
+                    let mut index = index + 1;
+                    while matches!(regions.get(index), Some(Region::Image(_))) {
+                        index += 1;
+                    }
+                    index
+                }
+            }
+            None => regions.len(),
+        };
+        regions.insert(index, Region::Image(image));
+    }
+}
+
+fn distance(a: &Rect, b: &Rect) -> f64 {
+    let dx = (a.x0 - b.x1).max(b.x0 - a.x1).max(0.0);
+    let dy = (a.y0 - b.y1).max(b.y0 - a.y1).max(0.0);
+
+    (dx * dx + dy * dy).sqrt()
+}
+
+/// Splits `items` at every empty band along `axis` at least `min_gap` wide, returning the indices
+/// of the items on each side in reading order: left to right, or top to bottom.
+fn split(items: &[Item], axis: Axis, min_gap: f64) -> Vec<Vec<usize>> {
+    let interval = |item: &Item| match axis {
+        Axis::X => (item.bbox().x0, item.bbox().x1),
+        // negated so that sorting by start goes from the top of the page down
+        Axis::Y => (-item.bbox().y1, -item.bbox().y0),
+    };
+
+    let mut order: Vec<usize> = (0..items.len()).collect();
+    order.sort_by(|&a, &b| interval(&items[a]).0.total_cmp(&interval(&items[b]).0));
+
+    let mut groups: Vec<Vec<usize>> = vec![];
+    let mut group_end = f64::NEG_INFINITY;
+
+    for index in order {
+        let (start, end) = interval(&items[index]);
+        match groups.last_mut() {
+            Some(group) if start - group_end < min_gap => group.push(index),
+            _ => groups.push(vec![index]),
+        }
+        group_end = group_end.max(end);
+    }
+
+    groups
+}
+
+fn median_font_size(items: &[Item]) -> f64 {
+    let mut sizes: Vec<f64> = items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Line(line) => Some(line.font_size),
+            Item::Table(_) => None,
+        })
+        .collect();
+    sizes.sort_by(f64::total_cmp);
+
+    sizes.get(sizes.len() / 2).copied().unwrap_or(0.0).max(1.0)
+}
+
+/// Writes a copy of `doc` with the outline and reading order position of every detected block
+/// drawn over its pages, for checking the layout analysis. Text blocks are outlined in red, tables
+/// in blue and images in green.
+pub(super) fn write_debug_overlay(
+    doc: &Document,
+    pages: &[(ObjectId, Vec<Region>)],
+    path: &Path,
+) -> Result<()> {
+    tracing::info!("Writing layout overlay to {}...", path.display());
+
+    let mut doc = doc.clone();
+    let font_id = doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica",
+        "Encoding" => "WinAnsiEncoding",
+    });
+
+    for (page_id, regions) in pages {
+        let mut operations = vec![];
+        for (index, region) in regions.iter().enumerate() {
+            let color = match region {
+                Region::Text(_) => [1, 0, 0],
+                Region::Table(_) => [0, 0, 1],
+                Region::Image(_) => [0, 1, 0],
+            };
+            operations.extend(block_outline_operations(&region.bbox(), color, index + 1));
+        }
+
+        isolate_page_content(&mut doc, *page_id)?;
+        doc.add_page_contents(*page_id, Content { operations }.encode()?)?;
+        add_page_font(&mut doc, *page_id, DEBUG_FONT_NAME, font_id)?;
+    }
+
+    doc.save(path)?;
+
+    Ok(())
+}
+
+fn block_outline_operations(bbox: &Rect, color: [i64; 3], number: usize) -> Vec<Operation> {
+    let color: Vec<Object> = color.into_iter().map(Object::from).collect();
+
+    vec![
+        Operation::new("q", vec![]),
+        Operation::new("RG", color.clone()),
+        Operation::new("rg", color),
+        Operation::new("w", vec![0.5.into()]),
+        Operation::new(
+            "re",
+            vec![
+                bbox.x0.into(),
+                bbox.y0.into(),
+                bbox.width().into(),
+                bbox.height().into(),
+            ],
+        ),
+        Operation::new("S", vec![]),
+        Operation::new("BT", vec![]),
+        Operation::new("Tf", vec![DEBUG_FONT_NAME.into(), 8.into()]),
+        Operation::new("Td", vec![bbox.x0.into(), (bbox.y1 + 2.0).into()]),
+        Operation::new(
+            "Tj",
+            vec![Object::string_literal(encode_win_ansi(&number.to_string()))],
+        ),
+        Operation::new("ET", vec![]),
+        Operation::new("Q", vec![]),
+    ]
+}
+
+/// Wraps the existing content of a page in `q` ... `Q`, so that anything appended to it is drawn
+/// in the default coordinate system whatever state the original content leaves behind.
+pub(super) fn isolate_page_content(doc: &mut Document, page_id: ObjectId) -> Result<()> {
+    let mut contents: Vec<Object> = doc
+        .get_page_contents(page_id)
+        .into_iter()
+        .map(Object::Reference)
+        .collect();
+
+    let save_id = doc.add_object(Stream::new(dictionary! {}, b"q\n".to_vec()));
+    let restore_id = doc.add_object(Stream::new(dictionary! {}, b"\nQ\n".to_vec()));
+    contents.insert(0, save_id.into());
+    contents.push(restore_id.into());
+
+    doc.get_dictionary_mut(page_id)?.set("Contents", contents);
+
+    Ok(())
+}
@@ -0,0 +1,269 @@
+use hypher::Lang;
+
+use super::{string_width, styles::Word};
+
+/// Demerits every line adds, so that of two otherwise equal settings the one with fewer lines wins
+const LINE_PENALTY: f64 = 10.0;
+/// Penalty for ending a line with a hyphen
+const HYPHEN_PENALTY: f64 = 50.0;
+/// Demerits for two hyphenated lines in a row
+const DOUBLE_HYPHEN_DEMERITS: f64 = 3000.0;
+/// Demerits for a line that is wider than the column, which only happens to words too long for any
+/// line
+const OVERFULL_DEMERITS: f64 = 1e10;
+/// Badness of a line whose spaces have to be stretched further than they should
+const MAX_BADNESS: f64 = 10000.0;
+/// How far the spaces of justified text may stretch and shrink, as a fraction of their width
+const SPACE_STRETCH: f64 = 0.5;
+const SPACE_SHRINK: f64 = 1.0 / 3.0;
+/// How much room may be left at the end of a ragged-right line before it counts as badly set, in
+/// spaces
+const RAGGED_STRETCH: f64 = 4.0;
+/// Words with fewer letters than this are not hyphenated
+const MIN_HYPHENATED_LENGTH: usize = 5;
+/// Fewest letters left before and after a hyphen, unless the language asks for more
+const MIN_HYPHEN_LEFT: usize = 2;
+const MIN_HYPHEN_RIGHT: usize = 3;
+
+/// A line of a paragraph, as broken by [`break_lines`]
+#[derive(Debug)]
+pub(super) struct BrokenLine {
+    /// The words on the line, the last one ending in a hyphen if it was hyphenated
+    pub(super) words: Vec<Word>,
+    /// Width of the line with its spaces at their natural width
+    pub(super) width: f64,
+    /// Whether this is the last line of the paragraph, which is never stretched
+    pub(super) last: bool,
+}
+
+/// Where a paragraph may be broken after a fragment of a word
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Break {
+    /// Between two words
+    Space,
+    /// Within a word, where a hyphen is added if the line ends there
+    Hyphen,
+    /// After a hyphen the word already has
+    Dash,
+    /// After the last word
+    End,
+}
+
+impl Break {
+    fn is_hyphenated(self) -> bool {
+        matches!(self, Break::Hyphen | Break::Dash)
+    }
+}
+
+/// A word, or the part of one between two places it can be hyphenated
+#[derive(Debug)]
+struct Fragment {
+    word: Word,
+    width: f64,
+    after: Break,
+}
+
+/// Returns the hyphenation patterns for a language code such as `de` or `pt-BR`, if there are any.
+pub(super) fn hyphenation_language(language: Option<&str>) -> Option<Lang> {
+    let language = language?.to_ascii_lowercase();
+    let (code, region) = language.split_at_checked(2)?;
+    if !(region.is_empty() || region.starts_with(['-', '_'])) {
+        return None;
+    }
+
+    Lang::from_iso(code.as_bytes().try_into().ok()?)
+}
+
+/// Breaks a paragraph into lines at most `max_width` wide, with the total-fit algorithm of Knuth
+/// and Plass: rather than filling each line in turn, it picks the breaks that leave the spaces of
+/// the paragraph as a whole closest to their natural width, hyphenating words in `language` where
+/// that helps. When the text is not justified, the room left at the end of the lines is evened out
+/// instead.
+pub(super) fn break_lines(
+    words: &[Word],
+    max_width: f64,
+    scale: f64,
+    justify: bool,
+    language: Option<Lang>,
+) -> Vec<BrokenLine> {
+    let fragments = fragments(words, scale, language);
+    if fragments.is_empty() {
+        return vec![];
+    }
+    let space = string_width(" ") * scale;
+    let hyphen = string_width("-") * scale;
+
+    // the least demerits of the lines up to each break, and the break the last of them starts at.
+    // Break `index` follows fragment `index - 1`, and break 0 is the start of the paragraph.
+    let mut best = vec![(f64::INFINITY, 0); fragments.len() + 1];
+    best[0].0 = 0.0;
+
+    for end in 1..=fragments.len() {
+        let after = fragments[end - 1].after;
+        let mut width = if after == Break::Hyphen { hyphen } else { 0.0 };
+        let mut gaps = 0;
+
+        for start in (0..end).rev() {
+            width += fragments[start].width;
+            if start < end - 1 && fragments[start].after == Break::Space {
+                width += space;
+                gaps += 1;
+            }
+
+            let (stretch, shrink) = if justify {
+                let spaces = gaps as f64 * space;
+                (spaces * SPACE_STRETCH, spaces * SPACE_SHRINK)
+            } else {
+                (RAGGED_STRETCH * space, 0.0)
+            };
+            if width - shrink > max_width && start < end - 1 {
+                // starting any earlier only makes the line wider
+                break;
+            }
+
+            let badness = if width - shrink > max_width {
+                // a single word that doesn't fit on a line of its own
+                OVERFULL_DEMERITS
+            } else if width > max_width {
+                100.0 * ((width - max_width) / shrink).powi(3)
+            } else if after == Break::End {
+                0.0
+            } else if stretch > 0.0 {
+                (100.0 * ((max_width - width) / stretch).powi(3)).min(MAX_BADNESS)
+            } else {
+                MAX_BADNESS
+            };
+
+            let mut demerits = (LINE_PENALTY + badness).powi(2);
+            if after.is_hyphenated() {
+                demerits += HYPHEN_PENALTY.powi(2);
+                if start > 0 && fragments[start - 1].after.is_hyphenated() {
+                    demerits += DOUBLE_HYPHEN_DEMERITS;
+                }
+            }
+
+            let total = best[start].0 + demerits;
+            if total < best[end].0 {
+                best[end] = (total, start);
+            }
+        }
+    }
+
+    let mut breaks = vec![fragments.len()];
+    while let Some(&end) = breaks.last().filter(|&&end| end > 0) {
+        breaks.push(best[end].1);
+    }
+    breaks.reverse();
+
+    breaks
+        .windows(2)
+        .map(|window| set_line(&fragments[window[0]..window[1]], space, hyphen))
+        .collect()
+}
+
+/// Joins the fragments of a line back into words.
+fn set_line(fragments: &[Fragment], space: f64, hyphen: f64) -> BrokenLine {
+    let mut words: Vec<Word> = vec![];
+    let mut width = 0.0;
+    let mut after = Break::Space;
+
+    for fragment in fragments {
+        match words.last_mut() {
+            Some(word) if after != Break::Space => word.append(fragment.word.clone()),
+            Some(_) => {
+                width += space;
+                words.push(fragment.word.clone());
+            }
+            None => words.push(fragment.word.clone()),
+        }
+        width += fragment.width;
+        after = fragment.after;
+    }
+
+    if after == Break::Hyphen {
+        if let Some(word) = words.last_mut() {
+            let emphasis = word.pieces.last().map(|(_, emphasis)| *emphasis);
+            word.append(Word {
+                pieces: vec![("-".to_string(), emphasis.unwrap_or_default())],
+            });
+            width += hyphen;
+        }
+    }
+
+    BrokenLine {
+        words,
+        width,
+        last: after == Break::End,
+    }
+}
+
+/// Splits the words of a paragraph into fragments at the places they may be hyphenated.
+fn fragments(words: &[Word], scale: f64, language: Option<Lang>) -> Vec<Fragment> {
+    let mut fragments = vec![];
+
+    for (index, word) in words.iter().enumerate() {
+        let mut rest = word.clone();
+        let mut offset = 0;
+        for (point, after) in hyphenation_points(&word.text(), language) {
+            let (head, tail) = rest.split_at(point - offset);
+            fragments.push(Fragment {
+                width: string_width(&head.text()) * scale,
+                word: head,
+                after,
+            });
+            rest = tail;
+            offset = point;
+        }
+
+        fragments.push(Fragment {
+            width: string_width(&rest.text()) * scale,
+            word: rest,
+            after: if index + 1 == words.len() {
+                Break::End
+            } else {
+                Break::Space
+            },
+        });
+    }
+
+    fragments
+}
+
+/// Byte offsets in `word` at which it may be broken across lines: after the hyphens it already
+/// has, and between the syllables of its letters where the hyphenation patterns of `language`
+/// allow. Punctuation and digits are never split off.
+fn hyphenation_points(word: &str, language: Option<Lang>) -> Vec<(usize, Break)> {
+    let mut points = vec![];
+    let mut start = 0;
+
+    for part in word.split_inclusive('-') {
+        let letters = part.trim_matches(|c: char| !c.is_alphabetic());
+        if let Some(language) = language.filter(|_| {
+            letters.chars().all(char::is_alphabetic)
+                && letters.chars().count() >= MIN_HYPHENATED_LENGTH
+        }) {
+            let (left, right) = language.bounds();
+            let syllables: Vec<&str> = hypher::hyphenate_bounded(
+                letters,
+                language,
+                left.max(MIN_HYPHEN_LEFT),
+                right.max(MIN_HYPHEN_RIGHT),
+            )
+            .collect();
+
+            let mut point =
+                start + part.len() - part.trim_start_matches(|c: char| !c.is_alphabetic()).len();
+            for syllable in &syllables[..syllables.len() - 1] {
+                point += syllable.len();
+                points.push((point, Break::Hyphen));
+            }
+        }
+
+        start += part.len();
+        if part.len() > 1 && part.ends_with('-') && start < word.len() {
+            points.push((start, Break::Dash));
+        }
+    }
+
+    points
+}
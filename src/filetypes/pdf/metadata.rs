@@ -0,0 +1,81 @@
+use std::{future::Future, sync::Arc};
+
+use eyre::Result;
+use lopdf::{decode_text_string, text_string, Dictionary, Document, Object};
+
+use super::edit_snippets;
+use crate::options::RequestOptions;
+
+/// Document information entries whose values are prose rather than names or dates
+const TRANSLATED_INFO_KEYS: [&str; 3] = ["Title", "Subject", "Keywords"];
+
+/// Returns a copy of the `/Info` dictionary of `doc` with the title, subject and keywords
+/// translated. Other entries are copied as they are.
+pub(super) async fn translate_info<F, Fut>(
+    doc: &Document,
+    request_options: &RequestOptions,
+    edit_func: &Arc<F>,
+) -> Result<Option<Dictionary>>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let Some(mut info) = read_info(doc) else {
+        return Ok(None);
+    };
+
+    let mut keys = vec![];
+    let mut values = vec![];
+    for key in TRANSLATED_INFO_KEYS {
+        if let Ok(value) = info.get(key.as_bytes()).and_then(decode_text_string) {
+            if !value.trim().is_empty() {
+                keys.push(key);
+                values.push(value);
+            }
+        }
+    }
+
+    let values = edit_snippets(request_options, edit_func, values).await?;
+    for (key, value) in keys.into_iter().zip(values) {
+        info.set(key, text_string(&value));
+    }
+
+    Ok(Some(info))
+}
+
+/// Reads the `/Info` dictionary with all of its values resolved, so that it can be written into
+/// another document.
+fn read_info(doc: &Document) -> Option<Dictionary> {
+    let (_, info) = doc.dereference(doc.trailer.get(b"Info").ok()?).ok()?;
+    let info = info.as_dict().ok()?;
+
+    let mut resolved = Dictionary::new();
+    for (key, value) in info.iter() {
+        if let Ok((_, value)) = doc.dereference(value) {
+            if matches!(value, Object::String(..) | Object::Name(_)) {
+                resolved.set(key.clone(), value.clone());
+            }
+        }
+    }
+
+    Some(resolved)
+}
+
+pub(super) fn set_info(doc: &mut Document, info: Option<Dictionary>) {
+    match info {
+        Some(info) => {
+            let info_id = doc.add_object(info);
+            doc.trailer.set("Info", info_id);
+        }
+        None => {
+            doc.trailer.remove(b"Info");
+        }
+    }
+}
+
+/// Declares the natural language of the document's text in the catalog.
+pub(super) fn set_language(doc: &mut Document, language: &str) -> Result<()> {
+    doc.catalog_mut()?.set("Lang", text_string(language));
+
+    Ok(())
+}
@@ -0,0 +1,1303 @@
+mod annotate;
+mod encryption;
+mod footnotes;
+mod forms;
+mod headers;
+mod layout;
+mod linebreak;
+mod metadata;
+mod ocr;
+mod outline;
+mod paragraphs;
+mod preserve;
+mod structure;
+mod styles;
+mod table;
+mod text;
+
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::Arc,
+    vec,
+};
+
+use eyre::Result;
+use lopdf::{
+    content::{Content, Operation},
+    decode_text_string, dictionary, Dictionary, Document, Object, ObjectId, Stream,
+};
+use regex::Regex;
+
+use super::edit_snippets;
+use crate::options::{PdfMode, PdfOptions, RequestOptions};
+use footnotes::{Footnote, PlacedNote};
+use headers::PageHeaders;
+use layout::Region;
+use paragraphs::{RunningLines, OBJECT_MARKER};
+use structure::StructureTree;
+use styles::{Emphasis, Word};
+use text::ImagePlacement;
+
+pub use encryption::{parse_permissions, PdfEncryption};
+pub use ocr::{OcrBackend, TesseractCommand};
+
+/// Left edge of the text on reflowed pages
+const LEFT_MARGIN: f64 = 50.0;
+
+/// Size of the body text on reflowed pages
+const BODY_FONT_SIZE: f64 = 12.0;
+
+/// Sizes of the headings on reflowed pages, by level
+const HEADING_FONT_SIZES: [f64; 6] = [20.0, 16.0, 14.0, 12.0, 12.0, 12.0];
+
+/// Resource names and base fonts of the fonts reflowed text is set in, by emphasis: regular, bold,
+/// italic, and bold italic
+const REFLOW_FONTS: [(&str, &str); 4] = [
+    ("F1", "Courier"),
+    ("F2", "Courier-Bold"),
+    ("F3", "Courier-Oblique"),
+    ("F4", "Courier-BoldOblique"),
+];
+
+/// Fewest lines of a paragraph left at the bottom of a page when it is split across pages, so that
+/// it doesn't start with a lone orphan line
+const ORPHAN_LINES: usize = 2;
+
+/// Fewest lines of a paragraph carried over to the top of the next page, so that it doesn't end
+/// with a lone widow line
+const WIDOW_LINES: usize = 2;
+
+/// Size of superscripts, such as footnote references, relative to the text around them
+const SUPERSCRIPT_SCALE: f64 = 0.6;
+
+/// How far superscripts are raised above the baseline, relative to the size of the text around them
+const SUPERSCRIPT_RISE: f64 = 0.35;
+
+/// Page attributes that pages inherit from their ancestors in the page tree
+const INHERITABLE_PAGE_ATTRIBUTES: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+#[derive(Debug)]
+struct PagesState {
+    pages: Vec<Content>,
+    y_pos: f64,
+    // (source page, index of the output page its text starts on, top of that text)
+    anchors: Vec<(ObjectId, usize, f64)>,
+    // (index of an output page, source page that is copied to it untranslated)
+    untouched: Vec<(usize, ObjectId)>,
+    structure: StructureTree,
+    /// The structure element the content being added to the last page is marked as
+    marked: Option<usize>,
+    /// Footnotes of the source page being laid out that no paragraph has referred to yet
+    pending_notes: Vec<Footnote>,
+    placed_notes: Vec<PlacedNote>,
+    /// Height of the footnotes at the bottom of the last page
+    notes_height: f64,
+}
+
+impl PagesState {
+    fn new(options: &PdfOptions) -> Self {
+        Self {
+            pages: vec![Content {
+                operations: new_page_operations(),
+            }],
+            y_pos: options.max_y_pos,
+            anchors: vec![],
+            untouched: vec![],
+            structure: StructureTree::default(),
+            marked: None,
+            pending_notes: vec![],
+            placed_notes: vec![],
+            notes_height: 0.0,
+        }
+    }
+
+    /// The lowest the text on the last page may go, above its footnotes.
+    fn bottom(&self, options: &PdfOptions) -> f64 {
+        options.min_y_pos + self.notes_height
+    }
+
+    /// Whether nothing has been drawn on the last page yet.
+    fn is_page_empty(&self, options: &PdfOptions) -> bool {
+        self.y_pos >= options.max_y_pos && self.notes_height == 0.0
+    }
+
+    /// Marks the content that follows on the last page as belonging to the structure element
+    /// `element`, until [`PagesState::end_marked_content`].
+    fn begin_marked_content(&mut self, element: usize) {
+        let operation = self
+            .structure
+            .begin_marked_content(element, self.pages.len() - 1);
+        if let Some(last_page) = self.pages.last_mut() {
+            last_page.operations.push(operation);
+        }
+        self.marked = Some(element);
+    }
+
+    fn end_marked_content(&mut self) {
+        if self.marked.take().is_some() {
+            if let Some(last_page) = self.pages.last_mut() {
+                last_page.operations.push(structure::end_marked_content());
+            }
+        }
+    }
+
+    /// Places a source page that is not translated after the reflowed pages so far, to be copied
+    /// as it is. `top` is the top of the source page, where links to it point.
+    fn add_untouched_page(&mut self, page_id: ObjectId, top: f64, options: &PdfOptions) {
+        if self.is_page_empty(options) {
+            self.pages.pop();
+        } else {
+            end_text_section(self);
+        }
+
+        self.anchors.push((page_id, self.pages.len(), top));
+        self.untouched.push((self.pages.len(), page_id));
+        self.pages.push(Content { operations: vec![] });
+
+        self.pages.push(Content {
+            operations: new_page_operations(),
+        });
+        self.y_pos = options.max_y_pos;
+        self.notes_height = 0.0;
+    }
+
+    /// Ends the text on the last page, or drops the page if it was only started for text following
+    /// an untouched page and none came.
+    fn finish(&mut self, options: &PdfOptions) {
+        let after_untouched = self
+            .untouched
+            .last()
+            .is_some_and(|&(index, _)| index + 2 == self.pages.len());
+
+        if after_untouched && self.is_page_empty(options) {
+            self.pages.pop();
+        } else {
+            end_text_section(self);
+        }
+    }
+
+    fn anchor_source_page(&mut self, page_id: ObjectId, options: &PdfOptions) {
+        let top = self.y_pos + options.line_height;
+        self.anchors.push((page_id, self.pages.len() - 1, top));
+    }
+
+    /// The index of the output page the text of the source page `page_id` begins on.
+    fn anchor_index(&self, page_id: ObjectId) -> Option<usize> {
+        self.anchors
+            .iter()
+            .find(|&&(source_id, _, _)| source_id == page_id)
+            .map(|&(_, index, _)| index)
+    }
+
+    /// The source page whose text is at the top of each output page, by index: the one continued
+    /// from an earlier page, or else the first one that begins on it.
+    fn source_pages(&self) -> Vec<Option<ObjectId>> {
+        (0..self.pages.len())
+            .map(|index| {
+                let continued = self
+                    .anchors
+                    .iter()
+                    .rev()
+                    .find(|&&(_, anchor_index, _)| anchor_index < index);
+                let first = self
+                    .anchors
+                    .iter()
+                    .find(|&&(_, anchor_index, _)| anchor_index == index);
+                continued.or(first).map(|&(source_id, _, _)| source_id)
+            })
+            .collect()
+    }
+
+    /// Maps each source page to the output page and height at which its reflowed text begins.
+    fn page_map(&self, page_ids: &[Object]) -> PageMap {
+        self.anchors
+            .iter()
+            .filter_map(|&(source_id, index, top)| {
+                let page_id = page_ids.get(index)?.as_reference().ok()?;
+                Some((source_id, (page_id, top)))
+            })
+            .collect()
+    }
+}
+
+/// Source page id to (output page id, top of the page's content on the output page)
+type PageMap = HashMap<ObjectId, (ObjectId, f64)>;
+
+/// Reads the PDF at `path`, decrypting it with `password` if it is encrypted and can't be opened
+/// without one.
+pub fn read_pdf(path: &str, password: Option<&str>) -> Result<Document> {
+    tracing::info!("Reading {path}...");
+    let mut doc = Document::load(path)?;
+    encryption::decrypt(&mut doc, password)?;
+
+    Ok(doc)
+}
+
+pub fn write_pdf(mut doc: Document, to: &str, encryption: Option<&PdfEncryption>) -> Result<()> {
+    if let Some(encryption) = encryption {
+        tracing::info!("Encrypting pdf...");
+        encryption::encrypt(&mut doc, encryption)?;
+    }
+
+    tracing::info!("Writing pdf to {to}...");
+    doc.save(to)?;
+
+    Ok(())
+}
+
+pub async fn edit_pdf<F, Fut>(
+    mut doc: Document,
+    request_options: RequestOptions,
+    mut pdf_options: PdfOptions,
+    edit_func: F,
+) -> Result<Document>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let edit_func = Arc::new(edit_func);
+
+    if pdf_options.omit_unselected && pdf_options.pages.is_some() {
+        let unselected: Vec<u32> = doc
+            .get_pages()
+            .into_keys()
+            .filter(|&page_num| !pdf_options.is_page_selected(page_num))
+            .collect();
+        tracing::info!("Leaving out {} unselected pages...", unselected.len());
+        doc.delete_pages(&unselected);
+        // the remaining pages are numbered anew, and are all selected
+        pdf_options.pages = None;
+    }
+
+    match pdf_options.mode {
+        PdfMode::Reflow => reflow_pdf(doc, &request_options, &pdf_options, &edit_func).await,
+        PdfMode::Preserve => {
+            preserve::edit_in_place(doc, &request_options, &pdf_options, &edit_func).await
+        }
+        PdfMode::Annotate => {
+            annotate::annotate_pdf(doc, &request_options, &pdf_options, &edit_func).await
+        }
+    }
+}
+
+async fn reflow_pdf<F, Fut>(
+    doc: Document,
+    request_options: &RequestOptions,
+    pdf_options: &PdfOptions,
+    edit_func: &Arc<F>,
+) -> Result<Document>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let mut edited_doc = Document::with_version("1.5");
+    let pages_id = edited_doc.new_object_id();
+    let fonts = add_fonts(&mut edited_doc);
+
+    let mut page_ids = Vec::with_capacity(doc.get_pages().len());
+    let mut image_resources = dictionary! {};
+    let mut pages_state = PagesState::new(pdf_options);
+
+    let mut page_texts = Vec::new();
+    let mut page_objects = Vec::new();
+    let mut source_page_ids = Vec::new();
+    let mut page_regions = Vec::new();
+    let mut untouched_pages = HashSet::new();
+    let alt_texts = structure::read_alt_texts(&doc);
+
+    for (page_num, page_id) in doc.get_pages() {
+        if !pdf_options.is_page_selected(page_num) {
+            // an empty page keeps paragraphs from being joined across the untouched page
+            page_texts.push(String::new());
+            page_objects.push(vec![]);
+            source_page_ids.push(page_id);
+            untouched_pages.insert(page_id);
+            continue;
+        }
+
+        let regions = match text::extract_content(&doc, page_id) {
+            Ok(mut content) => {
+                if let Some(backend) = &pdf_options.ocr {
+                    ocr::recognize_scanned_page(&doc, &mut content, backend.as_ref(), page_num);
+                }
+                let mut regions = layout::analyze_page(content);
+                for region in &mut regions {
+                    if let Region::Image(image) = region {
+                        image.alt = image
+                            .mcid
+                            .and_then(|mcid| alt_texts.get(&(page_id, mcid)))
+                            .cloned();
+                    }
+                }
+                regions
+            }
+            Err(err) => {
+                tracing::warn!("Unable to analyze the layout of page {page_num}: {err}");
+                vec![]
+            }
+        };
+
+        let (text, objects) = if regions.is_empty() {
+            // without their placement, images go after the text as they are
+            let images = doc
+                .get_page_images(page_id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|image| {
+                    Region::Image(ImagePlacement {
+                        id: image.id,
+                        matrix: [image.width as f64, 0.0, 0.0, image.height as f64, 0.0, 0.0],
+                        mcid: None,
+                        alt: None,
+                    })
+                })
+                .collect();
+            (doc.extract_text(&[page_num])?, images)
+        } else {
+            // the text is marked up once the size of the body text of the whole document is known
+            let text = String::new();
+            let objects: Vec<Region> = regions
+                .iter()
+                .filter(|region| !matches!(region, Region::Text(_)))
+                .cloned()
+                .collect();
+            (text, objects)
+        };
+
+        page_texts.push(text);
+        page_objects.push(objects);
+        source_page_ids.push(page_id);
+        page_regions.push((page_id, regions));
+    }
+
+    if let Some(path) = &pdf_options.layout_debug {
+        layout::write_debug_overlay(&doc, &page_regions, path)?;
+    }
+
+    let body_font_size =
+        styles::body_font_size(page_regions.iter().flat_map(|(_, regions)| regions))
+            .unwrap_or(BODY_FONT_SIZE);
+    let mut page_notes: HashMap<ObjectId, Vec<Footnote>> = page_regions
+        .iter_mut()
+        .map(|(page_id, regions)| (*page_id, footnotes::take_footnotes(regions, body_font_size)))
+        .filter(|(_, notes)| !notes.is_empty())
+        .collect();
+    let analyzed_pages: HashMap<ObjectId, &Vec<Region>> = page_regions
+        .iter()
+        .filter(|(_, regions)| !regions.is_empty())
+        .map(|(page_id, regions)| (*page_id, regions))
+        .collect();
+    for (text, page_id) in page_texts.iter_mut().zip(&source_page_ids) {
+        if let Some(regions) = analyzed_pages.get(page_id) {
+            *text = regions
+                .iter()
+                .map(|region| region.text(body_font_size))
+                .collect::<Vec<_>>()
+                .join("\n\n");
+        }
+    }
+
+    // table cells are translated one by one after all of the paragraphs, followed by the
+    // descriptions of the images and the footnotes
+    let (page_paragraphs, running_lines) = paragraphs::reconstruct_paragraphs(&page_texts);
+    let mut running_lines: HashMap<ObjectId, RunningLines> =
+        source_page_ids.iter().copied().zip(running_lines).collect();
+    let mut snippets: Vec<String> = page_paragraphs
+        .iter()
+        .flatten()
+        .filter(|paragraph| *paragraph != OBJECT_MARKER)
+        .cloned()
+        .collect();
+    snippets.extend(
+        page_objects
+            .iter()
+            .flatten()
+            .flat_map(|object| match object {
+                Region::Table(table) => table.cells().cloned().collect(),
+                _ => vec![],
+            }),
+    );
+    snippets.extend(
+        page_objects
+            .iter()
+            .flatten()
+            .filter_map(|object| match object {
+                Region::Image(image) => image.alt.clone(),
+                _ => None,
+            }),
+    );
+    snippets.extend(
+        source_page_ids
+            .iter()
+            .filter_map(|page_id| page_notes.get(page_id))
+            .flatten()
+            .map(|note| note.text.clone()),
+    );
+
+    let mut edited_snippets = edit_snippets(request_options, edit_func, snippets)
+        .await?
+        .into_iter();
+    let page_paragraphs: Vec<Vec<String>> = page_paragraphs
+        .into_iter()
+        .map(|paragraphs| {
+            paragraphs
+                .into_iter()
+                .map(|paragraph| match paragraph.as_str() {
+                    OBJECT_MARKER => paragraph,
+                    _ => edited_snippets.next().unwrap_or_default(),
+                })
+                .collect()
+        })
+        .collect();
+    for object in page_objects.iter_mut().flatten() {
+        if let Region::Table(table) = object {
+            table.set_cells(&mut edited_snippets);
+        }
+    }
+    for object in page_objects.iter_mut().flatten() {
+        if let Region::Image(ImagePlacement { alt: Some(alt), .. }) = object {
+            *alt = edited_snippets.next().unwrap_or_default();
+        }
+    }
+    for page_id in &source_page_ids {
+        for note in page_notes.get_mut(page_id).into_iter().flatten() {
+            note.text = edited_snippets.next().unwrap_or_default();
+        }
+    }
+
+    let mut copied_objects = HashMap::new();
+    for ((paragraphs, objects), page_id) in page_paragraphs
+        .iter()
+        .zip(&page_objects)
+        .zip(source_page_ids)
+    {
+        if untouched_pages.contains(&page_id) {
+            let top = inherited_attribute(&doc, page_id, b"MediaBox")
+                .and_then(|media_box| media_box.as_array().ok())
+                .and_then(|media_box| media_box.get(3)?.as_float().ok())
+                .map_or(pdf_options.max_y_pos + pdf_options.line_height, f64::from);
+            pages_state.add_untouched_page(page_id, top, pdf_options);
+            continue;
+        }
+
+        pages_state.anchor_source_page(page_id, pdf_options);
+
+        pages_state.pending_notes = page_notes.remove(&page_id).unwrap_or_default();
+        format_content(pdf_options, &mut pages_state, paragraphs, objects);
+        footnotes::place_remaining_notes(&mut pages_state, pdf_options);
+        add_images_to_resources(
+            &mut edited_doc,
+            &doc,
+            &mut image_resources,
+            objects,
+            &mut copied_objects,
+        );
+    }
+
+    pages_state.finish(pdf_options);
+    footnotes::draw_notes(&mut pages_state, pdf_options);
+
+    let mut outline = outline::read_outline(&doc);
+    outline::translate_outline(&mut outline, request_options, edit_func).await?;
+    let info = metadata::translate_info(&doc, request_options, edit_func).await?;
+    if headers::uses_running_lines(pdf_options) {
+        headers::translate_running_lines(&mut running_lines, request_options, edit_func).await?;
+    }
+
+    let chapters = outline::chapters(&outline)
+        .into_iter()
+        .filter_map(|(page_id, title)| Some((pages_state.anchor_index(page_id)?, title)))
+        .collect();
+    let title = info
+        .as_ref()
+        .and_then(|info| info.get(b"Title").and_then(decode_text_string).ok());
+    let page_headers = PageHeaders::new(
+        pdf_options,
+        title,
+        pages_state.pages.len(),
+        &pages_state.source_pages(),
+        chapters,
+        &running_lines,
+    );
+
+    add_pages_to_document(
+        &mut edited_doc,
+        &doc,
+        &pages_state,
+        &page_headers,
+        pages_id,
+        &mut page_ids,
+        &mut copied_objects,
+    )?;
+
+    let resources_id = add_resources(&mut edited_doc, fonts, image_resources);
+    add_pages_object(&mut edited_doc, pages_id, &page_ids, resources_id);
+    add_catalog(&mut edited_doc, pages_id);
+    pages_state.structure.write(&mut edited_doc, &page_ids)?;
+
+    let page_map = pages_state.page_map(&page_ids);
+    outline::add_outline(&mut edited_doc, &outline, &page_map)?;
+    outline::add_named_destinations(&mut edited_doc, &doc, &page_map)?;
+
+    metadata::set_info(&mut edited_doc, info);
+    if let Some(language) = &pdf_options.language {
+        metadata::set_language(&mut edited_doc, language)?;
+    }
+
+    edited_doc.compress();
+    Ok(edited_doc)
+}
+
+/// Adds the fonts of [`REFLOW_FONTS`] to `doc`, returning the font resource dictionary.
+fn add_fonts(doc: &mut Document) -> Dictionary {
+    let mut fonts = Dictionary::new();
+    for (name, base_font) in REFLOW_FONTS {
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => base_font,
+            "Encoding" => "WinAnsiEncoding",
+        });
+        fonts.set(name, font_id);
+    }
+    fonts
+}
+
+fn font_name(emphasis: Emphasis) -> &'static str {
+    let index = match (emphasis.bold, emphasis.italic) {
+        (false, false) => 0,
+        (true, false) => 1,
+        (false, true) => 2,
+        (true, true) => 3,
+    };
+    REFLOW_FONTS[index].0
+}
+
+/// Copies the images among `objects` from `source` into `doc`, together with their soft masks,
+/// color space lookup tables and anything else they refer to, and adds them to `resources`.
+fn add_images_to_resources(
+    doc: &mut Document,
+    source: &Document,
+    resources: &mut Dictionary,
+    objects: &[Region],
+    copied: &mut HashMap<ObjectId, ObjectId>,
+) {
+    for object in objects {
+        let Region::Image(image) = object else {
+            continue;
+        };
+        let name = image_name(image);
+        if resources.has(name.as_bytes()) {
+            continue;
+        }
+
+        let image_ref = copy_object(source, doc, &Object::Reference(image.id), copied);
+        resources.set(name, image_ref);
+    }
+}
+
+fn image_name(image: &ImagePlacement) -> String {
+    format!("Im{}", image.id.0)
+}
+
+/// Copies `object` from `source` into `target`, along with every object it refers to. `copied`
+/// maps the ids of the objects copied so far to their ids in `target`, so that shared objects are
+/// only copied once.
+fn copy_object(
+    source: &Document,
+    target: &mut Document,
+    object: &Object,
+    copied: &mut HashMap<ObjectId, ObjectId>,
+) -> Object {
+    match object {
+        Object::Reference(id) => {
+            if let Some(&copy_id) = copied.get(id) {
+                return Object::Reference(copy_id);
+            }
+
+            let copy_id = target.new_object_id();
+            copied.insert(*id, copy_id);
+            let copy = match source.get_object(*id) {
+                Ok(object) => copy_object(source, target, object, copied),
+                Err(_) => Object::Null,
+            };
+            target.objects.insert(copy_id, copy);
+
+            Object::Reference(copy_id)
+        }
+        Object::Array(items) => Object::Array(
+            items
+                .iter()
+                .map(|item| copy_object(source, target, item, copied))
+                .collect(),
+        ),
+        Object::Dictionary(dict) => {
+            Object::Dictionary(copy_dictionary(source, target, dict, copied))
+        }
+        Object::Stream(stream) => {
+            let mut stream = stream.clone();
+            stream.dict = copy_dictionary(source, target, &stream.dict, copied);
+            Object::Stream(stream)
+        }
+        object => object.clone(),
+    }
+}
+
+fn copy_dictionary(
+    source: &Document,
+    target: &mut Document,
+    dict: &Dictionary,
+    copied: &mut HashMap<ObjectId, ObjectId>,
+) -> Dictionary {
+    let mut copy = Dictionary::new();
+    for (key, value) in dict.iter() {
+        copy.set(key.clone(), copy_object(source, target, value, copied));
+    }
+    copy
+}
+
+/// Adds the reflowed pages, with their headers and footers, and copies of the untouched ones.
+fn add_pages_to_document(
+    doc: &mut Document,
+    source: &Document,
+    pages_state: &PagesState,
+    page_headers: &PageHeaders,
+    pages_id: ObjectId,
+    page_ids: &mut Vec<Object>,
+    copied: &mut HashMap<ObjectId, ObjectId>,
+) -> Result<()> {
+    let untouched: HashMap<usize, ObjectId> = pages_state.untouched.iter().copied().collect();
+
+    for (index, content) in pages_state.pages.iter().enumerate() {
+        if let Some(&source_page_id) = untouched.get(&index) {
+            let page_id = copy_page(source, doc, source_page_id, pages_id, copied);
+            page_ids.push(page_id.into());
+            continue;
+        }
+
+        let mut content = content.clone();
+        content.operations.extend(page_headers.operations(index));
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode()?));
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+        });
+        page_ids.push(page_id.into());
+    }
+
+    Ok(())
+}
+
+/// Copies a page of `source` into `target` as it is, together with the attributes it inherits from
+/// the page tree. Its annotations are left out, like those of the reflowed pages, since links would
+/// bring the pages they point to along, and so is its place in the structure tree of the source.
+fn copy_page(
+    source: &Document,
+    target: &mut Document,
+    page_id: ObjectId,
+    pages_id: ObjectId,
+    copied: &mut HashMap<ObjectId, ObjectId>,
+) -> ObjectId {
+    let copy_id = target.new_object_id();
+    copied.insert(page_id, copy_id);
+
+    let mut page = source.get_dictionary(page_id).cloned().unwrap_or_default();
+    for key in [b"Parent".as_slice(), b"Annots", b"B", b"StructParents"] {
+        page.remove(key);
+    }
+    for key in INHERITABLE_PAGE_ATTRIBUTES {
+        if !page.has(key) {
+            if let Some(value) = inherited_attribute(source, page_id, key) {
+                page.set(key, value.clone());
+            }
+        }
+    }
+
+    let mut page = copy_dictionary(source, target, &page, copied);
+    page.set("Parent", pages_id);
+    target.objects.insert(copy_id, Object::Dictionary(page));
+
+    copy_id
+}
+
+fn add_resources(doc: &mut Document, fonts: Dictionary, image_resources: Dictionary) -> ObjectId {
+    doc.add_object(dictionary! {
+        "Font" => fonts,
+        "XObject" => image_resources,
+    })
+}
+
+fn add_pages_object(
+    doc: &mut Document,
+    pages_id: ObjectId,
+    page_ids: &[Object],
+    resources_id: ObjectId,
+) {
+    let pages = dictionary! {
+        "Type" => "Pages",
+        "Kids" => page_ids.to_vec(),
+        "Count" => page_ids.len() as u32,
+        "Resources" => resources_id,
+        "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+    };
+    doc.objects.insert(pages_id, Object::Dictionary(pages));
+}
+
+fn add_catalog(doc: &mut Document, pages_id: ObjectId) {
+    let catalog_id = doc.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    doc.trailer.set("Root", catalog_id);
+}
+
+/// Lays out the paragraphs of a page, drawing each table or image in `objects` where its
+/// [`OBJECT_MARKER`] is.
+fn format_content(
+    options: &PdfOptions,
+    pages_state: &mut PagesState,
+    paragraphs: &[String],
+    objects: &[Region],
+) {
+    let paragraph_split = Regex::new(r"\n\s*\n").unwrap();
+    let mut objects = objects.iter();
+
+    for paragraph in paragraphs {
+        if paragraph == OBJECT_MARKER {
+            if let Some(object) = objects.next() {
+                format_object(options, pages_state, object);
+            }
+            continue;
+        }
+
+        for paragraph in paragraph_split.split(paragraph) {
+            format_paragraph(options, pages_state, paragraph);
+        }
+    }
+
+    // objects without a marker, such as the images of a page whose layout could not be analyzed,
+    // go after the text
+    for object in objects {
+        format_object(options, pages_state, object);
+    }
+}
+
+fn format_object(options: &PdfOptions, pages_state: &mut PagesState, object: &Region) {
+    match object {
+        Region::Table(table) => table::format_table(options, pages_state, table),
+        Region::Image(image) => add_image(options, pages_state, image),
+        Region::Text(_) => {}
+    }
+}
+
+/// Lays out a paragraph marked up by [`styles`], in the fonts of its emphasis, and in bold at the
+/// size of its level if it is a heading. Body text is justified if the options ask for it, and
+/// split across pages without leaving orphan or widow lines.
+fn format_paragraph(options: &PdfOptions, pages_state: &mut PagesState, paragraph: &str) {
+    let paragraph = styles::parse_paragraph(paragraph);
+    let font_size = paragraph.heading_level.map_or(BODY_FONT_SIZE, |level| {
+        HEADING_FONT_SIZES[level as usize - 1]
+    });
+    let scale = font_size / BODY_FONT_SIZE;
+    let line_height = options.line_height * scale;
+    let bold = paragraph.heading_level.is_some();
+
+    if font_size > BODY_FONT_SIZE {
+        // room for the first line, which is taller than the body text the position was set for
+        add_paragraph_spacing(options, pages_state, line_height - options.line_height);
+    }
+
+    // headings are neither justified nor hyphenated
+    let justify = options.justify && !bold;
+    let language = if bold {
+        None
+    } else {
+        linebreak::hyphenation_language(options.language.as_deref())
+    };
+    let lines = linebreak::break_lines(
+        &paragraph.words,
+        options.max_width,
+        scale,
+        justify,
+        language,
+    );
+    if lines.is_empty() {
+        add_paragraph_spacing(options, pages_state, options.paragraph_spacing);
+        return;
+    }
+
+    // the footnotes the paragraph refers to go at the bottom of the page it starts on
+    let notes = footnotes::referenced_notes(pages_state, &paragraph.words, options);
+
+    // lines are set from `y_pos` down to the lowest position at or above `bottom`
+    let lines_per_page =
+        |y_pos: f64, bottom: f64| ((y_pos - bottom) / line_height + 1.0).floor() as usize;
+    let per_page = lines_per_page(options.max_y_pos, options.min_y_pos);
+    let mut runs = split_lines(
+        lines.len(),
+        lines_per_page(
+            pages_state.y_pos,
+            pages_state.bottom(options) + footnotes::notes_height(pages_state, &notes, options),
+        ),
+        per_page,
+        !pages_state.is_page_empty(options),
+    );
+    if runs.first() == Some(&0) {
+        create_new_page(pages_state, options.max_y_pos);
+        let bottom = options.min_y_pos + footnotes::notes_height(pages_state, &notes, options);
+        runs = split_lines(
+            lines.len(),
+            lines_per_page(options.max_y_pos, bottom),
+            per_page,
+            false,
+        );
+    }
+    let tag = paragraph
+        .heading_level
+        .map_or("P".to_string(), |level| format!("H{level}"));
+    let element = pages_state.structure.add_element(None, &tag);
+    // after the paragraph's element, so that screen readers read each note after the text citing it
+    footnotes::place_notes(pages_state, pages_state.pages.len() - 1, notes, options);
+    pages_state.begin_marked_content(element);
+
+    let mut lines = lines.iter();
+    for (index, run) in runs.into_iter().enumerate() {
+        if index > 0 {
+            create_new_page(pages_state, options.max_y_pos);
+        }
+        for line in lines.by_ref().take(run) {
+            let gaps = line.words.len().saturating_sub(1);
+            let word_spacing = if justify && !line.last && gaps > 0 {
+                (options.max_width - line.width) / gaps as f64
+            } else {
+                0.0
+            };
+            let line_height = if line.last { 0.0 } else { line_height };
+            add_line_to_page(
+                pages_state,
+                &line.words,
+                font_size,
+                bold,
+                word_spacing,
+                line_height,
+            );
+        }
+    }
+    pages_state.end_marked_content();
+
+    add_paragraph_spacing(options, pages_state, options.paragraph_spacing);
+}
+
+/// Splits the `line_count` lines of a paragraph into the runs that go on consecutive pages, given
+/// room for `available` lines on the current page and `per_page` on a new one. A split leaves at
+/// least [`ORPHAN_LINES`] at the bottom of a page and [`WIDOW_LINES`] at the top of the next; the
+/// first run is empty if the paragraph has to start on a new page for that, which it `can_move` to
+/// unless the current page is still empty.
+fn split_lines(line_count: usize, available: usize, per_page: usize, can_move: bool) -> Vec<usize> {
+    let per_page = per_page.max(1);
+    let mut runs = vec![];
+    let mut remaining = line_count;
+    let mut room = available;
+
+    while remaining > room {
+        let mut run = room.min(remaining.saturating_sub(WIDOW_LINES));
+        if runs.is_empty() && run < ORPHAN_LINES && can_move {
+            // start the paragraph on the next page instead
+            run = 0;
+        } else if run == 0 {
+            // pages too short to keep the lines together, so fill them
+            run = room;
+        }
+
+        runs.push(run);
+        remaining -= run;
+        room = per_page;
+    }
+    runs.push(remaining);
+
+    runs
+}
+
+/// Shows a line of words at `font_size`, with `word_spacing` added to every space, and moves down
+/// by `line_height`.
+fn add_line_to_page(
+    pages_state: &mut PagesState,
+    words: &[Word],
+    font_size: f64,
+    bold: bool,
+    word_spacing: f64,
+    line_height: f64,
+) {
+    if let Some(last_page) = pages_state.pages.last_mut() {
+        last_page
+            .operations
+            .extend(line_operations(words, font_size, bold, word_spacing));
+        last_page
+            .operations
+            .push(Operation::new("Td", vec![0.into(), (-line_height).into()])); // set text position
+        pages_state.y_pos -= line_height;
+    }
+}
+
+/// Operations that show a line of words at `font_size`, switching fonts where their emphasis
+/// changes and raising superscripts, with `word_spacing` added to every space. The body font is
+/// set again afterwards, since the rest of the page expects it.
+fn line_operations(
+    words: &[Word],
+    font_size: f64,
+    bold: bool,
+    word_spacing: f64,
+) -> Vec<Operation> {
+    let mut pieces: Vec<(String, Emphasis)> = vec![];
+    for (index, word) in words.iter().enumerate() {
+        for (piece_index, (text, emphasis)) in word.pieces.iter().enumerate() {
+            let emphasis = Emphasis {
+                bold: emphasis.bold || bold,
+                ..*emphasis
+            };
+            let mut text = text.clone();
+            if index > 0 && piece_index == 0 {
+                // the space between two words is set at the size of the text, not of a superscript
+                match pieces.last_mut() {
+                    Some((previous, previous_emphasis)) if !previous_emphasis.superscript => {
+                        previous.push(' ')
+                    }
+                    _ => text.insert(0, ' '),
+                }
+            }
+            match pieces.last_mut() {
+                Some((previous, previous_emphasis)) if *previous_emphasis == emphasis => {
+                    previous.push_str(&text)
+                }
+                _ => pieces.push((text, emphasis)),
+            }
+        }
+    }
+
+    let mut operations = vec![];
+    let body_font = (Emphasis::default(), BODY_FONT_SIZE);
+    let mut current_font = body_font;
+
+    if word_spacing != 0.0 {
+        operations.push(Operation::new("Tw", vec![word_spacing.into()])); // set word spacing
+    }
+    for (text, emphasis) in pieces {
+        if emphasis.superscript != current_font.0.superscript {
+            let rise = if emphasis.superscript {
+                font_size * SUPERSCRIPT_RISE
+            } else {
+                0.0
+            };
+            operations.push(Operation::new("Ts", vec![rise.into()])); // set text rise
+        }
+        let size = if emphasis.superscript {
+            font_size * SUPERSCRIPT_SCALE
+        } else {
+            font_size
+        };
+        if (emphasis, size) != current_font {
+            current_font = (emphasis, size);
+            operations.push(Operation::new(
+                "Tf", // set text font
+                vec![font_name(emphasis).into(), size.into()],
+            ));
+        }
+        operations.push(Operation::new(
+            "Tj", // show text
+            vec![Object::string_literal(encode_win_ansi(&text))],
+        ));
+    }
+    if current_font.0.superscript {
+        operations.push(Operation::new("Ts", vec![0.into()]));
+    }
+    if current_font != body_font {
+        operations.push(Operation::new(
+            "Tf",
+            vec![font_name(body_font.0).into(), body_font.1.into()],
+        ));
+    }
+    if word_spacing != 0.0 {
+        operations.push(Operation::new("Tw", vec![0.into()]));
+    }
+
+    operations
+}
+
+fn add_paragraph_spacing(
+    options: &PdfOptions,
+    pages_state: &mut PagesState,
+    paragraph_spacing: f64,
+) {
+    if let Some(last_page) = pages_state.pages.last_mut() {
+        pages_state.y_pos -= paragraph_spacing;
+        last_page.operations.push(Operation::new(
+            "Td",
+            vec![0.into(), (-paragraph_spacing).into()],
+        ));
+
+        if pages_state.y_pos < pages_state.bottom(options) {
+            create_new_page(pages_state, options.max_y_pos);
+        }
+    }
+}
+
+/// Starts a text object at the current position, for text that follows graphics drawn outside of
+/// one.
+fn begin_text_section(pages_state: &mut PagesState) {
+    let y_pos = pages_state.y_pos;
+    if let Some(last_page) = pages_state.pages.last_mut() {
+        last_page.operations.extend_from_slice(&[
+            Operation::new("BT", vec![]),
+            Operation::new("Tf", vec!["F1".into(), 12.into()]),
+            Operation::new("Td", vec![LEFT_MARGIN.into(), y_pos.into()]),
+        ]);
+    }
+}
+
+fn end_text_section(pages_state: &mut PagesState) {
+    if let Some(last_page) = pages_state.pages.last_mut() {
+        last_page.operations.push(Operation::new("ET", vec![]));
+    }
+}
+
+/// Draws an image at the size it had on the source page, scaled down to fit if needed, with its
+/// top left corner at the current position.
+fn add_image(options: &PdfOptions, pages_state: &mut PagesState, image: &ImagePlacement) {
+    let bbox = image.bbox();
+    if bbox.width() <= 0.0 || bbox.height() <= 0.0 {
+        return;
+    }
+
+    let scale = calculate_image_scale(
+        bbox.width(),
+        bbox.height(),
+        options.max_image_width,
+        options.max_image_height,
+    );
+    let scaled_height = bbox.height() * scale;
+
+    end_text_section(pages_state);
+
+    if pages_state.y_pos - scaled_height < pages_state.bottom(options) {
+        create_graphics_page(pages_state, options.max_y_pos);
+    }
+
+    let element = pages_state.structure.add_element(None, "Figure");
+    if let Some(alt) = &image.alt {
+        pages_state.structure.set_alt(element, alt.clone());
+    }
+    pages_state.begin_marked_content(element);
+    if let Some(last_page) = pages_state.pages.last_mut() {
+        add_image_operations(last_page, image, scale, pages_state.y_pos);
+        pages_state.y_pos -= scaled_height + options.paragraph_spacing;
+    }
+    pages_state.end_marked_content();
+
+    begin_text_section(pages_state);
+}
+
+fn calculate_image_scale(width: f64, height: f64, max_width: f64, max_height: f64) -> f64 {
+    let width_scale = max_width / width;
+    let height_scale = max_height / height;
+    width_scale.min(height_scale).min(1.0)
+}
+
+/// Ends the text on the last page and starts a new one. Marked content that is still open is
+/// closed on the last page and continued on the new one.
+fn create_new_page(pages_state: &mut PagesState, max_y_pos: f64) {
+    let marked = pages_state.marked;
+    pages_state.end_marked_content();
+
+    if let Some(last_page) = pages_state.pages.last_mut() {
+        last_page.operations.extend_from_slice(&[
+            Operation::new("ET", vec![]), // end text
+        ]);
+    }
+
+    pages_state.pages.push(Content {
+        operations: new_page_operations(),
+    });
+    pages_state.y_pos = max_y_pos;
+    pages_state.notes_height = 0.0;
+
+    if let Some(element) = marked {
+        pages_state.begin_marked_content(element);
+    }
+}
+
+/// Starts a new page for graphics drawn outside of a text object, such as tables and images.
+fn create_graphics_page(pages_state: &mut PagesState, max_y_pos: f64) {
+    pages_state.pages.push(Content { operations: vec![] });
+    pages_state.y_pos = max_y_pos;
+    pages_state.notes_height = 0.0;
+}
+
+/// Draws `image` scaled by `scale` with the top left corner of its bounding box at the left margin
+/// and `y_pos`. The rest of its source matrix is kept, so rotated and flipped images stay that way.
+fn add_image_operations(page: &mut Content, image: &ImagePlacement, scale: f64, y_pos: f64) {
+    let bbox = image.bbox();
+    let [a, b, c, d, e, f] = image.matrix;
+    let x = LEFT_MARGIN - (bbox.x0 - e) * scale;
+    let y = y_pos - bbox.height() * scale - (bbox.y0 - f) * scale;
+
+    page.operations.extend_from_slice(&[
+        Operation::new("q", vec![]), // save the current graphics state
+        Operation::new(
+            // apply a transformation matrix to current graphics state
+            "cm",
+            vec![
+                (a * scale).into(),
+                (b * scale).into(),
+                (c * scale).into(),
+                (d * scale).into(),
+                x.into(),
+                y.into(),
+            ],
+        ),
+        Operation::new(
+            // draw XObject
+            "Do",
+            vec![Object::Name(image_name(image).into_bytes())],
+        ),
+        Operation::new("Q", vec![]), // restore previosly saved graphics state
+    ]);
+}
+
+fn string_width(s: &str) -> f64 {
+    // every glyph of Courier is 600 units of a thousandth of the font size wide
+    const CHAR_WIDTH: f64 = 0.6 * BODY_FONT_SIZE;
+    s.chars().count() as f64 * CHAR_WIDTH
+}
+
+/// Breaks `text` into lines that fit `max_width` when set at `font_size`, keeping its own line
+/// breaks. A word wider than a line gets a line of its own.
+fn wrap_text(text: &str, font_size: f64, max_width: f64) -> Vec<String> {
+    let scale = font_size / BODY_FONT_SIZE;
+    let mut lines = vec![];
+
+    for paragraph in text.lines() {
+        let mut current = String::new();
+        for word in paragraph.split_whitespace() {
+            let candidate = if current.is_empty() {
+                word.to_string()
+            } else {
+                format!("{current} {word}")
+            };
+
+            if string_width(&candidate) * scale > max_width && !current.is_empty() {
+                lines.push(std::mem::replace(&mut current, word.to_string()));
+            } else {
+                current = candidate;
+            }
+        }
+        lines.push(current);
+    }
+
+    lines
+}
+
+fn new_page_operations() -> Vec<Operation> {
+    vec![
+        Operation::new("BT", vec![]),                               // begin text
+        Operation::new("Tf", vec!["F1".into(), 12.into()]),         // set text font
+        Operation::new("Td", vec![LEFT_MARGIN.into(), 750.into()]), // set text position
+    ]
+}
+
+/// Returns a copy of the resource dictionary that applies to `page_id`, following the page tree
+/// for inherited resources.
+fn page_resources(doc: &Document, page_id: ObjectId) -> Dictionary {
+    inherited_attribute(doc, page_id, b"Resources")
+        .and_then(|resources| resources.as_dict().ok())
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Returns the value of `key` for `page_id`, dereferenced, looking it up in the page's ancestors
+/// if the page doesn't set it itself.
+fn inherited_attribute<'a>(doc: &'a Document, page_id: ObjectId, key: &[u8]) -> Option<&'a Object> {
+    let mut node = doc.get_dictionary(page_id).ok();
+    let mut depth = 0;
+
+    while let Some(dict) = node {
+        if let Ok((_, value)) = dict.get(key).and_then(|value| doc.dereference(value)) {
+            return Some(value);
+        }
+
+        depth += 1;
+        if depth > 32 {
+            break;
+        }
+        node = dict
+            .get(b"Parent")
+            .and_then(Object::as_reference)
+            .and_then(|id| doc.get_dictionary(id))
+            .ok();
+    }
+
+    None
+}
+
+/// Makes `font_id` available to the content of a page as `name`, giving the page a resource
+/// dictionary of its own so that pages sharing their resources are left alone.
+fn add_page_font(
+    doc: &mut Document,
+    page_id: ObjectId,
+    name: &str,
+    font_id: ObjectId,
+) -> Result<()> {
+    let mut resources = page_resources(doc, page_id);
+    let mut fonts = resources
+        .get(b"Font")
+        .and_then(|fonts| doc.dereference(fonts))
+        .and_then(|(_, fonts)| fonts.as_dict())
+        .cloned()
+        .unwrap_or_default();
+
+    fonts.set(name, font_id);
+    resources.set("Font", fonts);
+    doc.get_dictionary_mut(page_id)?.set("Resources", resources);
+
+    Ok(())
+}
+
+/// Encodes `text` for a simple font using `WinAnsiEncoding`, replacing characters the encoding
+/// can't represent with `?`.
+fn encode_win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c {
+            '\u{20}'..='\u{7e}' | '\u{a0}'..='\u{ff}' => c as u8,
+            '€' => 0x80,
+            '‚' => 0x82,
+            'ƒ' => 0x83,
+            '„' => 0x84,
+            '…' => 0x85,
+            '†' => 0x86,
+            '‡' => 0x87,
+            'ˆ' => 0x88,
+            '‰' => 0x89,
+            'Š' => 0x8a,
+            '‹' => 0x8b,
+            'Œ' => 0x8c,
+            'Ž' => 0x8e,
+            '‘' => 0x91,
+            '’' => 0x92,
+            '“' => 0x93,
+            '”' => 0x94,
+            '•' => 0x95,
+            '–' => 0x96,
+            '—' => 0x97,
+            '˜' => 0x98,
+            '™' => 0x99,
+            'š' => 0x9a,
+            '›' => 0x9b,
+            'œ' => 0x9c,
+            'ž' => 0x9e,
+            'Ÿ' => 0x9f,
+            c if c.is_whitespace() => b' ',
+            _ => b'?',
+        })
+        .collect()
+}
@@ -0,0 +1,482 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use eyre::{eyre, Result};
+use lopdf::{Document, Object, ObjectId, Stream};
+
+use super::{
+    styles::Emphasis,
+    text::{apply, ImagePlacement, PageContent, TextRun},
+};
+
+/// Recognizes the text in scanned page images.
+pub trait OcrBackend {
+    /// Returns the lines of text found in the image file at `path`.
+    fn recognize(&self, path: &Path) -> Result<Vec<OcrLine>>;
+}
+
+/// A line of recognized text, with its bounding box in pixels from the top left corner of the image
+#[derive(Clone, Debug)]
+pub struct OcrLine {
+    pub text: String,
+    pub left: f64,
+    pub top: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// Runs the `tesseract` command line program on each image.
+pub struct TesseractCommand {
+    program: PathBuf,
+    /// Languages of the scanned text, in Tesseract's notation, such as `eng` or `deu+fra`
+    languages: String,
+}
+
+impl TesseractCommand {
+    pub fn new(program: impl Into<PathBuf>, languages: impl Into<String>) -> Self {
+        Self {
+            program: program.into(),
+            languages: languages.into(),
+        }
+    }
+}
+
+impl OcrBackend for TesseractCommand {
+    fn recognize(&self, path: &Path) -> Result<Vec<OcrLine>> {
+        let output = Command::new(&self.program)
+            .arg(path)
+            .arg("stdout")
+            .args(["-l", &self.languages])
+            .arg("tsv")
+            .output()?;
+
+        if !output.status.success() {
+            return Err(eyre!(
+                "{} failed: {}",
+                self.program.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        Ok(parse_tsv(&String::from_utf8_lossy(&output.stdout)))
+    }
+}
+
+/// Joins the words of Tesseract's TSV output into lines.
+fn parse_tsv(tsv: &str) -> Vec<OcrLine> {
+    // keyed by page, block, paragraph and line number, so the lines come out in Tesseract's order
+    let mut lines: BTreeMap<[u32; 4], OcrLine> = BTreeMap::new();
+
+    for row in tsv.lines().skip(1) {
+        let fields: Vec<&str> = row.split('\t').collect();
+        let [level, page, block, paragraph, line, _word, left, top, width, height, _confidence, text] =
+            fields.as_slice()
+        else {
+            continue;
+        };
+        // level 5 rows are words, the others describe the page structure
+        if *level != "5" || text.trim().is_empty() {
+            continue;
+        }
+
+        let key = [page, block, paragraph, line].map(|number| number.parse().unwrap_or(0));
+        let [Ok(left), Ok(top), Ok(width), Ok(height)] =
+            [left, top, width, height].map(|number| number.parse::<f64>())
+        else {
+            continue;
+        };
+
+        match lines.get_mut(&key) {
+            Some(line) => {
+                let right = (line.left + line.width).max(left + width);
+                let bottom = (line.top + line.height).max(top + height);
+                line.left = line.left.min(left);
+                line.top = line.top.min(top);
+                line.width = right - line.left;
+                line.height = bottom - line.top;
+                line.text.push(' ');
+                line.text.push_str(text.trim());
+            }
+            None => {
+                lines.insert(
+                    key,
+                    OcrLine {
+                        text: text.trim().to_string(),
+                        left,
+                        top,
+                        width,
+                        height,
+                    },
+                );
+            }
+        }
+    }
+
+    lines.into_values().collect()
+}
+
+/// Runs OCR on the images of a page that has no text of its own, such as a scan, and adds the
+/// recognized lines to its runs as if they had been set in the page. Images that text was found in
+/// are taken out of `content.images`, since the text replaces them.
+pub(super) fn recognize_scanned_page(
+    doc: &Document,
+    content: &mut PageContent,
+    backend: &dyn OcrBackend,
+    page_num: u32,
+) {
+    if !content.runs.is_empty() || content.images.is_empty() {
+        return;
+    }
+
+    tracing::info!("Running OCR on page {page_num}...");
+
+    let mut remaining = vec![];
+    for image in std::mem::take(&mut content.images) {
+        match recognize_image(doc, &image, backend) {
+            Ok(runs) if !runs.is_empty() => content.runs.extend(runs),
+            Ok(_) => remaining.push(image),
+            Err(err) => {
+                tracing::warn!("Unable to run OCR on an image of page {page_num}: {err}");
+                remaining.push(image);
+            }
+        }
+    }
+    content.images = remaining;
+}
+
+fn recognize_image(
+    doc: &Document,
+    image: &ImagePlacement,
+    backend: &dyn OcrBackend,
+) -> Result<Vec<TextRun>> {
+    let stream = doc.get_object(image.id)?.as_stream()?;
+    let width = stream.dict.get(b"Width")?.as_i64()? as f64;
+    let height = stream.dict.get(b"Height")?.as_i64()? as f64;
+
+    let path = write_image_file(doc, image.id, stream)?;
+    let lines = backend.recognize(&path);
+    let _ = fs::remove_file(&path);
+
+    // image space has its origin at the top left, the unit square the matrix maps at the bottom left
+    let runs = lines?
+        .into_iter()
+        .map(|line| {
+            let (x0, bottom) = (line.left / width, 1.0 - (line.top + line.height) / height);
+            let (x1, top) = ((line.left + line.width) / width, 1.0 - line.top / height);
+            let (start_x, start_y) = apply(&image.matrix, x0, bottom);
+            let (end_x, _) = apply(&image.matrix, x1, bottom);
+            let (_, top_y) = apply(&image.matrix, x0, top);
+            let font_size = (top_y - start_y).abs();
+
+            TextRun {
+                text: line.text,
+                x: start_x.min(end_x),
+                // the line box runs from the descenders to the ascenders
+                y: start_y.min(top_y) + font_size * 0.25,
+                width: (end_x - start_x).abs(),
+                font_size,
+                emphasis: Emphasis::default(),
+            }
+        })
+        .collect();
+
+    Ok(runs)
+}
+
+/// Writes an image XObject to a temporary file in a format OCR engines read: JPEG and JPEG 2000
+/// data as it is, fax-encoded data wrapped in a TIFF header, and anything else decoded to a
+/// portable bitmap, graymap or pixmap.
+fn write_image_file(doc: &Document, id: ObjectId, stream: &Stream) -> Result<PathBuf> {
+    let filters: Vec<Vec<u8>> = match stream.dict.get(b"Filter") {
+        Ok(Object::Name(name)) => vec![name.clone()],
+        Ok(Object::Array(names)) => names
+            .iter()
+            .filter_map(|name| name.as_name().ok().map(<[u8]>::to_vec))
+            .collect(),
+        _ => vec![],
+    };
+
+    let (extension, data) = match filters.last().map(Vec::as_slice) {
+        Some(b"DCTDecode") if filters.len() == 1 => ("jpg", stream.content.clone()),
+        Some(b"JPXDecode") if filters.len() == 1 => ("jp2", stream.content.clone()),
+        Some(b"CCITTFaxDecode") if filters.len() == 1 => ("tif", fax_tiff(doc, stream)?),
+        Some(b"JBIG2Decode") => return Err(eyre!("JBIG2 images are not supported")),
+        _ => ("pnm", portable_anymap(doc, stream)?),
+    };
+
+    let path = std::env::temp_dir().join(format!(
+        "ziggurat-ocr-{}-{}-{}.{extension}",
+        std::process::id(),
+        id.0,
+        id.1
+    ));
+    fs::write(&path, data)?;
+
+    Ok(path)
+}
+
+fn image_size(stream: &Stream) -> Result<(u32, u32)> {
+    Ok((
+        stream.dict.get(b"Width")?.as_i64()? as u32,
+        stream.dict.get(b"Height")?.as_i64()? as u32,
+    ))
+}
+
+/// The color spaces of the images that can be decoded for OCR
+enum ColorSpace {
+    Gray,
+    Rgb,
+    Cmyk,
+    /// Samples that index a palette of colors in the base color space, with the components of
+    /// each color in turn
+    Indexed {
+        base: Box<ColorSpace>,
+        palette: Vec<u8>,
+    },
+}
+
+impl ColorSpace {
+    /// Reads the color space `object`, or fails if it isn't one of those OCR images are decoded
+    /// from.
+    fn read(doc: &Document, object: &Object) -> Result<Self> {
+        let (_, object) = doc.dereference(object)?;
+        let (family, params) = match object {
+            Object::Name(name) => (name.as_slice(), &[][..]),
+            Object::Array(array) => match array.split_first() {
+                Some((family, params)) => (family.as_name()?, params),
+                None => return Err(eyre!("Image with an empty color space")),
+            },
+            _ => return Err(eyre!("Image with an invalid color space")),
+        };
+
+        let color_space = match family {
+            b"DeviceGray" | b"G" | b"CalGray" => Self::Gray,
+            b"DeviceRGB" | b"RGB" | b"CalRGB" => Self::Rgb,
+            b"DeviceCMYK" | b"CMYK" => Self::Cmyk,
+            // an ICC profile describes colors with the components of one of the device spaces
+            b"ICCBased" => {
+                let profile = params
+                    .first()
+                    .ok_or(eyre!("ICC based color space without a profile"))
+                    .and_then(|profile| Ok(doc.dereference(profile)?.1.as_stream()?))?;
+                match profile.dict.get(b"N").and_then(Object::as_i64) {
+                    Ok(1) => Self::Gray,
+                    Ok(3) => Self::Rgb,
+                    Ok(4) => Self::Cmyk,
+                    _ => Self::read(doc, profile.dict.get(b"Alternate")?)?,
+                }
+            }
+            b"Indexed" | b"I" => {
+                let [base, _, lookup] = params else {
+                    return Err(eyre!("Indexed color space without a base and a palette"));
+                };
+                let base = Self::read(doc, base)?;
+                if matches!(base, Self::Indexed { .. }) {
+                    return Err(eyre!("Indexed color space with an indexed base"));
+                }
+                let palette = match doc.dereference(lookup)?.1 {
+                    Object::String(palette, _) => palette.clone(),
+                    Object::Stream(stream) => stream_data(stream)?,
+                    _ => return Err(eyre!("Indexed color space with an invalid palette")),
+                };
+                Self::Indexed {
+                    base: Box::new(base),
+                    palette,
+                }
+            }
+            family => {
+                return Err(eyre!(
+                    "Images in the {} color space are not supported",
+                    String::from_utf8_lossy(family)
+                ))
+            }
+        };
+
+        Ok(color_space)
+    }
+
+    fn components(&self) -> usize {
+        match self {
+            Self::Gray | Self::Indexed { .. } => 1,
+            Self::Rgb => 3,
+            Self::Cmyk => 4,
+        }
+    }
+}
+
+/// The content of `stream`, decompressed.
+fn stream_data(stream: &Stream) -> Result<Vec<u8>> {
+    if !stream.dict.has(b"Filter") {
+        return Ok(stream.content.clone());
+    }
+    // lopdf refuses to decompress anything marked as an image
+    let mut stream = stream.clone();
+    stream.dict.remove(b"Subtype");
+    Ok(stream.decompressed_content()?)
+}
+
+/// Decodes an image into a PBM, PGM or PPM file, depending on its color space. The samples of
+/// indexed images are looked up in their palette.
+fn portable_anymap(doc: &Document, stream: &Stream) -> Result<Vec<u8>> {
+    let (width, height) = image_size(stream)?;
+    let data = stream_data(stream)?;
+    let bits = stream
+        .dict
+        .get(b"BitsPerComponent")
+        .and_then(Object::as_i64)
+        .unwrap_or(1);
+    let is_mask = stream
+        .dict
+        .get(b"ImageMask")
+        .and_then(Object::as_bool)
+        .unwrap_or(false);
+
+    let mut file = vec![];
+    let color_space = match stream.dict.get(b"ColorSpace") {
+        _ if is_mask => None,
+        Ok(color_space) => Some(ColorSpace::read(doc, color_space)?),
+        Err(_) => return Err(eyre!("Images without a color space are not supported")),
+    };
+    if matches!(color_space, None | Some(ColorSpace::Gray)) && bits == 1 {
+        file.extend(format!("P4\n{width} {height}\n").into_bytes());
+        // in PDF a 0 bit is black (or painted, for masks) unless `Decode` swaps them, in PBM it is
+        // white
+        let invert = !has_inverted_decode(stream);
+        file.extend(data.iter().map(|byte| if invert { !byte } else { *byte }));
+        return Ok(file);
+    }
+
+    let (color_space, data) = match color_space {
+        Some(ColorSpace::Indexed { base, palette }) if matches!(bits, 1 | 2 | 4 | 8) => {
+            let components = base.components();
+            let pixels = indices(&data, width as usize, bits as usize)
+                .flat_map(|index| {
+                    let start = index * components;
+                    (start..start + components).map(|i| palette.get(i).copied().unwrap_or(0))
+                })
+                .collect();
+            (*base, pixels)
+        }
+        Some(color_space) if bits == 8 => (color_space, data),
+        _ => return Err(eyre!("{bits}-bit images are not supported")),
+    };
+
+    match color_space {
+        ColorSpace::Gray => {
+            file.extend(format!("P5\n{width} {height}\n255\n").into_bytes());
+            file.extend(data);
+        }
+        ColorSpace::Rgb => {
+            file.extend(format!("P6\n{width} {height}\n255\n").into_bytes());
+            file.extend(data);
+        }
+        ColorSpace::Cmyk => {
+            file.extend(format!("P6\n{width} {height}\n255\n").into_bytes());
+            for pixel in data.chunks_exact(4) {
+                let black = 255 - pixel[3] as u32;
+                file.extend(
+                    pixel[..3]
+                        .iter()
+                        .map(|&ink| ((255 - ink as u32) * black / 255) as u8),
+                );
+            }
+        }
+        ColorSpace::Indexed { .. } => {
+            return Err(eyre!("Indexed color space with an indexed base"));
+        }
+    }
+
+    Ok(file)
+}
+
+/// The palette indices of the `bits`-bit samples of an image `width` pixels wide, whose rows start
+/// on a byte boundary.
+fn indices(data: &[u8], width: usize, bits: usize) -> impl Iterator<Item = usize> + '_ {
+    let row_length = (width * bits).div_ceil(8);
+    data.chunks(row_length.max(1)).flat_map(move |row| {
+        (0..width).map(move |x| {
+            let bit = x * bits;
+            let byte = row.get(bit / 8).copied().unwrap_or(0) as usize;
+            (byte >> (8 - bits - bit % 8)) & ((1 << bits) - 1)
+        })
+    })
+}
+
+fn has_inverted_decode(stream: &Stream) -> bool {
+    let decode = stream.dict.get(b"Decode").and_then(Object::as_array);
+    matches!(decode.map(Vec::as_slice), Ok([first, ..]) if first.as_float().ok() == Some(1.0))
+}
+
+/// Wraps CCITT fax data in a single-strip TIFF file, which is how most OCR engines read it.
+fn fax_tiff(doc: &Document, stream: &Stream) -> Result<Vec<u8>> {
+    let (width, height) = image_size(stream)?;
+    let params = stream
+        .dict
+        .get(b"DecodeParms")
+        .and_then(|params| doc.dereference(params))
+        .and_then(|(_, params)| match params {
+            Object::Array(params) => params
+                .first()
+                .ok_or(lopdf::Error::DictKey("DecodeParms".to_string()))
+                .and_then(Object::as_dict),
+            params => params.as_dict(),
+        })
+        .ok();
+    let param = |key: &[u8], default: i64| {
+        params
+            .and_then(|params| params.get(key).and_then(Object::as_i64).ok())
+            .unwrap_or(default)
+    };
+
+    let k = param(b"K", 0);
+    let rows = param(b"Rows", height as i64) as u32;
+    let (compression, options_tag) = match k {
+        k if k < 0 => (4, (293, 0)),
+        0 => (3, (292, 0)),
+        _ => (3, (292, 1)),
+    };
+
+    // tag, type (3 = SHORT, 4 = LONG), value
+    let mut entries: Vec<(u16, u16, u32)> = vec![
+        (256, 4, param(b"Columns", width as i64) as u32),
+        (257, 4, rows),
+        (258, 3, 1),
+        (259, 3, compression),
+        (262, 3, 0),
+        (273, 4, 0),
+        (277, 3, 1),
+        (278, 4, rows),
+        (279, 4, stream.content.len() as u32),
+    ];
+    entries.push((options_tag.0, 4, options_tag.1));
+    entries.sort_by_key(|(tag, _, _)| *tag);
+
+    let header_len = 8;
+    let directory_len = 2 + entries.len() * 12 + 4;
+    let data_offset = (header_len + directory_len) as u32;
+
+    let mut file = b"II*\0".to_vec();
+    file.extend(8u32.to_le_bytes());
+    file.extend((entries.len() as u16).to_le_bytes());
+    for (tag, kind, value) in entries {
+        let value = if tag == 273 { data_offset } else { value };
+        file.extend(tag.to_le_bytes());
+        file.extend(kind.to_le_bytes());
+        file.extend(1u32.to_le_bytes());
+        match kind {
+            3 => {
+                file.extend((value as u16).to_le_bytes());
+                file.extend([0, 0]);
+            }
+            _ => file.extend(value.to_le_bytes()),
+        }
+    }
+    file.extend(0u32.to_le_bytes());
+    file.extend(&stream.content);
+
+    Ok(file)
+}
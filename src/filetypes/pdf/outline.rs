@@ -0,0 +1,378 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    future::Future,
+    sync::Arc,
+};
+
+use eyre::Result;
+use lopdf::{decode_text_string, dictionary, text_string, Dictionary, Document, Object, ObjectId};
+
+use super::{edit_snippets, PageMap};
+use crate::options::RequestOptions;
+
+pub(super) struct OutlineItem {
+    id: ObjectId,
+    title: String,
+    target: Option<Target>,
+    open: bool,
+    children: Vec<OutlineItem>,
+}
+
+enum Target {
+    /// A source page, either given directly or through a named destination
+    Page(ObjectId),
+    /// An external link
+    Uri(Vec<u8>),
+}
+
+/// Reads the outline (bookmark) tree of `doc`, resolving every destination to a source page.
+pub(super) fn read_outline(doc: &Document) -> Vec<OutlineItem> {
+    let Ok(root) = doc
+        .catalog()
+        .and_then(|catalog| catalog.get_deref(b"Outlines", doc))
+        .and_then(Object::as_dict)
+    else {
+        return vec![];
+    };
+    let destinations = named_destinations(doc);
+
+    read_siblings(doc, root, &destinations, &mut HashSet::new())
+}
+
+fn read_siblings(
+    doc: &Document,
+    parent: &Dictionary,
+    destinations: &BTreeMap<Vec<u8>, Object>,
+    seen: &mut HashSet<ObjectId>,
+) -> Vec<OutlineItem> {
+    let mut items = vec![];
+    let mut next = parent.get(b"First").and_then(Object::as_reference).ok();
+
+    while let Some(id) = next {
+        // outline trees are linked lists, so a broken file can easily contain a cycle
+        if !seen.insert(id) {
+            break;
+        }
+        let Ok(node) = doc.get_dictionary(id) else {
+            break;
+        };
+
+        let title = node
+            .get_deref(b"Title", doc)
+            .and_then(decode_text_string)
+            .unwrap_or_default();
+        let open = node
+            .get(b"Count")
+            .and_then(Object::as_i64)
+            .is_ok_and(|count| count > 0);
+
+        items.push(OutlineItem {
+            id,
+            title,
+            target: resolve_target(doc, node, destinations),
+            open,
+            children: read_siblings(doc, node, destinations, seen),
+        });
+
+        next = node.get(b"Next").and_then(Object::as_reference).ok();
+    }
+
+    items
+}
+
+fn resolve_target(
+    doc: &Document,
+    node: &Dictionary,
+    destinations: &BTreeMap<Vec<u8>, Object>,
+) -> Option<Target> {
+    if let Ok(dest) = node.get_deref(b"Dest", doc) {
+        return resolve_destination(doc, dest, destinations).map(Target::Page);
+    }
+
+    let action = node.get_deref(b"A", doc).and_then(Object::as_dict).ok()?;
+    match action.get(b"S").and_then(Object::as_name).ok()? {
+        b"GoTo" => {
+            let dest = action.get_deref(b"D", doc).ok()?;
+            resolve_destination(doc, dest, destinations).map(Target::Page)
+        }
+        b"URI" => action
+            .get_deref(b"URI", doc)
+            .and_then(Object::as_str)
+            .ok()
+            .map(|uri| Target::Uri(uri.to_vec())),
+        _ => None,
+    }
+}
+
+/// Resolves an explicit or named destination to the id of the page it points to.
+fn resolve_destination(
+    doc: &Document,
+    dest: &Object,
+    destinations: &BTreeMap<Vec<u8>, Object>,
+) -> Option<ObjectId> {
+    let dest = match dest {
+        Object::Name(name) | Object::String(name, _) => destinations.get(name)?,
+        dest => dest,
+    };
+
+    explicit_destination_page(doc, dest)
+}
+
+fn explicit_destination_page(doc: &Document, dest: &Object) -> Option<ObjectId> {
+    let (_, dest) = doc.dereference(dest).ok()?;
+    let dest = match dest {
+        // named destinations may be wrapped in a dictionary with the array under `D`
+        Object::Dictionary(dict) => doc.dereference(dict.get(b"D").ok()?).ok()?.1,
+        dest => dest,
+    };
+
+    dest.as_array().ok()?.first()?.as_reference().ok()
+}
+
+/// Collects the named destinations from both the PDF 1.1 `Dests` dictionary and the `Dests`
+/// name tree of the catalog.
+fn named_destinations(doc: &Document) -> BTreeMap<Vec<u8>, Object> {
+    let mut destinations = BTreeMap::new();
+    let Ok(catalog) = doc.catalog() else {
+        return destinations;
+    };
+
+    if let Ok(dests) = catalog.get_deref(b"Dests", doc).and_then(Object::as_dict) {
+        for (name, dest) in dests.iter() {
+            destinations.insert(name.clone(), dest.clone());
+        }
+    }
+
+    if let Ok(tree) = catalog
+        .get_deref(b"Names", doc)
+        .and_then(Object::as_dict)
+        .and_then(|names| names.get_deref(b"Dests", doc))
+        .and_then(Object::as_dict)
+    {
+        collect_name_tree(doc, tree, &mut destinations, &mut HashSet::new());
+    }
+
+    destinations
+}
+
+fn collect_name_tree(
+    doc: &Document,
+    node: &Dictionary,
+    entries: &mut BTreeMap<Vec<u8>, Object>,
+    seen: &mut HashSet<ObjectId>,
+) {
+    if let Ok(names) = node.get_deref(b"Names", doc).and_then(Object::as_array) {
+        for pair in names.chunks_exact(2) {
+            if let Ok(name) = pair[0].as_str() {
+                entries.insert(name.to_vec(), pair[1].clone());
+            }
+        }
+    }
+
+    if let Ok(kids) = node.get_deref(b"Kids", doc).and_then(Object::as_array) {
+        for kid in kids {
+            let Ok(id) = kid.as_reference() else {
+                continue;
+            };
+            if !seen.insert(id) {
+                continue;
+            }
+            if let Ok(kid) = doc.get_dictionary(id) {
+                collect_name_tree(doc, kid, entries, seen);
+            }
+        }
+    }
+}
+
+pub(super) async fn translate_outline<F, Fut>(
+    items: &mut [OutlineItem],
+    request_options: &RequestOptions,
+    edit_func: &Arc<F>,
+) -> Result<()>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let mut titles = vec![];
+    collect_titles(items, &mut titles);
+
+    let titles = edit_snippets(request_options, edit_func, titles).await?;
+    set_titles(items, &mut titles.into_iter());
+
+    Ok(())
+}
+
+/// Collects the titles to translate, leaving out the empty ones, which `set_titles` skips too.
+fn collect_titles(items: &[OutlineItem], titles: &mut Vec<String>) {
+    for item in items {
+        if !item.title.trim().is_empty() {
+            titles.push(item.title.clone());
+        }
+        collect_titles(&item.children, titles);
+    }
+}
+
+fn set_titles(items: &mut [OutlineItem], titles: &mut impl Iterator<Item = String>) {
+    for item in items {
+        if !item.title.trim().is_empty() {
+            if let Some(title) = titles.next() {
+                item.title = title;
+            }
+        }
+        set_titles(&mut item.children, titles);
+    }
+}
+
+/// The chapters of the document in order, as the source page each starts on and its title. They are
+/// the top level items of the outline, or the items below it if it has a single one, such as the
+/// title of a book.
+pub(super) fn chapters(items: &[OutlineItem]) -> Vec<(ObjectId, String)> {
+    let items = match items {
+        [item] if !item.children.is_empty() => &item.children,
+        items => items,
+    };
+
+    items
+        .iter()
+        .filter_map(|item| match item.target {
+            Some(Target::Page(page_id)) => Some((page_id, item.title.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Writes the translated titles back into the outline items of the document they were read from.
+pub(super) fn retitle_outline(doc: &mut Document, items: &[OutlineItem]) -> Result<()> {
+    for item in items {
+        doc.get_dictionary_mut(item.id)?
+            .set("Title", text_string(&item.title));
+        retitle_outline(doc, &item.children)?;
+    }
+
+    Ok(())
+}
+
+/// Rebuilds the outline tree in a reflowed document, pointing each item at the output page where
+/// the text of its source page begins.
+pub(super) fn add_outline(
+    doc: &mut Document,
+    items: &[OutlineItem],
+    page_map: &PageMap,
+) -> Result<()> {
+    if items.is_empty() {
+        return Ok(());
+    }
+
+    let outlines_id = doc.new_object_id();
+    let (first, last, count) = add_outline_items(doc, items, outlines_id, page_map);
+    doc.objects.insert(
+        outlines_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Outlines",
+            "First" => first,
+            "Last" => last,
+            "Count" => count,
+        }),
+    );
+    doc.catalog_mut()?.set("Outlines", outlines_id);
+
+    Ok(())
+}
+
+/// Adds `items` as the children of `parent_id` and returns the first and last child along with
+/// the number of visible descendants.
+fn add_outline_items(
+    doc: &mut Document,
+    items: &[OutlineItem],
+    parent_id: ObjectId,
+    page_map: &PageMap,
+) -> (ObjectId, ObjectId, i64) {
+    let ids: Vec<ObjectId> = items.iter().map(|_| doc.new_object_id()).collect();
+    let mut visible = 0;
+
+    for (index, item) in items.iter().enumerate() {
+        let mut node = dictionary! {
+            "Title" => text_string(&item.title),
+            "Parent" => parent_id,
+        };
+        if index > 0 {
+            node.set("Prev", ids[index - 1]);
+        }
+        if let Some(&next) = ids.get(index + 1) {
+            node.set("Next", next);
+        }
+
+        match &item.target {
+            Some(Target::Page(source_id)) => {
+                if let Some(&(page_id, top)) = page_map.get(source_id) {
+                    node.set("Dest", page_destination(page_id, top));
+                }
+            }
+            Some(Target::Uri(uri)) => node.set(
+                "A",
+                dictionary! {
+                    "S" => "URI",
+                    "URI" => Object::string_literal(uri.clone()),
+                },
+            ),
+            None => {}
+        }
+
+        visible += 1;
+        if !item.children.is_empty() {
+            let (first, last, count) = add_outline_items(doc, &item.children, ids[index], page_map);
+            node.set("First", first);
+            node.set("Last", last);
+            if item.open {
+                node.set("Count", count);
+                visible += count;
+            } else {
+                // a closed item counts the descendants that would show if it were opened
+                node.set("Count", -count);
+            }
+        }
+
+        doc.objects.insert(ids[index], Object::Dictionary(node));
+    }
+
+    (ids[0], ids[ids.len() - 1], visible)
+}
+
+/// Carries the named destinations of `source` over to a reflowed document so that links into it
+/// by name keep working.
+pub(super) fn add_named_destinations(
+    doc: &mut Document,
+    source: &Document,
+    page_map: &PageMap,
+) -> Result<()> {
+    let mut names = vec![];
+    for (name, dest) in named_destinations(source) {
+        let Some(&(page_id, top)) =
+            explicit_destination_page(source, &dest).and_then(|source_id| page_map.get(&source_id))
+        else {
+            continue;
+        };
+        names.push(Object::String(name, lopdf::StringFormat::Literal));
+        names.push(page_destination(page_id, top));
+    }
+
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let dests_id = doc.add_object(dictionary! { "Names" => names });
+    doc.catalog_mut()?
+        .set("Names", dictionary! { "Dests" => dests_id });
+
+    Ok(())
+}
+
+fn page_destination(page_id: ObjectId, top: f64) -> Object {
+    Object::Array(vec![
+        page_id.into(),
+        "XYZ".into(),
+        Object::Null,
+        top.into(),
+        Object::Null,
+    ])
+}
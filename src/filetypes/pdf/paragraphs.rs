@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use super::styles::{self, plain_text};
+
+/// Lines this close to the top or bottom of a page are candidates for running headers, footers
+/// and page numbers
+const MARGIN_LINES: usize = 2;
+
+/// A margin line that repeats on at least this fraction of the pages is a running header or footer
+const RUNNING_LINE_RATIO: f64 = 0.5;
+
+/// Running headers are only detected in documents with at least this many pages
+const MIN_PAGES_FOR_RUNNING_LINES: usize = 3;
+
+/// A line ending a sentence is taken to end its paragraph when it is shorter than this fraction of
+/// the typical line length
+const SHORT_LINE_RATIO: f64 = 0.8;
+
+/// Stands in for a table or image in the page text, so that the paragraphs around it keep their
+/// place. It is always a paragraph of its own and is never dropped or merged with its neighbours.
+pub(super) const OBJECT_MARKER: &str = "\u{fffc}";
+
+/// The running header and footer dropped from a source page, as plain text without their page
+/// numbers
+#[derive(Clone, Debug, Default)]
+pub(super) struct RunningLines {
+    pub(super) header: String,
+    pub(super) footer: String,
+}
+
+/// Turns the text extracted from each page into paragraphs.
+///
+/// `extract_text` returns the text as it was laid out: with hard line breaks, words hyphenated at
+/// the end of lines, running headers, footers and page numbers in the middle of the flow, and
+/// sentences split across pages. This rejoins hyphenated words, merges wrapped lines into
+/// paragraphs, drops repeated headers, footers and page numbers, and moves a paragraph that
+/// continues on the next page back onto the page where it starts.
+///
+/// The lines may be marked up with [`styles`]; lines marked as headings are kept out of the
+/// paragraphs around them. The running headers and footers that were dropped are returned along
+/// with the paragraphs, by page.
+pub(super) fn reconstruct_paragraphs(pages: &[String]) -> (Vec<Vec<String>>, Vec<RunningLines>) {
+    let mut pages: Vec<Vec<&str>> = pages
+        .iter()
+        .map(|page| page.lines().map(str::trim).collect())
+        .collect();
+
+    let running_lines = remove_running_lines(&mut pages);
+
+    let typical_length = typical_line_length(&pages);
+    let mut paragraphs: Vec<Vec<String>> = pages
+        .iter()
+        .map(|lines| merge_lines(lines, typical_length))
+        .collect();
+
+    carry_across_pages(&mut paragraphs);
+
+    (paragraphs, running_lines)
+}
+
+/// Blanks out page numbers and lines that repeat in the margins of many pages, returning the
+/// repeated lines of each page.
+fn remove_running_lines(pages: &mut [Vec<&str>]) -> Vec<RunningLines> {
+    // Roman page numbers are only taken in lowercase, as front matter numbers its pages, so that
+    // chapter headings such as `IV` and words such as `Did` at the top of a page are kept
+    let page_number = Regex::new(
+        r"^((?i:page)\s+)?[-–—(\[]?\s*(\d+|m{0,3}(cm|cd|d?c{0,3})(xc|xl|l?x{0,3})(ix|iv|v?i{0,3}))\s*[-–—)\]]?(\s*((?i:of)|/)\s*\d+)?$",
+    )
+    .unwrap();
+    let is_page_number = |text: &str| {
+        page_number
+            .captures(text)
+            .is_some_and(|captures| !captures[2].is_empty())
+    };
+    // a page number at either end of a running header, such as `12 | Chapter One`
+    let edge_number = Regex::new(r"^\d+\s*[-–—|·•]?\s+|\s+[-–—|·•]?\s*\d+$").unwrap();
+
+    let mut margin_counts: HashMap<String, usize> = HashMap::new();
+    for lines in pages.iter() {
+        let mut seen = vec![];
+        for &index in &margin_indices(lines) {
+            let key = normalize(lines[index]);
+            if !seen.contains(&key) {
+                seen.push(key.clone());
+                *margin_counts.entry(key).or_default() += 1;
+            }
+        }
+    }
+
+    let detect_running = pages.len() >= MIN_PAGES_FOR_RUNNING_LINES;
+    let min_count = ((pages.len() as f64 * RUNNING_LINE_RATIO).ceil() as usize).max(2);
+
+    let mut running_lines = vec![];
+    for lines in pages.iter_mut() {
+        let mut running = RunningLines::default();
+        let middle = lines.len() / 2;
+
+        for index in margin_indices(lines) {
+            let line = lines[index];
+            let text = plain_text(line);
+            let is_running = detect_running && margin_counts[&normalize(line)] >= min_count;
+
+            if is_running || is_page_number(&text) {
+                tracing::debug!("Dropping running header or footer {line:?}");
+                lines[index] = "";
+            }
+
+            if is_running && !is_page_number(&text) {
+                let kept = if index < middle {
+                    &mut running.header
+                } else {
+                    &mut running.footer
+                };
+                if !kept.is_empty() {
+                    kept.push(' ');
+                }
+                kept.push_str(edge_number.replace_all(text.trim(), "").trim());
+            }
+        }
+        running_lines.push(running);
+    }
+
+    running_lines
+}
+
+/// Indices of the first and last few non-blank lines of a page.
+fn margin_indices(lines: &[&str]) -> Vec<usize> {
+    let non_blank: Vec<usize> = (0..lines.len())
+        .filter(|&index| !lines[index].is_empty() && lines[index] != OBJECT_MARKER)
+        .collect();
+
+    let mut indices: Vec<usize> = non_blank.iter().take(MARGIN_LINES).copied().collect();
+    for &index in non_blank.iter().rev().take(MARGIN_LINES) {
+        if !indices.contains(&index) {
+            indices.push(index);
+        }
+    }
+
+    indices
+}
+
+/// Makes running lines that only differ in their page number compare equal.
+fn normalize(line: &str) -> String {
+    plain_text(line)
+        .split_whitespace()
+        .map(|word| {
+            word.chars()
+                .map(|c| if c.is_ascii_digit() { '#' } else { c })
+                .collect::<String>()
+                .to_lowercase()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn typical_line_length(pages: &[Vec<&str>]) -> usize {
+    let mut lengths: Vec<usize> = pages
+        .iter()
+        .flatten()
+        .filter(|&&line| !line.is_empty() && line != OBJECT_MARKER)
+        .map(|line| plain_text(line).chars().count())
+        .collect();
+
+    if lengths.is_empty() {
+        return 0;
+    }
+
+    lengths.sort_unstable();
+    // upper quartile rather than median, since short lines (headings, ends of paragraphs) are common
+    lengths[lengths.len() * 3 / 4]
+}
+
+fn merge_lines(lines: &[&str], typical_length: usize) -> Vec<String> {
+    let mut paragraphs = vec![];
+    let mut current = String::new();
+
+    for &line in lines {
+        if line.is_empty() {
+            push_paragraph(&mut paragraphs, &mut current);
+            continue;
+        }
+
+        if line == OBJECT_MARKER {
+            push_paragraph(&mut paragraphs, &mut current);
+            paragraphs.push(line.to_string());
+            continue;
+        }
+
+        // headings are paragraphs of their own, though one may take up several lines
+        let heading_level = styles::heading_level(line);
+        if heading_level.is_some() && styles::heading_level(&current) == heading_level {
+            styles::extend_heading(&mut current, line);
+            continue;
+        }
+        if heading_level.is_some() || styles::heading_level(&current).is_some() {
+            push_paragraph(&mut paragraphs, &mut current);
+        }
+
+        if !current.is_empty() && starts_new_paragraph(&current, line, typical_length) {
+            push_paragraph(&mut paragraphs, &mut current);
+        }
+
+        join_line(&mut current, line);
+    }
+    push_paragraph(&mut paragraphs, &mut current);
+
+    paragraphs
+}
+
+fn push_paragraph(paragraphs: &mut Vec<String>, current: &mut String) {
+    if !current.is_empty() {
+        paragraphs.push(std::mem::take(current));
+    }
+}
+
+fn starts_new_paragraph(paragraph: &str, line: &str, typical_length: usize) -> bool {
+    let previous_line_length = paragraph
+        .rsplit('\n')
+        .next()
+        .map_or(0, |line| plain_text(line).chars().count());
+    let previous_is_short =
+        (previous_line_length as f64) < typical_length as f64 * SHORT_LINE_RATIO;
+
+    (ends_sentence(paragraph) && previous_is_short) || is_list_item(&plain_text(line))
+}
+
+/// Appends `line` to `paragraph`, rejoining a word that was hyphenated across the line break.
+pub(super) fn join_line(paragraph: &mut String, line: &str) {
+    if paragraph.is_empty() {
+        paragraph.push_str(line);
+        return;
+    }
+
+    if is_hyphenated(paragraph, line) {
+        paragraph.pop();
+    } else {
+        // the newline marks where the previous line ended so its length can still be measured;
+        // it is turned into a space once the paragraph is complete
+        paragraph.push('\n');
+    }
+    paragraph.push_str(line);
+}
+
+fn is_hyphenated(text: &str, next: &str) -> bool {
+    let mut chars = text.chars().rev();
+    let ends_with_hyphen =
+        chars.next() == Some('-') && chars.next().is_some_and(char::is_alphabetic);
+
+    ends_with_hyphen && next.chars().next().is_some_and(char::is_lowercase)
+}
+
+fn ends_sentence(text: &str) -> bool {
+    let last_line = text.rsplit('\n').next().unwrap_or(text);
+    plain_text(last_line)
+        .trim_end()
+        .ends_with(['.', '!', '?', ':', '"', '”', '»', ')', '…'])
+}
+
+fn is_list_item(line: &str) -> bool {
+    let mut chars = line.chars();
+    match chars.next() {
+        Some('•' | '◦' | '▪' | '–' | '*') => true,
+        Some(c) if c.is_ascii_digit() => {
+            let rest: String = chars.take_while(|c| !c.is_whitespace()).collect();
+            rest.trim_start_matches(|c: char| c.is_ascii_digit())
+                .starts_with(['.', ')'])
+        }
+        _ => false,
+    }
+}
+
+/// Moves paragraphs that continue from the end of one page onto the next back to the page where
+/// they start, then turns the line markers into spaces.
+fn carry_across_pages(pages: &mut [Vec<String>]) {
+    for index in 1..pages.len() {
+        let continues = match (pages[index - 1].last(), pages[index].first()) {
+            (Some(previous), Some(next))
+                if previous != OBJECT_MARKER
+                    && next != OBJECT_MARKER
+                    && styles::heading_level(previous).is_none()
+                    && styles::heading_level(next).is_none() =>
+            {
+                let starts_lowercase = plain_text(next)
+                    .chars()
+                    .next()
+                    .is_some_and(char::is_lowercase);
+                is_hyphenated(previous, next) || (!ends_sentence(previous) && starts_lowercase)
+            }
+            _ => false,
+        };
+
+        if continues {
+            let next = pages[index].remove(0);
+            if let Some(previous) = pages[index - 1].last_mut() {
+                join_line(previous, &next);
+            }
+        }
+    }
+
+    for paragraph in pages.iter_mut().flatten() {
+        *paragraph = styles::join_spans(&paragraph.replace('\n', " "));
+    }
+}
@@ -0,0 +1,600 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::Arc,
+};
+
+use eyre::Result;
+use lopdf::{
+    content::{Content, Operation},
+    dictionary, Dictionary, Document, Encoding, Object, ObjectId, Stream,
+};
+
+use super::{
+    add_page_font, edit_snippets, encode_win_ansi, forms,
+    layout::{self, Rect, Region},
+    metadata, ocr, outline, text, OcrBackend,
+};
+use crate::options::{PdfOptions, RequestOptions};
+
+/// Resource name of the font the translated text is set in
+const FONT_NAME: &str = "ZgF1";
+
+/// Translated text never shrinks below this fraction of the original font size
+const MIN_FONT_SCALE: f64 = 0.5;
+
+/// Average width of a Helvetica character, in ems, for fitting invisible text to a line
+const AVERAGE_CHAR_WIDTH: f64 = 0.5;
+
+/// Where a content stream comes from
+#[derive(Clone, Copy)]
+enum ContentOwner {
+    Page(ObjectId),
+    /// A form XObject drawn by a page, whose content is shared by every page that draws it
+    Form(ObjectId),
+}
+
+/// A content stream with text to translate
+struct TextContent {
+    owner: ContentOwner,
+    content: Content,
+    /// The resources the content is drawn with
+    resources: Dictionary,
+    text_objects: Vec<TextObject>,
+}
+
+/// A `BT` ... `ET` text object in a content stream
+struct TextObject {
+    /// Index of the `BT` operation
+    start: usize,
+    /// Index of the `ET` operation
+    end: usize,
+    text: String,
+    font_size: f64,
+}
+
+/// Text recognized on a scanned page: a block, or a whole table
+struct ScannedText {
+    lines: Vec<ScannedLine>,
+    text: String,
+}
+
+struct ScannedLine {
+    bbox: Rect,
+    font_size: f64,
+    /// Number of characters recognized on the line
+    length: usize,
+}
+
+/// Translates `doc` without rebuilding it: every text object in the page content streams, and in
+/// the form XObjects they draw, has its text replaced by the translation, while graphics, images,
+/// annotations (including links), outlines, named destinations and form fields stay exactly where
+/// they were. A form XObject drawn on several pages is translated once, for all of them.
+///
+/// Pages without any text, such as scans, are run through OCR if a backend is configured, and get
+/// the translation of the recognized text as an invisible layer over the scan.
+pub(super) async fn edit_in_place<F, Fut>(
+    mut doc: Document,
+    request_options: &RequestOptions,
+    pdf_options: &PdfOptions,
+    edit_func: &Arc<F>,
+) -> Result<Document>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let mut pages = vec![];
+    let mut snippets = vec![];
+    // the form XObjects collected so far, and whether they show text
+    let mut forms = HashMap::new();
+
+    for (page_num, page_id) in doc.get_pages() {
+        if !pdf_options.is_page_selected(page_num) {
+            continue;
+        }
+
+        let content = doc.get_and_decode_page_content(page_id)?;
+        let resources = super::page_resources(&doc, page_id);
+        let mut contents = vec![];
+        let has_text = collect_text_contents(
+            &doc,
+            ContentOwner::Page(page_id),
+            content,
+            resources,
+            0,
+            &mut forms,
+            &mut contents,
+        );
+        let scanned = match &pdf_options.ocr {
+            Some(backend) if !has_text => recognize_page(&doc, page_id, page_num, backend.as_ref()),
+            _ => vec![],
+        };
+
+        snippets.extend(
+            contents
+                .iter()
+                .flat_map(|content| &content.text_objects)
+                .map(|object| object.text.clone()),
+        );
+        snippets.extend(scanned.iter().map(|scanned| scanned.text.clone()));
+        pages.push((page_id, contents, scanned));
+    }
+
+    let mut translations = edit_snippets(request_options, edit_func, snippets)
+        .await?
+        .into_iter();
+
+    let font_id = doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica",
+        "Encoding" => "WinAnsiEncoding",
+    });
+
+    for (page_id, contents, scanned) in pages {
+        for text_content in contents {
+            let TextContent {
+                owner,
+                content,
+                resources,
+                text_objects,
+            } = text_content;
+            let content = replace_text_objects(content, &text_objects, &mut translations);
+            match owner {
+                ContentOwner::Page(page_id) => {
+                    set_page_content(&mut doc, page_id, content.encode()?)?;
+                    add_page_font(&mut doc, page_id, FONT_NAME, font_id)?;
+                }
+                ContentOwner::Form(form_id) => {
+                    set_form_content(&mut doc, form_id, content.encode()?, resources, font_id)?;
+                }
+            }
+        }
+        if !scanned.is_empty() {
+            let operations = text_layer_operations(&scanned, &mut translations);
+            layout::isolate_page_content(&mut doc, page_id)?;
+            doc.add_page_contents(page_id, Content { operations }.encode()?)?;
+            add_page_font(&mut doc, page_id, FONT_NAME, font_id)?;
+        }
+    }
+
+    forms::translate_form(&mut doc, request_options, edit_func).await?;
+
+    let mut outline = outline::read_outline(&doc);
+    outline::translate_outline(&mut outline, request_options, edit_func).await?;
+    outline::retitle_outline(&mut doc, &outline)?;
+
+    let info = metadata::translate_info(&doc, request_options, edit_func).await?;
+    metadata::set_info(&mut doc, info);
+    if let Some(language) = &pdf_options.language {
+        metadata::set_language(&mut doc, language)?;
+    }
+
+    // drop the replaced content streams
+    doc.prune_objects();
+    doc.compress();
+    Ok(doc)
+}
+
+/// Collects `content`, drawn with `resources`, into `contents` if it has text objects, along with
+/// the form XObjects it draws that haven't been collected yet, depth first. Returns whether any of
+/// them shows text, including the forms collected before.
+fn collect_text_contents(
+    doc: &Document,
+    owner: ContentOwner,
+    content: Content,
+    resources: Dictionary,
+    depth: usize,
+    forms: &mut HashMap<ObjectId, bool>,
+    contents: &mut Vec<TextContent>,
+) -> bool {
+    let text_objects = find_text_objects(doc, &resources, &content);
+    let mut has_text = !text_objects.is_empty();
+
+    let names = content
+        .operations
+        .iter()
+        .filter(|operation| operation.operator == "Do")
+        .filter_map(|operation| operation.operands.first()?.as_name().ok());
+    let mut form_contents = vec![];
+    for name in names {
+        let Some((form_id, form)) = form_xobject(doc, &resources, name) else {
+            continue;
+        };
+        if let Some(&form_has_text) = forms.get(&form_id) {
+            has_text |= form_has_text;
+            continue;
+        }
+        if depth >= text::MAX_FORM_DEPTH {
+            tracing::warn!(
+                "Form XObject {} {} is nested too deeply, leaving any text in it untranslated",
+                form_id.0,
+                form_id.1
+            );
+            continue;
+        }
+        let Ok(form_content) = text::form_content(form) else {
+            tracing::warn!(
+                "Unable to decode form XObject {} {}, leaving any text in it untranslated",
+                form_id.0,
+                form_id.1
+            );
+            continue;
+        };
+        // forms without resources of their own use those of what draws them
+        let form_resources = form
+            .dict
+            .get_deref(b"Resources", doc)
+            .and_then(Object::as_dict)
+            .unwrap_or(&resources)
+            .clone();
+
+        // marked first, so that forms drawing themselves end
+        forms.insert(form_id, false);
+        let form_has_text = collect_text_contents(
+            doc,
+            ContentOwner::Form(form_id),
+            form_content,
+            form_resources,
+            depth + 1,
+            forms,
+            &mut form_contents,
+        );
+        forms.insert(form_id, form_has_text);
+        has_text |= form_has_text;
+    }
+
+    if !text_objects.is_empty() {
+        contents.push(TextContent {
+            owner,
+            content,
+            resources,
+            text_objects,
+        });
+    }
+    contents.extend(form_contents);
+
+    has_text
+}
+
+/// The form XObject `name` of `resources`, with its id.
+fn form_xobject<'a>(
+    doc: &'a Document,
+    resources: &Dictionary,
+    name: &[u8],
+) -> Option<(ObjectId, &'a Stream)> {
+    let id = resources
+        .get_deref(b"XObject", doc)
+        .and_then(Object::as_dict)
+        .and_then(|xobjects| xobjects.get(name))
+        .and_then(Object::as_reference)
+        .ok()?;
+    let form = doc.get_object(id).and_then(Object::as_stream).ok()?;
+
+    (form.dict.get(b"Subtype").and_then(Object::as_name).ok()? == b"Form").then_some((id, form))
+}
+
+fn find_text_objects(doc: &Document, resources: &Dictionary, content: &Content) -> Vec<TextObject> {
+    let encodings: BTreeMap<Vec<u8>, Encoding> = resources
+        .get_deref(b"Font", doc)
+        .and_then(Object::as_dict)
+        .map(|fonts| {
+            fonts
+                .iter()
+                .filter_map(|(name, font)| {
+                    let font = doc.dereference(font).ok()?.1.as_dict().ok()?;
+                    Some((name.clone(), font.get_font_encoding(doc).ok()?))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut text_objects = vec![];
+    let mut current: Option<TextObject> = None;
+    let mut encoding = None;
+    let mut font_size = 12.0;
+
+    for (index, operation) in content.operations.iter().enumerate() {
+        match operation.operator.as_str() {
+            "BT" => {
+                current = Some(TextObject {
+                    start: index,
+                    end: index,
+                    text: String::new(),
+                    font_size,
+                });
+            }
+            "ET" => {
+                if let Some(mut object) = current.take() {
+                    if !object.text.trim().is_empty() {
+                        object.end = index;
+                        object.text = object.text.trim().to_string();
+                        text_objects.push(object);
+                    }
+                }
+            }
+            "Tf" => {
+                if let [name, size] = operation.operands.as_slice() {
+                    encoding = name.as_name().ok().and_then(|name| encodings.get(name));
+                    font_size = size.as_float().map_or(font_size, f64::from);
+                }
+                if let Some(object) = current.as_mut().filter(|object| object.text.is_empty()) {
+                    object.font_size = font_size;
+                }
+            }
+            "T*" | "Td" | "TD" | "Tm" => {
+                if let Some(object) = current.as_mut() {
+                    push_separator(&mut object.text);
+                }
+            }
+            "Tj" | "TJ" | "'" | "\"" => {
+                if let (Some(object), Some(encoding)) = (current.as_mut(), encoding) {
+                    if operation.operator != "Tj" && operation.operator != "TJ" {
+                        push_separator(&mut object.text);
+                    }
+                    collect_text(&mut object.text, encoding, &operation.operands);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    text_objects
+}
+
+fn collect_text(text: &mut String, encoding: &Encoding, operands: &[Object]) {
+    for operand in operands {
+        match operand {
+            Object::String(bytes, _) => {
+                if let Ok(decoded) = Document::decode_text(encoding, bytes) {
+                    text.push_str(&decoded);
+                }
+            }
+            Object::Array(array) => collect_text(text, encoding, array),
+            // large negative adjustments in a TJ array are how many producers space words
+            Object::Integer(adjustment) if *adjustment < -100 => push_separator(text),
+            Object::Real(adjustment) if *adjustment < -100.0 => push_separator(text),
+            _ => {}
+        }
+    }
+}
+
+fn push_separator(text: &mut String) {
+    if !text.is_empty() && !text.ends_with(char::is_whitespace) {
+        text.push(' ');
+    }
+}
+
+/// Replaces the text shown by each text object with its translation, keeping the positioning and
+/// graphics state operations. The translation is shown where the original text began.
+fn replace_text_objects(
+    content: Content,
+    text_objects: &[TextObject],
+    translations: &mut impl Iterator<Item = String>,
+) -> Content {
+    let mut operations = Vec::with_capacity(content.operations.len());
+    let mut objects = text_objects.iter().peekable();
+    // the text object being rewritten, and its translation until it has been shown
+    let mut active: Option<(&TextObject, Option<String>)> = None;
+    // the source's font as set by its last `Tf`, which is set again after each translation so that
+    // the text shown after it keeps its font, along with the fonts saved by `q`
+    let mut source_font: Option<Operation> = None;
+    let mut saved_fonts = vec![];
+
+    for (index, operation) in content.operations.into_iter().enumerate() {
+        match operation.operator.as_str() {
+            "Tf" => source_font = Some(operation.clone()),
+            "q" => saved_fonts.push(source_font.clone()),
+            "Q" => {
+                if let Some(font) = saved_fonts.pop() {
+                    source_font = font;
+                }
+            }
+            _ => {}
+        }
+        if let Some(object) = objects.next_if(|object| object.start == index) {
+            let translation = translations.next().unwrap_or_else(|| object.text.clone());
+            active = Some((object, Some(translation)));
+        }
+
+        match active.as_mut() {
+            Some((object, translation)) if is_text_showing(&operation) => {
+                if operation.operator == "\"" {
+                    if let [word_spacing, char_spacing, _] = operation.operands.as_slice() {
+                        operations.push(Operation::new("Tw", vec![word_spacing.clone()]));
+                        operations.push(Operation::new("Tc", vec![char_spacing.clone()]));
+                    }
+                }
+                if operation.operator == "'" || operation.operator == "\"" {
+                    operations.push(Operation::new("T*", vec![]));
+                }
+                if let Some(translation) = translation.take() {
+                    let font_size = fit_font_size(object.font_size, &object.text, &translation);
+                    operations.push(Operation::new(
+                        "Tf",
+                        vec![Object::Name(FONT_NAME.into()), font_size.into()],
+                    ));
+                    operations.push(Operation::new(
+                        "Tj",
+                        vec![Object::string_literal(encode_win_ansi(&translation))],
+                    ));
+                    operations.extend(source_font.clone());
+                }
+            }
+            _ => operations.push(operation),
+        }
+
+        if active
+            .as_ref()
+            .is_some_and(|(object, _)| object.end == index)
+        {
+            active = None;
+        }
+    }
+
+    Content { operations }
+}
+
+fn is_text_showing(operation: &Operation) -> bool {
+    matches!(operation.operator.as_str(), "Tj" | "TJ" | "'" | "\"")
+}
+
+/// Shrinks the font size in proportion to how much longer the translation is than the original,
+/// so that it roughly keeps to the original's width.
+fn fit_font_size(font_size: f64, original: &str, translation: &str) -> f64 {
+    let original_len = original.chars().count() as f64;
+    let translation_len = translation.chars().count().max(1) as f64;
+    let scale = (original_len / translation_len).clamp(MIN_FONT_SCALE, 1.0);
+
+    font_size * scale
+}
+
+fn recognize_page(
+    doc: &Document,
+    page_id: ObjectId,
+    page_num: u32,
+    backend: &dyn OcrBackend,
+) -> Vec<ScannedText> {
+    let mut content = match text::extract_content(doc, page_id) {
+        Ok(content) => content,
+        Err(err) => {
+            tracing::warn!("Unable to read the images of page {page_num}: {err}");
+            return vec![];
+        }
+    };
+    ocr::recognize_scanned_page(doc, &mut content, backend, page_num);
+    if content.runs.is_empty() {
+        return vec![];
+    }
+
+    layout::analyze_page(content)
+        .into_iter()
+        .filter_map(|region| match region {
+            Region::Text(block) => Some(ScannedText {
+                lines: block
+                    .lines
+                    .iter()
+                    .map(|line| ScannedLine {
+                        bbox: line.bbox,
+                        font_size: line.font_size,
+                        length: line.text().chars().count(),
+                    })
+                    .collect(),
+                text: block.text().replace('\n', " "),
+            }),
+            // the cells are translated together and laid over the table as a single line
+            Region::Table(table) => Some(ScannedText {
+                lines: vec![ScannedLine {
+                    bbox: table.bbox,
+                    font_size: table.bbox.height() / table.rows.len().max(1) as f64,
+                    length: 1,
+                }],
+                text: table.cells().cloned().collect::<Vec<_>>().join(" "),
+            }),
+            Region::Image(_) => None,
+        })
+        .collect()
+}
+
+/// Sets each translation as invisible text over the lines of the scan it translates, so that the
+/// translation can be searched and copied while the scan stays as it is. The words are shared out
+/// between the lines in proportion to their original length, and each line is stretched or
+/// squeezed to the width of the original.
+fn text_layer_operations(
+    scanned: &[ScannedText],
+    translations: &mut impl Iterator<Item = String>,
+) -> Vec<Operation> {
+    let mut operations = vec![
+        Operation::new("BT", vec![]),
+        // render mode 3 neither fills nor strokes the glyphs
+        Operation::new("Tr", vec![3.into()]),
+    ];
+
+    for scanned in scanned {
+        let translation = translations.next().unwrap_or_else(|| scanned.text.clone());
+        let lengths: Vec<usize> = scanned.lines.iter().map(|line| line.length).collect();
+
+        for (line, text) in scanned
+            .lines
+            .iter()
+            .zip(share_words(&translation, &lengths))
+        {
+            let (bbox, font_size) = (line.bbox, line.font_size);
+            let natural_width = text.chars().count() as f64 * font_size * AVERAGE_CHAR_WIDTH;
+            // lines the OCR backend gave no height can't be scaled to their width
+            if natural_width <= 0.0 || bbox.width() <= 0.0 {
+                continue;
+            }
+            operations.extend([
+                Operation::new("Tf", vec![Object::Name(FONT_NAME.into()), font_size.into()]),
+                Operation::new("Tz", vec![(bbox.width() / natural_width * 100.0).into()]),
+                Operation::new(
+                    "Tm",
+                    vec![
+                        1.into(),
+                        0.into(),
+                        0.into(),
+                        1.into(),
+                        bbox.x0.into(),
+                        (bbox.y0 + font_size * 0.25).into(),
+                    ],
+                ),
+                Operation::new("Tj", vec![Object::string_literal(encode_win_ansi(&text))]),
+            ]);
+        }
+    }
+
+    operations.push(Operation::new("ET", vec![]));
+    operations
+}
+
+/// Splits the words of `text` into as many lines as there are `lengths`, each getting a share of
+/// the words proportional to its length.
+fn share_words(text: &str, lengths: &[usize]) -> Vec<String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let total: usize = lengths.iter().sum::<usize>().max(1);
+
+    let mut lines = vec![];
+    let mut before = 0;
+    for length in lengths {
+        let start = words.len() * before / total;
+        before += length;
+        let end = words.len() * before / total;
+        lines.push(words[start..end].join(" "));
+    }
+
+    lines
+}
+
+/// Replaces the content of the form XObject `form_id`, drawn with `resources`, and makes `font_id`
+/// available to it. The form gets a resource dictionary of its own, so that the forms and pages
+/// sharing its resources are left alone.
+fn set_form_content(
+    doc: &mut Document,
+    form_id: ObjectId,
+    content: Vec<u8>,
+    mut resources: Dictionary,
+    font_id: ObjectId,
+) -> Result<()> {
+    let mut fonts = resources
+        .get_deref(b"Font", doc)
+        .and_then(Object::as_dict)
+        .cloned()
+        .unwrap_or_default();
+    fonts.set(FONT_NAME, font_id);
+    resources.set("Font", fonts);
+
+    let form = doc.get_object_mut(form_id)?.as_stream_mut()?;
+    form.dict.set("Resources", resources);
+    form.set_plain_content(content);
+
+    Ok(())
+}
+
+/// Gives the page a content stream of its own, since the source may share streams between pages.
+fn set_page_content(doc: &mut Document, page_id: ObjectId, content: Vec<u8>) -> Result<()> {
+    let content_id = doc.add_object(Stream::new(dictionary! {}, content));
+    doc.get_dictionary_mut(page_id)?.set("Contents", content_id);
+
+    Ok(())
+}
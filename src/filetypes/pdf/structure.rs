@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+
+use eyre::Result;
+use lopdf::{
+    content::Operation, decode_text_string, dictionary, text_string, Document, Object, ObjectId,
+};
+
+/// Structure elements nested deeper than this in a source document are not searched for alternate
+/// descriptions
+const MAX_STRUCTURE_DEPTH: usize = 64;
+
+/// The logical structure of the reflowed text, which is written out as the structure tree of a
+/// tagged PDF so that screen readers can read the pages in order and tell headings, paragraphs,
+/// figures and tables apart
+#[derive(Debug, Default)]
+pub(super) struct StructureTree {
+    elements: Vec<Element>,
+    /// The next marked content id to hand out on each output page, by index
+    next_mcids: HashMap<usize, i64>,
+}
+
+#[derive(Debug)]
+struct Element {
+    /// Standard structure type, such as `P`, `H1` or `Figure`
+    tag: String,
+    parent: Option<usize>,
+    alt: Option<String>,
+    kids: Vec<Kid>,
+}
+
+#[derive(Debug)]
+enum Kid {
+    Element(usize),
+    /// A marked-content sequence on the output page with index `page`
+    Content {
+        page: usize,
+        mcid: i64,
+    },
+}
+
+impl StructureTree {
+    /// Adds an element of type `tag` as the last child of `parent`, or of the document if there is
+    /// no parent, and returns its index.
+    pub(super) fn add_element(&mut self, parent: Option<usize>, tag: &str) -> usize {
+        let index = self.elements.len();
+        self.elements.push(Element {
+            tag: tag.to_string(),
+            parent,
+            alt: None,
+            kids: vec![],
+        });
+        if let Some(parent) = parent {
+            self.elements[parent].kids.push(Kid::Element(index));
+        }
+
+        index
+    }
+
+    pub(super) fn set_alt(&mut self, element: usize, alt: String) {
+        self.elements[element].alt = Some(alt);
+    }
+
+    /// Starts a marked-content sequence on the output page with index `page` that belongs to
+    /// `element`, returning the operation that begins it. It is ended by [`end_marked_content`].
+    pub(super) fn begin_marked_content(&mut self, element: usize, page: usize) -> Operation {
+        let next_mcid = self.next_mcids.entry(page).or_default();
+        let mcid = *next_mcid;
+        *next_mcid += 1;
+
+        let element = &mut self.elements[element];
+        element.kids.push(Kid::Content { page, mcid });
+
+        Operation::new(
+            "BDC",
+            vec![
+                Object::Name(element.tag.clone().into_bytes()),
+                Object::Dictionary(dictionary! { "MCID" => mcid }),
+            ],
+        )
+    }
+
+    /// Adds the structure tree to `doc`, whose pages are `page_ids` in the order of their indices,
+    /// and marks the document as tagged.
+    pub(super) fn write(&self, doc: &mut Document, page_ids: &[Object]) -> Result<()> {
+        let root_id = doc.new_object_id();
+        let document_id = doc.new_object_id();
+        let element_ids: Vec<ObjectId> =
+            self.elements.iter().map(|_| doc.new_object_id()).collect();
+        let page_id = |page: usize| page_ids.get(page).cloned().unwrap_or(Object::Null);
+
+        // the elements each marked-content sequence of a page belongs to, by marked content id
+        let mut parents: HashMap<usize, Vec<Object>> = HashMap::new();
+        let mut top_level = vec![];
+
+        for (index, element) in self.elements.iter().enumerate() {
+            let parent_id = element
+                .parent
+                .map_or(document_id, |parent| element_ids[parent]);
+            if element.parent.is_none() {
+                top_level.push(Object::Reference(element_ids[index]));
+            }
+
+            let mut kids = vec![];
+            for kid in &element.kids {
+                match *kid {
+                    Kid::Element(kid) => kids.push(Object::Reference(element_ids[kid])),
+                    Kid::Content { page, mcid } => {
+                        kids.push(
+                            dictionary! {
+                                "Type" => "MCR",
+                                "Pg" => page_id(page),
+                                "MCID" => mcid,
+                            }
+                            .into(),
+                        );
+
+                        let page_parents = parents.entry(page).or_default();
+                        let mcid = mcid as usize;
+                        if page_parents.len() <= mcid {
+                            page_parents.resize(mcid + 1, Object::Null);
+                        }
+                        page_parents[mcid] = Object::Reference(element_ids[index]);
+                    }
+                }
+            }
+
+            let mut dict = dictionary! {
+                "Type" => "StructElem",
+                "S" => element.tag.as_str(),
+                "P" => parent_id,
+                "K" => kids,
+            };
+            if let Some(alt) = &element.alt {
+                dict.set("Alt", text_string(alt));
+            }
+            doc.objects
+                .insert(element_ids[index], Object::Dictionary(dict));
+        }
+
+        doc.objects.insert(
+            document_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "StructElem",
+                "S" => "Document",
+                "P" => root_id,
+                "K" => top_level,
+            }),
+        );
+
+        // the parent tree maps the `/StructParents` key of each page to the elements of its
+        // marked content
+        let mut pages: Vec<usize> = parents.keys().copied().collect();
+        pages.sort_unstable();
+        let mut nums = vec![];
+        for page in pages {
+            let Ok(page_id) = page_id(page).as_reference() else {
+                continue;
+            };
+            doc.get_dictionary_mut(page_id)?
+                .set("StructParents", page as i64);
+            nums.push(Object::Integer(page as i64));
+            nums.push(Object::Array(parents.remove(&page).unwrap_or_default()));
+        }
+
+        doc.objects.insert(
+            root_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "StructTreeRoot",
+                "K" => document_id,
+                "ParentTree" => dictionary! { "Nums" => nums },
+                "ParentTreeNextKey" => page_ids.len() as i64,
+            }),
+        );
+
+        let catalog = doc.catalog_mut()?;
+        catalog.set("StructTreeRoot", root_id);
+        catalog.set("MarkInfo", dictionary! { "Marked" => true });
+
+        Ok(())
+    }
+}
+
+/// Ends the marked-content sequence started last.
+pub(super) fn end_marked_content() -> Operation {
+    Operation::new("EMC", vec![])
+}
+
+/// Operations that wrap `operations` as an artifact, content such as rules and borders that is
+/// not part of the text and that screen readers skip.
+pub(super) fn artifact(operations: Vec<Operation>) -> Vec<Operation> {
+    let mut artifact = vec![Operation::new("BMC", vec!["Artifact".into()])];
+    artifact.extend(operations);
+    artifact.push(end_marked_content());
+    artifact
+}
+
+/// Reads the alternate descriptions in the structure tree of a tagged document, keyed by the page
+/// and marked content id of the content they describe.
+pub(super) fn read_alt_texts(doc: &Document) -> HashMap<(ObjectId, i64), String> {
+    let mut alt_texts = HashMap::new();
+    let root = doc
+        .catalog()
+        .and_then(|catalog| catalog.get_deref(b"StructTreeRoot", doc))
+        .and_then(Object::as_dict);
+
+    if let Ok(kids) = root.and_then(|root| root.get(b"K")) {
+        collect_alt_texts(doc, kids, None, None, 0, &mut alt_texts);
+    }
+
+    alt_texts
+}
+
+/// Walks the kids `object` of a structure element, recording `alt` for every marked content they
+/// contain. `page` is the page the kids are on unless they say otherwise.
+fn collect_alt_texts(
+    doc: &Document,
+    object: &Object,
+    page: Option<ObjectId>,
+    alt: Option<&str>,
+    depth: usize,
+    alt_texts: &mut HashMap<(ObjectId, i64), String>,
+) {
+    if depth > MAX_STRUCTURE_DEPTH {
+        return;
+    }
+
+    let object = match doc.dereference(object) {
+        Ok((_, object)) => object,
+        Err(_) => return,
+    };
+
+    match object {
+        Object::Array(kids) => {
+            for kid in kids {
+                collect_alt_texts(doc, kid, page, alt, depth + 1, alt_texts);
+            }
+        }
+        Object::Integer(mcid) => {
+            if let (Some(page), Some(alt)) = (page, alt) {
+                alt_texts.insert((page, *mcid), alt.to_string());
+            }
+        }
+        Object::Dictionary(dict) => {
+            let page = dict.get(b"Pg").and_then(Object::as_reference).ok().or(page);
+
+            if dict.has_type(b"MCR") {
+                if let (Some(page), Some(alt), Ok(mcid)) =
+                    (page, alt, dict.get(b"MCID").and_then(Object::as_i64))
+                {
+                    alt_texts.insert((page, mcid), alt.to_string());
+                }
+                return;
+            }
+
+            let own_alt = dict
+                .get(b"Alt")
+                .and_then(decode_text_string)
+                .ok()
+                .filter(|alt| !alt.trim().is_empty());
+            let alt = own_alt.as_deref().or(alt);
+            if let Ok(kids) = dict.get(b"K") {
+                collect_alt_texts(doc, kids, page, alt, depth + 1, alt_texts);
+            }
+        }
+        _ => {}
+    }
+}
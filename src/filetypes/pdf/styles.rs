@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+
+use lopdf::{Dictionary, Object};
+
+use super::{
+    layout::{Block, Line, Region},
+    text::TextRun,
+};
+use crate::filetypes::entities::{escape, unescape};
+
+/// A line set at least this many times the size of the body text is a heading, of the level that
+/// goes with the first ratio it reaches
+const HEADING_RATIOS: [(u8, f64); 3] = [(1, 1.8), (2, 1.4), (3, 1.15)];
+
+/// Level of the headings that are only set apart by being bold lines on their own
+const BOLD_HEADING_LEVEL: u8 = 4;
+
+/// Font sizes are rounded to this step when looking for the size of the body text
+const FONT_SIZE_STEP: f64 = 0.5;
+
+/// Bit of the font descriptor `/Flags` marking italic fonts
+const ITALIC_FLAG: i64 = 1 << 6;
+
+/// Bit of the font descriptor `/Flags` asking for small glyphs to be drawn bolder
+const FORCE_BOLD_FLAG: i64 = 1 << 18;
+
+/// `/FontWeight` from which a font counts as bold
+const BOLD_FONT_WEIGHT: f64 = 600.0;
+
+/// Text rendering mode that strokes the outlines of the glyphs after filling them, which some
+/// producers use to fake bold
+const FILL_STROKE_RENDER_MODE: i64 = 2;
+
+/// A run set at most this fraction of the size of its line is a superscript if it is raised
+const SUPERSCRIPT_SIZE_RATIO: f64 = 0.85;
+
+/// How far above the baseline of its line a superscript is raised at least, in ems of the line
+const SUPERSCRIPT_RISE_RATIO: f64 = 0.2;
+
+/// Bold and italic text, and superscripts such as footnote references
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) struct Emphasis {
+    pub(super) bold: bool,
+    pub(super) italic: bool,
+    pub(super) superscript: bool,
+}
+
+impl Emphasis {
+    /// The emphasis of a font, from its name and font descriptor
+    pub(super) fn of_font(base_font: &str, descriptor: Option<&Dictionary>) -> Self {
+        // subsets are named `ABCDEF+Name`
+        let name = base_font
+            .split_once('+')
+            .map_or(base_font, |(_, name)| name)
+            .to_lowercase();
+        let flags = descriptor
+            .and_then(|descriptor| descriptor.get(b"Flags").and_then(Object::as_i64).ok())
+            .unwrap_or(0);
+        let weight = descriptor
+            .and_then(|descriptor| {
+                descriptor
+                    .get(b"FontWeight")
+                    .and_then(Object::as_float)
+                    .ok()
+            })
+            .map_or(0.0, f64::from);
+        let italic_angle = descriptor
+            .and_then(|descriptor| {
+                descriptor
+                    .get(b"ItalicAngle")
+                    .and_then(Object::as_float)
+                    .ok()
+            })
+            .map_or(0.0, f64::from);
+
+        Self {
+            bold: ["bold", "black", "heavy", "demi"]
+                .iter()
+                .any(|style| name.contains(style))
+                || flags & FORCE_BOLD_FLAG != 0
+                || weight >= BOLD_FONT_WEIGHT,
+            italic: ["italic", "oblique"]
+                .iter()
+                .any(|style| name.contains(style))
+                || flags & ITALIC_FLAG != 0
+                || italic_angle != 0.0,
+            superscript: false,
+        }
+    }
+
+    /// The emphasis of text set in a font of emphasis `self` with the text rendering mode
+    /// `render_mode`
+    pub(super) fn rendered(self, render_mode: i64) -> Self {
+        Self {
+            bold: self.bold || render_mode == FILL_STROKE_RENDER_MODE,
+            ..self
+        }
+    }
+
+    fn tags(self) -> impl DoubleEndedIterator<Item = &'static str> {
+        [
+            (self.bold, "b"),
+            (self.italic, "i"),
+            (self.superscript, "sup"),
+        ]
+        .into_iter()
+        .filter_map(|(set, tag)| set.then_some(tag))
+    }
+}
+
+/// Size of the body text: the size most of the text of `regions` is set in
+pub(super) fn body_font_size<'a>(regions: impl IntoIterator<Item = &'a Region>) -> Option<f64> {
+    let mut sizes: HashMap<i64, usize> = HashMap::new();
+    for region in regions {
+        let Region::Text(block) = region else {
+            continue;
+        };
+        for run in block.lines.iter().flat_map(|line| &line.runs) {
+            let size = (run.font_size / FONT_SIZE_STEP).round() as i64;
+            *sizes.entry(size).or_default() += run.text.chars().count();
+        }
+    }
+
+    sizes
+        .into_iter()
+        .max_by_key(|&(size, count)| (count, size))
+        .map(|(size, _)| size as f64 * FONT_SIZE_STEP)
+}
+
+/// The text of a block with its bold, italic and superscript runs and its headings marked up as
+/// HTML, which translation services keep in place. Each line is marked up on its own, so that its
+/// tags stay balanced when lines are dropped or moved while the paragraphs are put back together.
+pub(super) fn mark_up_block(block: &Block, body_font_size: f64) -> String {
+    let stands_alone = block.lines.len() == 1;
+
+    block
+        .lines
+        .iter()
+        .map(
+            |line| match heading_level_of_line(line, body_font_size, stands_alone) {
+                Some(level) => format!("<h{level}>{}</h{level}>", escape(&line.text(), false)),
+                None => mark_up_line(line),
+            },
+        )
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn heading_level_of_line(line: &Line, body_font_size: f64, stands_alone: bool) -> Option<u8> {
+    let ratio = line.font_size / body_font_size;
+    if let Some(&(level, _)) = HEADING_RATIOS
+        .iter()
+        .find(|&&(_, min_ratio)| ratio >= min_ratio)
+    {
+        return Some(level);
+    }
+
+    let all_bold = line.runs.iter().all(|run| run.emphasis.bold);
+    let text = line.text();
+    let ends_sentence = text.ends_with(['.', ',', ';', ':', '!', '?']);
+
+    (stands_alone && all_bold && !ends_sentence && text.chars().any(char::is_alphabetic))
+        .then_some(BOLD_HEADING_LEVEL)
+}
+
+/// The text of a line with its bold, italic and superscript runs marked up.
+pub(super) fn mark_up_line(line: &Line) -> String {
+    let mut current = Emphasis::default();
+    let mut text = line.join_runs(|text, run| {
+        let escaped = escape(&run.text, false);
+        let content = escaped.trim_start();
+        // leading spaces go before the opening tags, so that the tags hug the words
+        text.push_str(&escaped[..escaped.len() - content.len()]);
+        let emphasis = Emphasis {
+            superscript: is_superscript(run, line),
+            ..run.emphasis
+        };
+        switch_emphasis(text, &mut current, emphasis);
+        text.push_str(content);
+    });
+    switch_emphasis(&mut text, &mut current, Emphasis::default());
+
+    text
+}
+
+/// Whether `run` is set smaller than the rest of `line` and raised above its baseline.
+pub(super) fn is_superscript(run: &TextRun, line: &Line) -> bool {
+    let baseline = line
+        .runs
+        .iter()
+        .max_by(|a, b| a.font_size.total_cmp(&b.font_size))
+        .map_or(run.y, |largest| largest.y);
+
+    run.font_size <= line.font_size * SUPERSCRIPT_SIZE_RATIO
+        && run.y - baseline >= line.font_size * SUPERSCRIPT_RISE_RATIO
+        && !run.text.trim().is_empty()
+}
+
+/// Closes the tags of `current` and opens those of `emphasis` if they differ. Closing tags go
+/// before any trailing spaces.
+fn switch_emphasis(text: &mut String, current: &mut Emphasis, emphasis: Emphasis) {
+    if *current == emphasis {
+        return;
+    }
+
+    let content_len = text.trim_end().len();
+    let trailing = text.split_off(content_len);
+    for tag in current.tags().rev() {
+        text.push_str(&format!("</{tag}>"));
+    }
+    text.push_str(&trailing);
+    for tag in emphasis.tags() {
+        text.push_str(&format!("<{tag}>"));
+    }
+
+    *current = emphasis;
+}
+
+/// The heading level of a paragraph or line that was marked up as a heading.
+pub(super) fn heading_level(text: &str) -> Option<u8> {
+    match tokens(text).next()? {
+        Token::Open(Tag::Heading(level)) => Some(level),
+        _ => None,
+    }
+}
+
+/// Appends a line marked up as a heading to a heading of the same level, as one heading.
+pub(super) fn extend_heading(heading: &mut String, line: &str) {
+    let Some(level) = heading_level(heading) else {
+        return;
+    };
+    let (opening, closing) = (format!("<h{level}>"), format!("</h{level}>"));
+
+    if let Some(content) = heading.strip_suffix(&closing) {
+        heading.truncate(content.len());
+    }
+    heading.push('\n');
+    heading.push_str(line.strip_prefix(&opening).unwrap_or(line));
+}
+
+/// Merges the spans that were only split by a line break, such as `<b>a</b> <b>b</b>`.
+pub(super) fn join_spans(text: &str) -> String {
+    text.replace("</i></b> <b><i>", " ")
+        .replace("</b> <b>", " ")
+        .replace("</i> <i>", " ")
+}
+
+/// The text without its markup, as it reads.
+pub(super) fn plain_text(text: &str) -> String {
+    let mut plain = String::new();
+    for token in tokens(text) {
+        if let Token::Text(text) = token {
+            plain.push_str(&unescape(text));
+        }
+    }
+    plain
+}
+
+/// A word of a marked up paragraph, made up of pieces in different emphasis where it changes
+/// within the word
+#[derive(Clone, Debug)]
+pub(super) struct Word {
+    pub(super) pieces: Vec<(String, Emphasis)>,
+}
+
+impl Word {
+    pub(super) fn text(&self) -> String {
+        self.pieces.iter().map(|(text, _)| text.as_str()).collect()
+    }
+
+    /// Splits the word at the byte offset `index` of its text.
+    pub(super) fn split_at(&self, index: usize) -> (Word, Word) {
+        let (mut head, mut tail) = (vec![], vec![]);
+        let mut start = 0;
+        for (text, emphasis) in &self.pieces {
+            let split = index.saturating_sub(start).min(text.len());
+            if split > 0 {
+                head.push((text[..split].to_string(), *emphasis));
+            }
+            if split < text.len() {
+                tail.push((text[split..].to_string(), *emphasis));
+            }
+            start += text.len();
+        }
+
+        (Word { pieces: head }, Word { pieces: tail })
+    }
+
+    /// Adds `other` to the end of the word.
+    pub(super) fn append(&mut self, other: Word) {
+        for (text, emphasis) in other.pieces {
+            match self.pieces.last_mut() {
+                Some((previous, previous_emphasis)) if *previous_emphasis == emphasis => {
+                    previous.push_str(&text)
+                }
+                _ => self.pieces.push((text, emphasis)),
+            }
+        }
+    }
+}
+
+/// A paragraph of translated text, parsed from its markup
+pub(super) struct StyledParagraph {
+    pub(super) heading_level: Option<u8>,
+    pub(super) words: Vec<Word>,
+}
+
+/// Parses a marked up paragraph into words. Translators sometimes reorder or drop tags, so
+/// unbalanced tags are tolerated: emphasis lasts until its tag is closed or the paragraph ends.
+pub(super) fn parse_paragraph(text: &str) -> StyledParagraph {
+    let mut paragraph = StyledParagraph {
+        heading_level: None,
+        words: vec![],
+    };
+    let (mut bold, mut italic, mut superscript) = (0usize, 0usize, 0usize);
+    let mut word: Vec<(String, Emphasis)> = vec![];
+
+    for token in tokens(text) {
+        match token {
+            Token::Open(Tag::Bold) => bold += 1,
+            Token::Close(Tag::Bold) => bold = bold.saturating_sub(1),
+            Token::Open(Tag::Italic) => italic += 1,
+            Token::Close(Tag::Italic) => italic = italic.saturating_sub(1),
+            Token::Open(Tag::Superscript) => superscript += 1,
+            Token::Close(Tag::Superscript) => superscript = superscript.saturating_sub(1),
+            Token::Open(Tag::Heading(level)) => {
+                paragraph.heading_level = paragraph.heading_level.or(Some(level))
+            }
+            Token::Close(Tag::Heading(_)) => {}
+            Token::Text(text) => {
+                let emphasis = Emphasis {
+                    bold: bold > 0,
+                    italic: italic > 0,
+                    superscript: superscript > 0,
+                };
+                for c in unescape(text).chars() {
+                    if c.is_whitespace() {
+                        if !word.is_empty() {
+                            paragraph.words.push(Word {
+                                pieces: std::mem::take(&mut word),
+                            });
+                        }
+                        continue;
+                    }
+                    match word.last_mut() {
+                        Some((piece, piece_emphasis)) if *piece_emphasis == emphasis => {
+                            piece.push(c)
+                        }
+                        _ => word.push((c.to_string(), emphasis)),
+                    }
+                }
+            }
+        }
+    }
+    if !word.is_empty() {
+        paragraph.words.push(Word { pieces: word });
+    }
+
+    paragraph
+}
+
+#[derive(Clone, Copy)]
+enum Tag {
+    Bold,
+    Italic,
+    Superscript,
+    Heading(u8),
+}
+
+impl Tag {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "b" | "strong" => Some(Tag::Bold),
+            "i" | "em" => Some(Tag::Italic),
+            "sup" => Some(Tag::Superscript),
+            _ => {
+                let level = name.strip_prefix('h')?.parse().ok()?;
+                (1..=6).contains(&level).then_some(Tag::Heading(level))
+            }
+        }
+    }
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    Open(Tag),
+    Close(Tag),
+}
+
+/// Splits marked up text into text and the tags this module uses. Anything else that looks like a
+/// tag is kept as text.
+fn tokens(text: &str) -> impl Iterator<Item = Token<'_>> {
+    let mut rest = text;
+
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+
+        if rest.starts_with('<') {
+            if let Some(end) = rest.find('>') {
+                let inner = rest[1..end].trim().to_lowercase();
+                let (closing, name) = match inner.strip_prefix('/') {
+                    Some(name) => (true, name.trim()),
+                    None => (false, inner.as_str()),
+                };
+                if let Some(tag) = Tag::parse(name) {
+                    rest = &rest[end + 1..];
+                    return Some(if closing {
+                        Token::Close(tag)
+                    } else {
+                        Token::Open(tag)
+                    });
+                }
+            }
+        }
+
+        // up to the next `<` that may start a tag, but at least one character
+        let first_len = rest.chars().next().map_or(0, char::len_utf8);
+        let end = rest[first_len..]
+            .find('<')
+            .map_or(rest.len(), |index| index + first_len);
+        let (text, remaining) = rest.split_at(end);
+        rest = remaining;
+        Some(Token::Text(text))
+    })
+}
@@ -0,0 +1,449 @@
+use lopdf::{content::Operation, Object};
+
+use super::{
+    begin_text_section, create_graphics_page, encode_win_ansi, end_text_section,
+    layout::{build_lines, Line, Rect},
+    structure,
+    text::{Ruling, TextRun},
+    wrap_text, PagesState, LEFT_MARGIN,
+};
+use crate::options::PdfOptions;
+
+/// Rules whose ends are this close together are taken to touch, and rules this close to each
+/// other mark the same cell boundary
+const RULING_TOLERANCE: f64 = 2.0;
+
+/// A table without rules needs at least this many consecutive rows with aligned cells
+const MIN_ALIGNED_ROWS: usize = 3;
+
+/// Rows further apart than this many ems don't belong to the same table
+const MAX_ROW_GAP_RATIO: f64 = 2.0;
+
+/// Aligned rows with more words than this per cell on average are running text set in columns
+const MAX_MEAN_CELL_WORDS: usize = 4;
+
+/// Font size of the text in reflowed tables
+pub(super) const TABLE_FONT_SIZE: f64 = 9.0;
+
+/// Space between the rules of a reflowed table and its text
+const CELL_PADDING: f64 = 3.0;
+
+#[derive(Clone, Debug)]
+pub(super) struct Table {
+    pub(super) bbox: Rect,
+    /// Text of each cell, row by row from the top
+    pub(super) rows: Vec<Vec<String>>,
+    /// Width of each column on the source page
+    pub(super) column_widths: Vec<f64>,
+}
+
+impl Table {
+    /// The text of the cells that have any, in reading order.
+    pub(super) fn cells(&self) -> impl Iterator<Item = &String> {
+        self.rows.iter().flatten().filter(|cell| !cell.is_empty())
+    }
+
+    /// Replaces the text of the cells returned by [`Table::cells`] with the next items of `cells`.
+    pub(super) fn set_cells(&mut self, cells: &mut impl Iterator<Item = String>) {
+        for cell in self.rows.iter_mut().flatten() {
+            if cell.is_empty() {
+                continue;
+            }
+            if let Some(text) = cells.next() {
+                *cell = text;
+            }
+        }
+    }
+}
+
+/// Finds grids of rules with at least two rows and two columns, and takes the runs inside them
+/// out of `runs` as the cells of a table.
+pub(super) fn detect_ruled_tables(
+    mut runs: Vec<TextRun>,
+    rulings: &[Ruling],
+) -> (Vec<Table>, Vec<TextRun>) {
+    let mut tables = vec![];
+
+    for group in connected_rulings(rulings) {
+        let columns = boundaries(
+            group
+                .iter()
+                .filter(|ruling| !ruling.is_horizontal())
+                .map(|ruling| (ruling.x0 + ruling.x1) / 2.0),
+        );
+        let mut rows = boundaries(
+            group
+                .iter()
+                .filter(|ruling| ruling.is_horizontal())
+                .map(|ruling| (ruling.y0 + ruling.y1) / 2.0),
+        );
+        rows.reverse();
+
+        if columns.len() < 3 || rows.len() < 3 {
+            continue;
+        }
+
+        let bbox = Rect {
+            x0: columns[0],
+            y0: rows[rows.len() - 1],
+            x1: columns[columns.len() - 1],
+            y1: rows[0],
+        };
+        let (inside, outside): (Vec<TextRun>, Vec<TextRun>) = runs.into_iter().partition(|run| {
+            let (x, y) = run_center(run);
+            x >= bbox.x0 && x <= bbox.x1 && y >= bbox.y0 && y <= bbox.y1
+        });
+        runs = outside;
+
+        if inside.is_empty() {
+            continue;
+        }
+
+        let mut cells: Vec<Vec<Vec<TextRun>>> =
+            vec![vec![vec![]; columns.len() - 1]; rows.len() - 1];
+        for run in inside {
+            let (x, y) = run_center(&run);
+            let column = columns[1..]
+                .iter()
+                .position(|&boundary| x < boundary)
+                .unwrap_or(columns.len() - 2);
+            let row = rows[1..]
+                .iter()
+                .position(|&boundary| y > boundary)
+                .unwrap_or(rows.len() - 2);
+            cells[row][column].push(run);
+        }
+
+        tables.push(Table {
+            bbox,
+            rows: cells
+                .into_iter()
+                .map(|row| row.into_iter().map(cell_text).collect())
+                .collect(),
+            column_widths: columns.windows(2).map(|pair| pair[1] - pair[0]).collect(),
+        });
+    }
+
+    (tables, runs)
+}
+
+/// Groups rules that cross or touch each other.
+fn connected_rulings(rulings: &[Ruling]) -> Vec<Vec<Ruling>> {
+    let touches = |a: &Ruling, b: &Ruling| {
+        a.x0 - RULING_TOLERANCE <= b.x1
+            && b.x0 - RULING_TOLERANCE <= a.x1
+            && a.y0 - RULING_TOLERANCE <= b.y1
+            && b.y0 - RULING_TOLERANCE <= a.y1
+    };
+
+    let mut parents: Vec<usize> = (0..rulings.len()).collect();
+    fn root(parents: &mut [usize], mut index: usize) -> usize {
+        while parents[index] != index {
+            parents[index] = parents[parents[index]];
+            index = parents[index];
+        }
+        index
+    }
+
+    for a in 0..rulings.len() {
+        for b in a + 1..rulings.len() {
+            if touches(&rulings[a], &rulings[b]) {
+                let (root_a, root_b) = (root(&mut parents, a), root(&mut parents, b));
+                parents[root_a] = root_b;
+            }
+        }
+    }
+
+    let mut groups: Vec<(usize, Vec<Ruling>)> = vec![];
+    for (index, ruling) in rulings.iter().enumerate() {
+        let group_root = root(&mut parents, index);
+        match groups.iter_mut().find(|(root, _)| *root == group_root) {
+            Some((_, group)) => group.push(*ruling),
+            None => groups.push((group_root, vec![*ruling])),
+        }
+    }
+
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// Sorts `positions` and merges those within [`RULING_TOLERANCE`] of each other.
+fn boundaries(positions: impl Iterator<Item = f64>) -> Vec<f64> {
+    let mut positions: Vec<f64> = positions.collect();
+    positions.sort_by(f64::total_cmp);
+
+    let mut merged: Vec<f64> = vec![];
+    for position in positions {
+        match merged.last() {
+            Some(&last) if position - last <= RULING_TOLERANCE => {}
+            _ => merged.push(position),
+        }
+    }
+
+    merged
+}
+
+fn run_center(run: &TextRun) -> (f64, f64) {
+    (run.x + run.width / 2.0, run.y + run.font_size * 0.25)
+}
+
+fn cell_text(runs: Vec<TextRun>) -> String {
+    build_lines(runs)
+        .iter()
+        .map(Line::text)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Finds runs of consecutive rows that are split into cells by column gaps, with the cells
+/// starting at the same positions on every row, and takes their lines out of `lines` as tables.
+pub(super) fn detect_aligned_tables(lines: Vec<Line>) -> (Vec<Table>, Vec<Line>) {
+    let mut tables = vec![];
+    let mut remaining = vec![];
+    let mut candidate: Vec<Vec<Line>> = vec![];
+
+    for row in group_rows(lines) {
+        let continues = candidate.last().is_some_and(|previous: &Vec<Line>| {
+            let gap = previous[0].bbox.y0 - row[0].bbox.y1;
+            gap <= row[0].font_size * MAX_ROW_GAP_RATIO
+        });
+
+        if row.len() < 2 || !continues {
+            match aligned_table(&candidate) {
+                Some(table) => tables.push(table),
+                None => remaining.extend(candidate.drain(..).flatten()),
+            }
+            candidate.clear();
+        }
+
+        if row.len() < 2 {
+            remaining.extend(row);
+        } else {
+            candidate.push(row);
+        }
+    }
+    match aligned_table(&candidate) {
+        Some(table) => tables.push(table),
+        None => remaining.extend(candidate.into_iter().flatten()),
+    }
+
+    (tables, remaining)
+}
+
+/// Groups lines that share a baseline, sorting each row left to right.
+fn group_rows(lines: Vec<Line>) -> Vec<Vec<Line>> {
+    let mut rows: Vec<Vec<Line>> = vec![];
+
+    for line in lines {
+        match rows.last_mut() {
+            Some(row)
+                if (row[0].bbox.y0 - line.bbox.y0).abs()
+                    < row[0].font_size.min(line.font_size) * 0.5 =>
+            {
+                row.push(line)
+            }
+            _ => rows.push(vec![line]),
+        }
+    }
+
+    for row in &mut rows {
+        row.sort_by(|a, b| a.bbox.x0.total_cmp(&b.bbox.x0));
+    }
+
+    rows
+}
+
+fn aligned_table(rows: &[Vec<Line>]) -> Option<Table> {
+    if rows.len() < MIN_ALIGNED_ROWS {
+        return None;
+    }
+
+    let em = rows[0][0].font_size.max(1.0);
+    let mut starts: Vec<(f64, usize)> = vec![];
+    for line in rows.iter().flatten() {
+        match starts
+            .iter_mut()
+            .find(|(start, _)| (start - line.bbox.x0).abs() <= em)
+        {
+            Some((_, count)) => *count += 1,
+            None => starts.push((line.bbox.x0, 1)),
+        }
+    }
+
+    let mut columns: Vec<f64> = starts
+        .into_iter()
+        .filter(|&(_, count)| count * 2 >= rows.len())
+        .map(|(start, _)| start)
+        .collect();
+    columns.sort_by(f64::total_cmp);
+
+    if columns.len() < 2 {
+        return None;
+    }
+
+    let mut cells = vec![];
+    let mut word_counts = vec![];
+    for row in rows {
+        let mut texts = vec![String::new(); columns.len()];
+        for line in row {
+            let column = columns
+                .iter()
+                .rposition(|&start| start <= line.bbox.x0 + em)
+                .unwrap_or(0);
+            let text = line.text();
+            word_counts.push(text.split_whitespace().count());
+
+            if !texts[column].is_empty() {
+                texts[column].push(' ');
+            }
+            texts[column].push_str(&text);
+        }
+        cells.push(texts);
+    }
+
+    if word_counts.iter().sum::<usize>() > MAX_MEAN_CELL_WORDS * word_counts.len() {
+        return None;
+    }
+
+    let bbox = rows
+        .iter()
+        .flatten()
+        .map(|line| line.bbox)
+        .reduce(|a, b| a.union(&b))?;
+    let mut column_widths: Vec<f64> = columns.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    // the other columns include the gutter after them
+    column_widths.push(bbox.x1 - columns[columns.len() - 1] + em * 2.0);
+
+    Some(Table {
+        bbox,
+        rows: cells,
+        column_widths,
+    })
+}
+
+/// Draws `table` as a grid across the full text width, with the columns in the proportions they
+/// had on the source page, starting new pages between rows as needed.
+pub(super) fn format_table(options: &PdfOptions, pages_state: &mut PagesState, table: &Table) {
+    let total_width: f64 = table.column_widths.iter().sum();
+    if total_width <= 0.0 {
+        return;
+    }
+
+    let widths: Vec<f64> = table
+        .column_widths
+        .iter()
+        .map(|width| width / total_width * options.max_width)
+        .collect();
+    let line_height = TABLE_FONT_SIZE * 1.2;
+
+    end_text_section(pages_state);
+    let table_element = pages_state.structure.add_element(None, "Table");
+
+    for row in &table.rows {
+        let cells: Vec<Vec<String>> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| wrap_text(cell, TABLE_FONT_SIZE, width - 2.0 * CELL_PADDING))
+            .collect();
+        let line_count = cells.iter().map(Vec::len).max().unwrap_or(0).max(1);
+        let height = line_count as f64 * line_height + 2.0 * CELL_PADDING;
+
+        if pages_state.y_pos - height < pages_state.bottom(options) {
+            create_graphics_page(pages_state, options.max_y_pos);
+        }
+
+        let row_element = pages_state.structure.add_element(Some(table_element), "TR");
+        let page_index = pages_state.pages.len() - 1;
+        let cell_marks: Vec<Operation> = cells
+            .iter()
+            .map(|_| {
+                let cell_element = pages_state.structure.add_element(Some(row_element), "TD");
+                pages_state
+                    .structure
+                    .begin_marked_content(cell_element, page_index)
+            })
+            .collect();
+
+        if let Some(page) = pages_state.pages.last_mut() {
+            page.operations.extend(row_operations(
+                &cells,
+                &widths,
+                pages_state.y_pos,
+                height,
+                cell_marks,
+            ));
+        }
+        pages_state.y_pos -= height;
+    }
+
+    pages_state.y_pos -= options.paragraph_spacing;
+    if pages_state.y_pos < pages_state.bottom(options) {
+        create_graphics_page(pages_state, options.max_y_pos);
+    }
+    begin_text_section(pages_state);
+}
+
+/// Draws the borders and text of a row of cells. The text of each cell is marked with the
+/// operation in `cell_marks` that begins its marked content, and the borders as an artifact.
+fn row_operations(
+    cells: &[Vec<String>],
+    widths: &[f64],
+    top: f64,
+    height: f64,
+    cell_marks: Vec<Operation>,
+) -> Vec<Operation> {
+    let mut borders = vec![
+        Operation::new("q", vec![]),
+        Operation::new("w", vec![0.5.into()]),
+    ];
+
+    let mut x = LEFT_MARGIN;
+    for width in widths {
+        borders.push(Operation::new(
+            "re",
+            vec![
+                x.into(),
+                (top - height).into(),
+                (*width).into(),
+                height.into(),
+            ],
+        ));
+        x += width;
+    }
+    borders.push(Operation::new("S", vec![]));
+    borders.push(Operation::new("Q", vec![]));
+
+    let mut operations = structure::artifact(borders);
+    operations.push(Operation::new("BT", vec![]));
+    operations.push(Operation::new(
+        "Tf",
+        vec!["F1".into(), TABLE_FONT_SIZE.into()],
+    ));
+
+    let mut x = LEFT_MARGIN;
+    for ((lines, width), mark) in cells.iter().zip(widths).zip(cell_marks) {
+        operations.push(mark);
+        for (index, line) in lines.iter().enumerate() {
+            let baseline = top - CELL_PADDING - TABLE_FONT_SIZE * (1.0 + 1.2 * index as f64);
+            operations.push(Operation::new(
+                "Tm",
+                vec![
+                    1.into(),
+                    0.into(),
+                    0.into(),
+                    1.into(),
+                    (x + CELL_PADDING).into(),
+                    baseline.into(),
+                ],
+            ));
+            operations.push(Operation::new(
+                "Tj",
+                vec![Object::string_literal(encode_win_ansi(line))],
+            ));
+        }
+        operations.push(structure::end_marked_content());
+        x += width;
+    }
+    operations.push(Operation::new("ET", vec![]));
+
+    operations
+}
@@ -0,0 +1,741 @@
+use std::collections::HashMap;
+
+use eyre::Result;
+use lopdf::{
+    content::{Content, Operation},
+    Dictionary, Document, Encoding, Object, ObjectId, Stream,
+};
+
+use super::{layout::Rect, styles::Emphasis};
+
+/// Form XObjects nested deeper than this are not searched for text and images
+pub(super) const MAX_FORM_DEPTH: usize = 8;
+
+/// Glyph width used when a font doesn't say, in thousandths of an em
+const DEFAULT_GLYPH_WIDTH: f64 = 500.0;
+
+/// Path segments and rectangles at most this thick are drawn lines rather than shapes
+const MAX_RULING_THICKNESS: f64 = 2.0;
+
+/// Path segments shorter than this are not table rules
+const MIN_RULING_LENGTH: f64 = 5.0;
+
+/// A PDF transformation matrix `[a b c d e f]`
+pub(super) type Matrix = [f64; 6];
+
+pub(super) const IDENTITY: Matrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+/// Text shown by a single text-showing operator, positioned in the default user space of the page
+#[derive(Clone, Debug)]
+pub(super) struct TextRun {
+    pub(super) text: String,
+    /// Start of the baseline
+    pub(super) x: f64,
+    pub(super) y: f64,
+    pub(super) width: f64,
+    pub(super) font_size: f64,
+    pub(super) emphasis: Emphasis,
+}
+
+impl TextRun {
+    pub(super) fn end_x(&self) -> f64 {
+        self.x + self.width
+    }
+}
+
+/// A horizontal or vertical line drawn on the page, such as the rules of a table
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Ruling {
+    pub(super) x0: f64,
+    pub(super) y0: f64,
+    pub(super) x1: f64,
+    pub(super) y1: f64,
+}
+
+impl Ruling {
+    /// Returns the segment from `(x0, y0)` to `(x1, y1)` if it is long and close enough to
+    /// horizontal or vertical to be a rule.
+    fn new(x0: f64, y0: f64, x1: f64, y1: f64) -> Option<Self> {
+        let ruling = Self {
+            x0: x0.min(x1),
+            y0: y0.min(y1),
+            x1: x0.max(x1),
+            y1: y0.max(y1),
+        };
+        let (width, height) = (ruling.x1 - ruling.x0, ruling.y1 - ruling.y0);
+        let straight = width.min(height) <= MAX_RULING_THICKNESS;
+
+        (straight && width.max(height) >= MIN_RULING_LENGTH).then_some(ruling)
+    }
+
+    pub(super) fn is_horizontal(&self) -> bool {
+        self.x1 - self.x0 > self.y1 - self.y0
+    }
+}
+
+/// An image XObject drawn on the page
+#[derive(Clone, Debug)]
+pub(super) struct ImagePlacement {
+    pub(super) id: ObjectId,
+    /// The transformation matrix in effect when the image was drawn, which maps the unit square
+    /// onto the page
+    pub(super) matrix: Matrix,
+    /// Id of the marked content the image was drawn in, which ties it to the structure tree of
+    /// tagged documents
+    pub(super) mcid: Option<i64>,
+    /// Description of the image for readers who can't see it
+    pub(super) alt: Option<String>,
+}
+
+impl ImagePlacement {
+    pub(super) fn bbox(&self) -> Rect {
+        let corners = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)]
+            .map(|(x, y)| apply(&self.matrix, x, y));
+
+        Rect {
+            x0: corners
+                .iter()
+                .map(|&(x, _)| x)
+                .fold(f64::INFINITY, f64::min),
+            y0: corners
+                .iter()
+                .map(|&(_, y)| y)
+                .fold(f64::INFINITY, f64::min),
+            x1: corners
+                .iter()
+                .map(|&(x, _)| x)
+                .fold(f64::NEG_INFINITY, f64::max),
+            y1: corners
+                .iter()
+                .map(|&(_, y)| y)
+                .fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+/// Everything on a page the layout analysis looks at
+#[derive(Default)]
+pub(super) struct PageContent {
+    pub(super) runs: Vec<TextRun>,
+    pub(super) rulings: Vec<Ruling>,
+    pub(super) images: Vec<ImagePlacement>,
+}
+
+struct FontInfo<'a> {
+    encoding: Option<Encoding<'a>>,
+    base_font: String,
+    /// Codes are two bytes long, as in composite (`Type0`) fonts
+    two_byte: bool,
+    first_char: i64,
+    widths: Vec<f64>,
+    /// Widths of composite fonts, from the `W` array of the descendant font
+    cid_widths: HashMap<u32, f64>,
+    default_width: f64,
+    emphasis: Emphasis,
+}
+
+impl<'a> FontInfo<'a> {
+    fn load(doc: &'a Document, font: &'a Dictionary) -> Self {
+        let number = |obj: &Object| obj.as_float().map(f64::from).ok();
+        let two_byte = font.get(b"Subtype").and_then(Object::as_name).ok() == Some(b"Type0");
+
+        let mut info = Self {
+            encoding: font.get_font_encoding(doc).ok(),
+            base_font: font
+                .get(b"BaseFont")
+                .and_then(Object::as_name)
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .unwrap_or_default(),
+            two_byte,
+            first_char: font.get(b"FirstChar").and_then(Object::as_i64).unwrap_or(0),
+            widths: vec![],
+            cid_widths: HashMap::new(),
+            default_width: DEFAULT_GLYPH_WIDTH,
+            emphasis: Emphasis::default(),
+        };
+        let descriptor = |font: &'a Dictionary| {
+            font.get_deref(b"FontDescriptor", doc)
+                .and_then(Object::as_dict)
+                .ok()
+        };
+
+        if two_byte {
+            let descendant = font
+                .get_deref(b"DescendantFonts", doc)
+                .and_then(Object::as_array)
+                .ok()
+                .and_then(|fonts| fonts.first())
+                .and_then(|font| doc.dereference(font).ok())
+                .and_then(|(_, font)| font.as_dict().ok());
+
+            if let Some(descendant) = descendant {
+                info.emphasis = Emphasis::of_font(&info.base_font, descriptor(descendant));
+                info.default_width = descendant
+                    .get(b"DW")
+                    .ok()
+                    .and_then(number)
+                    .unwrap_or(1000.0);
+                if let Ok(widths) = descendant.get_deref(b"W", doc).and_then(Object::as_array) {
+                    info.cid_widths = parse_cid_widths(doc, widths);
+                }
+            }
+        } else {
+            info.emphasis = Emphasis::of_font(&info.base_font, descriptor(font));
+            if let Ok(widths) = font.get_deref(b"Widths", doc).and_then(Object::as_array) {
+                info.widths = widths
+                    .iter()
+                    .map(|width| {
+                        doc.dereference(width)
+                            .ok()
+                            .and_then(|(_, width)| number(width))
+                            .unwrap_or(0.0)
+                    })
+                    .collect();
+            }
+            if let Some(missing) = font
+                .get_deref(b"FontDescriptor", doc)
+                .and_then(Object::as_dict)
+                .and_then(|descriptor| descriptor.get(b"MissingWidth"))
+                .ok()
+                .and_then(number)
+            {
+                info.default_width = missing;
+            } else if info.base_font.contains("Courier") {
+                info.default_width = 600.0;
+            }
+        }
+
+        info
+    }
+
+    fn decode(&self, bytes: &[u8]) -> String {
+        self.encoding
+            .as_ref()
+            .and_then(|encoding| Document::decode_text(encoding, bytes).ok())
+            .unwrap_or_default()
+    }
+
+    fn codes<'b>(&self, bytes: &'b [u8]) -> impl Iterator<Item = u32> + 'b {
+        let step = if self.two_byte { 2 } else { 1 };
+        bytes
+            .chunks(step)
+            .map(|code| code.iter().fold(0, |acc, &byte| (acc << 8) | byte as u32))
+    }
+
+    /// Width of the glyph for `code`, in thousandths of an em
+    fn glyph_width(&self, code: u32) -> f64 {
+        if self.two_byte {
+            return self
+                .cid_widths
+                .get(&code)
+                .copied()
+                .unwrap_or(self.default_width);
+        }
+
+        usize::try_from(code as i64 - self.first_char)
+            .ok()
+            .and_then(|index| self.widths.get(index))
+            .copied()
+            .filter(|&width| width > 0.0)
+            .unwrap_or(self.default_width)
+    }
+}
+
+fn parse_cid_widths(doc: &Document, widths: &[Object]) -> HashMap<u32, f64> {
+    let number = |obj: &Object| {
+        doc.dereference(obj)
+            .ok()
+            .and_then(|(_, obj)| obj.as_float().ok())
+            .map(f64::from)
+    };
+    let mut cid_widths = HashMap::new();
+    let mut index = 0;
+
+    while index + 1 < widths.len() {
+        let Some(first) = number(&widths[index]) else {
+            break;
+        };
+        let first = first as u32;
+
+        match doc.dereference(&widths[index + 1]).map(|(_, obj)| obj) {
+            // `c [w1 w2 ...]`
+            Ok(Object::Array(list)) => {
+                for (offset, width) in list.iter().enumerate() {
+                    if let Some(width) = number(width) {
+                        cid_widths.insert(first + offset as u32, width);
+                    }
+                }
+                index += 2;
+            }
+            // `c_first c_last w`
+            _ => {
+                let (Some(last), Some(width)) = (
+                    number(&widths[index + 1]),
+                    widths.get(index + 2).and_then(number),
+                ) else {
+                    break;
+                };
+                for cid in first..=(last as u32).min(first + 0xffff) {
+                    cid_widths.insert(cid, width);
+                }
+                index += 3;
+            }
+        }
+    }
+
+    cid_widths
+}
+
+#[derive(Clone)]
+struct GraphicsState {
+    ctm: Matrix,
+    char_spacing: f64,
+    word_spacing: f64,
+    horizontal_scaling: f64,
+    leading: f64,
+    rise: f64,
+    render_mode: i64,
+    font: Option<Vec<u8>>,
+    font_size: f64,
+}
+
+impl GraphicsState {
+    fn new(ctm: Matrix) -> Self {
+        Self {
+            ctm,
+            char_spacing: 0.0,
+            word_spacing: 0.0,
+            horizontal_scaling: 1.0,
+            leading: 0.0,
+            rise: 0.0,
+            render_mode: 0,
+            font: None,
+            font_size: 0.0,
+        }
+    }
+}
+
+/// Walks a page's content streams, including the form XObjects they draw, and collects every
+/// piece of text together with its position, size and font, every straight line and every image
+/// drawn.
+pub(super) fn extract_content(doc: &Document, page_id: ObjectId) -> Result<PageContent> {
+    let content = doc.get_and_decode_page_content(page_id)?;
+    let resources = super::page_resources(doc, page_id);
+    let mut page = PageContent::default();
+
+    interpret(doc, &content, &resources, IDENTITY, 0, &mut page);
+
+    Ok(page)
+}
+
+fn load_fonts<'a>(doc: &'a Document, resources: &'a Dictionary) -> HashMap<Vec<u8>, FontInfo<'a>> {
+    let Ok(fonts) = resources.get_deref(b"Font", doc).and_then(Object::as_dict) else {
+        return HashMap::new();
+    };
+
+    fonts
+        .iter()
+        .filter_map(|(name, font)| {
+            let (_, font) = doc.dereference(font).ok()?;
+            Some((name.clone(), FontInfo::load(doc, font.as_dict().ok()?)))
+        })
+        .collect()
+}
+
+fn interpret(
+    doc: &Document,
+    content: &Content,
+    resources: &Dictionary,
+    ctm: Matrix,
+    depth: usize,
+    page: &mut PageContent,
+) {
+    let fonts = load_fonts(doc, resources);
+    let mut state = GraphicsState::new(ctm);
+    let mut stack = vec![];
+    let mut text_matrix = IDENTITY;
+    let mut line_matrix = IDENTITY;
+    let mut path = Path::default();
+    // the marked content id of each marked-content sequence the current operation is in
+    let mut marked_content: Vec<Option<i64>> = vec![];
+
+    for operation in &content.operations {
+        let operands: Vec<f64> = operation
+            .operands
+            .iter()
+            .filter_map(|operand| operand.as_float().ok().map(f64::from))
+            .collect();
+
+        match (operation.operator.as_str(), operands.as_slice()) {
+            ("q", _) => stack.push(state.clone()),
+            ("Q", _) => {
+                if let Some(saved) = stack.pop() {
+                    state = saved;
+                }
+            }
+            ("cm", &[a, b, c, d, e, f]) => state.ctm = multiply(&[a, b, c, d, e, f], &state.ctm),
+            ("BT", _) => {
+                text_matrix = IDENTITY;
+                line_matrix = IDENTITY;
+            }
+            ("Tc", &[spacing]) => state.char_spacing = spacing,
+            ("Tw", &[spacing]) => state.word_spacing = spacing,
+            ("Tz", &[scaling]) => state.horizontal_scaling = scaling / 100.0,
+            ("TL", &[leading]) => state.leading = leading,
+            ("Ts", &[rise]) => state.rise = rise,
+            ("Tr", &[mode]) => state.render_mode = mode as i64,
+            ("Tf", &[size]) => {
+                state.font = operation
+                    .operands
+                    .first()
+                    .and_then(|name| name.as_name().ok())
+                    .map(<[u8]>::to_vec);
+                state.font_size = size;
+            }
+            ("Td", &[tx, ty]) => {
+                line_matrix = multiply(&[1.0, 0.0, 0.0, 1.0, tx, ty], &line_matrix);
+                text_matrix = line_matrix;
+            }
+            ("TD", &[tx, ty]) => {
+                state.leading = -ty;
+                line_matrix = multiply(&[1.0, 0.0, 0.0, 1.0, tx, ty], &line_matrix);
+                text_matrix = line_matrix;
+            }
+            ("Tm", &[a, b, c, d, e, f]) => {
+                line_matrix = [a, b, c, d, e, f];
+                text_matrix = line_matrix;
+            }
+            ("T*", _) => {
+                line_matrix = multiply(&[1.0, 0.0, 0.0, 1.0, 0.0, -state.leading], &line_matrix);
+                text_matrix = line_matrix;
+            }
+            ("Tj" | "TJ" | "'" | "\"", _) => {
+                if operation.operator == "\"" {
+                    if let [word_spacing, char_spacing, ..] = operands.as_slice() {
+                        state.word_spacing = *word_spacing;
+                        state.char_spacing = *char_spacing;
+                    }
+                }
+                if operation.operator == "'" || operation.operator == "\"" {
+                    line_matrix =
+                        multiply(&[1.0, 0.0, 0.0, 1.0, 0.0, -state.leading], &line_matrix);
+                    text_matrix = line_matrix;
+                }
+
+                let Some(font) = state.font.as_ref().and_then(|name| fonts.get(name)) else {
+                    continue;
+                };
+                let shown = operation.operands.last().map(std::slice::from_ref);
+                if let Some(run) = show_text(&state, font, &mut text_matrix, shown.unwrap_or(&[])) {
+                    page.runs.push(run);
+                }
+            }
+            ("m", &[x, y]) => path.move_to(apply(&state.ctm, x, y)),
+            ("l", &[x, y]) => path.line_to(apply(&state.ctm, x, y)),
+            ("re", &[x, y, width, height]) => {
+                let corners = [
+                    (x, y),
+                    (x + width, y),
+                    (x + width, y + height),
+                    (x, y + height),
+                ]
+                .map(|(x, y)| apply(&state.ctm, x, y));
+                path.rectangle(corners);
+            }
+            ("h", _) => path.close(),
+            ("S" | "s" | "f" | "F" | "f*" | "B" | "B*" | "b" | "b*", _) => {
+                page.rulings.extend(std::mem::take(&mut path).rulings());
+            }
+            ("n", _) => path = Path::default(),
+            ("BMC", _) => marked_content.push(None),
+            ("BDC", _) => marked_content.push(marked_content_id(doc, resources, operation)),
+            ("EMC", _) => {
+                marked_content.pop();
+            }
+            ("Do", _) if depth < MAX_FORM_DEPTH => {
+                let Some(name) = operation
+                    .operands
+                    .first()
+                    .and_then(|name| name.as_name().ok())
+                else {
+                    continue;
+                };
+                // marked content ids inside form XObjects belong to the form, not to the page
+                let mcid = match depth {
+                    0 => marked_content.iter().rev().find_map(|&mcid| mcid),
+                    _ => None,
+                };
+                draw_xobject(doc, resources, name, &state.ctm, mcid, depth, page);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The path under construction, in default user space
+#[derive(Default)]
+struct Path {
+    segments: Vec<((f64, f64), (f64, f64))>,
+    /// Thin rectangles, which are often used instead of stroked lines
+    bars: Vec<[(f64, f64); 4]>,
+    current: Option<(f64, f64)>,
+    start: Option<(f64, f64)>,
+}
+
+impl Path {
+    fn move_to(&mut self, point: (f64, f64)) {
+        self.current = Some(point);
+        self.start = Some(point);
+    }
+
+    fn line_to(&mut self, point: (f64, f64)) {
+        if let Some(current) = self.current {
+            self.segments.push((current, point));
+        }
+        self.current = Some(point);
+    }
+
+    fn close(&mut self) {
+        if let (Some(current), Some(start)) = (self.current, self.start) {
+            self.segments.push((current, start));
+            self.current = Some(start);
+        }
+    }
+
+    fn rectangle(&mut self, corners: [(f64, f64); 4]) {
+        self.bars.push(corners);
+        self.current = Some(corners[0]);
+        self.start = Some(corners[0]);
+    }
+
+    fn rulings(self) -> Vec<Ruling> {
+        let mut rulings: Vec<Ruling> = self
+            .segments
+            .into_iter()
+            .filter_map(|((x0, y0), (x1, y1))| Ruling::new(x0, y0, x1, y1))
+            .collect();
+
+        for corners in self.bars {
+            let xs = corners.map(|(x, _)| x);
+            let ys = corners.map(|(_, y)| y);
+            let (x0, x1) = (
+                xs.into_iter().fold(f64::INFINITY, f64::min),
+                xs.into_iter().fold(f64::NEG_INFINITY, f64::max),
+            );
+            let (y0, y1) = (
+                ys.into_iter().fold(f64::INFINITY, f64::min),
+                ys.into_iter().fold(f64::NEG_INFINITY, f64::max),
+            );
+
+            if let Some(ruling) = Ruling::new(x0, y0, x1, y1) {
+                // a thin bar is a single rule
+                rulings.push(ruling);
+            } else {
+                // the edges of a larger box, such as a cell border
+                rulings.extend(
+                    [
+                        (x0, y0, x1, y0),
+                        (x0, y1, x1, y1),
+                        (x0, y0, x0, y1),
+                        (x1, y0, x1, y1),
+                    ]
+                    .into_iter()
+                    .filter_map(|(x0, y0, x1, y1)| Ruling::new(x0, y0, x1, y1)),
+                );
+            }
+        }
+
+        rulings
+    }
+}
+
+/// Lays out the strings of a text-showing operation, advancing the text matrix past them.
+fn show_text(
+    state: &GraphicsState,
+    font: &FontInfo,
+    text_matrix: &mut Matrix,
+    operands: &[Object],
+) -> Option<TextRun> {
+    let start = multiply(text_matrix, &state.ctm);
+    let mut text = String::new();
+    let mut advance = 0.0;
+
+    for operand in operands {
+        match operand {
+            Object::String(bytes, _) => show_string(state, font, bytes, &mut text, &mut advance),
+            Object::Array(items) => {
+                for item in items {
+                    match item {
+                        Object::String(bytes, _) => {
+                            show_string(state, font, bytes, &mut text, &mut advance)
+                        }
+                        item => {
+                            let Ok(adjustment) = item.as_float() else {
+                                continue;
+                            };
+                            // large negative adjustments are how many producers space words
+                            if adjustment < -100.0 && !text.ends_with(' ') {
+                                text.push(' ');
+                            }
+                            advance -= f64::from(adjustment) / 1000.0
+                                * state.font_size
+                                * state.horizontal_scaling;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    *text_matrix = multiply(&[1.0, 0.0, 0.0, 1.0, advance, 0.0], text_matrix);
+
+    if text.trim().is_empty() {
+        return None;
+    }
+
+    let (x, y) = apply(&start, 0.0, state.rise);
+    let (end_x, _) = apply(&start, advance, state.rise);
+    let scale = (start[2] * start[2] + start[3] * start[3]).sqrt();
+
+    Some(TextRun {
+        text,
+        x: x.min(end_x),
+        y,
+        width: (end_x - x).abs(),
+        font_size: state.font_size * scale,
+        emphasis: font.emphasis.rendered(state.render_mode),
+    })
+}
+
+fn show_string(
+    state: &GraphicsState,
+    font: &FontInfo,
+    bytes: &[u8],
+    text: &mut String,
+    advance: &mut f64,
+) {
+    text.push_str(&font.decode(bytes));
+
+    for code in font.codes(bytes) {
+        // word spacing only applies to the single-byte code 32
+        let word_spacing = if !font.two_byte && code == 32 {
+            state.word_spacing
+        } else {
+            0.0
+        };
+        *advance +=
+            (font.glyph_width(code) / 1000.0 * state.font_size + state.char_spacing + word_spacing)
+                * state.horizontal_scaling;
+    }
+}
+
+/// The `/MCID` in the properties of a `BDC` operation, which are either inline or a named entry
+/// of the `/Properties` resources.
+fn marked_content_id(doc: &Document, resources: &Dictionary, operation: &Operation) -> Option<i64> {
+    let properties = match operation.operands.get(1)? {
+        Object::Dictionary(properties) => properties,
+        Object::Name(name) => resources
+            .get_deref(b"Properties", doc)
+            .and_then(Object::as_dict)
+            .and_then(|properties| properties.get_deref(name, doc))
+            .and_then(Object::as_dict)
+            .ok()?,
+        _ => return None,
+    };
+
+    properties.get(b"MCID").and_then(Object::as_i64).ok()
+}
+
+fn draw_xobject(
+    doc: &Document,
+    resources: &Dictionary,
+    name: &[u8],
+    ctm: &Matrix,
+    mcid: Option<i64>,
+    depth: usize,
+    page: &mut PageContent,
+) {
+    let Ok(reference) = resources
+        .get_deref(b"XObject", doc)
+        .and_then(Object::as_dict)
+        .and_then(|xobjects| xobjects.get(name))
+    else {
+        return;
+    };
+    let Ok((id, Object::Stream(form))) = doc.dereference(reference) else {
+        return;
+    };
+
+    match form.dict.get(b"Subtype").and_then(Object::as_name) {
+        Ok(b"Form") => {}
+        Ok(b"Image") => {
+            // streams are always indirect objects, so this only skips malformed resources
+            if let Some(id) = id {
+                page.images.push(ImagePlacement {
+                    id,
+                    matrix: *ctm,
+                    mcid,
+                    alt: None,
+                });
+            }
+            return;
+        }
+        _ => return,
+    }
+
+    let Ok(content) = form_content(form) else {
+        return;
+    };
+
+    let matrix = form
+        .dict
+        .get(b"Matrix")
+        .and_then(Object::as_array)
+        .ok()
+        .and_then(|values| {
+            let values: Vec<f64> = values
+                .iter()
+                .filter_map(|value| value.as_float().ok().map(f64::from))
+                .collect();
+            values.try_into().ok()
+        })
+        .unwrap_or(IDENTITY);
+    let form_resources = form
+        .dict
+        .get_deref(b"Resources", doc)
+        .and_then(Object::as_dict)
+        .unwrap_or(resources);
+
+    interpret(
+        doc,
+        &content,
+        form_resources,
+        multiply(&matrix, ctm),
+        depth + 1,
+        page,
+    );
+}
+
+/// Decodes the content stream of the form XObject `form`.
+pub(super) fn form_content(form: &Stream) -> lopdf::Result<Content> {
+    form.decompressed_content()
+        .or_else(|_| Ok::<_, lopdf::Error>(form.content.clone()))
+        .and_then(|data| Content::decode(&data))
+}
+
+/// `m1 × m2`, so that the result applies `m1` first
+pub(super) fn multiply(m1: &Matrix, m2: &Matrix) -> Matrix {
+    [
+        m1[0] * m2[0] + m1[1] * m2[2],
+        m1[0] * m2[1] + m1[1] * m2[3],
+        m1[2] * m2[0] + m1[3] * m2[2],
+        m1[2] * m2[1] + m1[3] * m2[3],
+        m1[4] * m2[0] + m1[5] * m2[2] + m2[4],
+        m1[4] * m2[1] + m1[5] * m2[3] + m2[5],
+    ]
+}
+
+pub(super) fn apply(m: &Matrix, x: f64, y: f64) -> (f64, f64) {
+    (m[0] * x + m[2] * y + m[4], m[1] * x + m[3] * y + m[5])
+}
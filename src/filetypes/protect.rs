@@ -0,0 +1,202 @@
+use std::collections::HashSet;
+
+use regex::{Captures, Regex};
+
+/// Letters that open and close the tokens spans are swapped for. Translation services carry such
+/// runs of capitals and digits over as they are, like product codes, where they translate or
+/// respace words and punctuation.
+const TOKEN_OPEN: &str = "ZQX";
+const TOKEN_CLOSE: &str = "XQZ";
+
+/// Spans of a text that are swapped for opaque tokens before it is sent for translation, so that
+/// the provider can't translate or rearrange them, and put back in place in the translation
+pub(crate) struct ProtectedSpans {
+    /// What the tokens start with, which is made longer if the text already has it
+    open: String,
+    /// The spans, by the number of their token
+    spans: Vec<String>,
+    /// Matches the tokens, including with the case or spacing translation services may change
+    tokens: Regex,
+}
+
+impl ProtectedSpans {
+    /// Swaps the spans of `text` that `patterns` match for tokens. The patterns are applied in
+    /// order, so a span of a later pattern may hold the tokens of earlier ones; it is kept with the
+    /// spans they stand for.
+    pub(crate) fn protect(text: &str, patterns: &[Regex]) -> (String, Self) {
+        let mut open = TOKEN_OPEN.to_string();
+        while text.to_ascii_uppercase().contains(&open) {
+            open.push('J');
+        }
+        let tokens = Regex::new(&format!(r"(?i){open}\s*(\d+)\s*{TOKEN_CLOSE}")).unwrap();
+        let mut protected = Self {
+            open,
+            spans: vec![],
+            tokens,
+        };
+
+        let mut text = text.to_string();
+        for pattern in patterns {
+            text = pattern
+                .replace_all(&text, |captures: &Captures| {
+                    if captures[0].is_empty() {
+                        return String::new();
+                    }
+                    let span = protected.expand(&captures[0]);
+                    protected.spans.push(span);
+                    protected.token(protected.spans.len() - 1)
+                })
+                .into_owned();
+        }
+
+        (text, protected)
+    }
+
+    /// Whether `text` holds nothing but tokens and whitespace, and so has nothing to translate.
+    pub(crate) fn is_opaque(&self, text: &str) -> bool {
+        !self.spans.is_empty() && self.tokens.replace_all(text, "").trim().is_empty()
+    }
+
+    /// Puts the spans back in place of their tokens in `translation`, the translation of the
+    /// document `source`. The spans of tokens that the provider dropped are lost, and are listed
+    /// in a warning; those of tokens it repeated are only put back once.
+    pub(crate) fn restore(&self, translation: &str, source: &str) -> String {
+        if self.spans.is_empty() {
+            return translation.to_string();
+        }
+
+        let mut restored = HashSet::new();
+        let translation = self
+            .tokens
+            .replace_all(translation, |captures: &Captures| {
+                let id = captures[1].parse::<usize>().ok();
+                match id.and_then(|id| Some((id, self.spans.get(id)?))) {
+                    Some((id, span)) if restored.insert(id) => span.clone(),
+                    Some(_) => String::new(),
+                    None => captures[0].to_string(),
+                }
+            })
+            .into_owned();
+
+        let lost: Vec<String> = (0..self.spans.len())
+            .filter(|id| !restored.contains(id))
+            .map(|id| format!("{} (`{}`)", self.token(id), excerpt(&self.spans[id])))
+            .collect();
+        if !lost.is_empty() {
+            tracing::warn!(
+                "{} protected spans were lost in the translation of {source}: {}",
+                lost.len(),
+                lost.join(", ")
+            );
+        }
+
+        translation
+    }
+
+    fn token(&self, id: usize) -> String {
+        format!("{}{id}{TOKEN_CLOSE}", self.open)
+    }
+
+    /// `text` with the tokens of the spans protected so far replaced by the spans.
+    fn expand(&self, text: &str) -> String {
+        self.tokens
+            .replace_all(text, |captures: &Captures| {
+                captures[1]
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|id| self.spans.get(id))
+                    .map_or_else(|| captures[0].to_string(), String::clone)
+            })
+            .into_owned()
+    }
+}
+
+/// The start of `span`, short enough to be shown in a warning.
+fn excerpt(span: &str) -> String {
+    const LENGTH: usize = 60;
+
+    let span = span.split_whitespace().collect::<Vec<_>>().join(" ");
+    match span.char_indices().nth(LENGTH) {
+        Some((end, _)) => format!("{}...", &span[..end]),
+        None => span,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protect(text: &str) -> (String, ProtectedSpans) {
+        ProtectedSpans::protect(text, &[Regex::new(r"\{\w+\}").unwrap()])
+    }
+
+    #[test]
+    fn restores_the_spans_of_the_tokens() {
+        let (text, spans) = protect("Hello {name}, you have {count} messages");
+        assert_eq!(text, "Hello ZQX0XQZ, you have ZQX1XQZ messages");
+
+        let translation = "Hallo ZQX0XQZ, du hast ZQX1XQZ Nachrichten";
+        assert_eq!(
+            spans.restore(translation, "test"),
+            "Hallo {name}, du hast {count} Nachrichten"
+        );
+    }
+
+    #[test]
+    fn restores_tokens_with_changed_case_and_spacing() {
+        let (_, spans) = protect("{a} and {b}");
+        assert_eq!(
+            spans.restore("zqx0xqz und ZQX 1 XQZ", "test"),
+            "{a} und {b}"
+        );
+    }
+
+    #[test]
+    fn drops_the_spans_of_lost_tokens() {
+        let (_, spans) = protect("{a} and {b}");
+        assert_eq!(spans.restore("ZQX1XQZ allein", "test"), "{b} allein");
+    }
+
+    #[test]
+    fn restores_repeated_tokens_once() {
+        let (_, spans) = protect("{a} again");
+        assert_eq!(
+            spans.restore("ZQX0XQZ wieder ZQX0XQZ", "test"),
+            "{a} wieder "
+        );
+    }
+
+    #[test]
+    fn keeps_tokens_without_a_span() {
+        let (_, spans) = protect("{a}");
+        assert_eq!(spans.restore("ZQX0XQZ ZQX7XQZ", "test"), "{a} ZQX7XQZ");
+    }
+
+    #[test]
+    fn lengthens_the_tokens_if_the_text_has_them() {
+        let (text, spans) = protect("Code zqx1 for {name}");
+        assert_eq!(text, "Code zqx1 for ZQXJ0XQZ");
+        assert_eq!(spans.restore(&text, "test"), "Code zqx1 for {name}");
+    }
+
+    #[test]
+    fn keeps_spans_that_hold_earlier_ones() {
+        let patterns = [
+            Regex::new(r"\{\w+\}").unwrap(),
+            Regex::new(r"<b>.*?</b>").unwrap(),
+        ];
+        let (text, spans) = ProtectedSpans::protect("<b>{name}</b>!", &patterns);
+        assert_eq!(text, "ZQX1XQZ!");
+        assert_eq!(spans.restore(&text, "test"), "<b>{name}</b>!");
+    }
+
+    #[test]
+    fn tells_opaque_texts() {
+        let (_, spans) = protect("{a} {b} text");
+        assert!(spans.is_opaque("ZQX0XQZ ZQX1XQZ"));
+        assert!(!spans.is_opaque("ZQX0XQZ text"));
+
+        let (_, spans) = protect("no spans");
+        assert!(!spans.is_opaque(""));
+    }
+}
@@ -1,6 +1,12 @@
 use std::path::PathBuf;
 
 use clap::Parser;
+use lopdf::Permissions;
+
+use crate::{
+    filetypes::pdf::parse_permissions,
+    options::{InlineMarkup, PdfMode, ProtectList, ProtectedKind, Selection, SkipList},
+};
 
 pub enum Provider {
     GoogleTranslate {
@@ -42,6 +48,157 @@ pub struct Args {
     #[arg(long)]
     pub to: String,
 
+    /// How translated PDFs are laid out
+    #[arg(long, value_enum, default_value_t = PdfMode::Reflow)]
+    pub pdf_mode: PdfMode,
+
+    /// PDF pages to translate, such as `1-10,45`
+    #[arg(long, value_parser = Selection::parse_pages)]
+    pub pages: Option<Selection>,
+
+    /// EPUB chapters to translate, by position in the reading order such as `1-3,7` or by spine
+    /// item id
+    #[arg(long, value_parser = Selection::parse_chapters)]
+    pub chapters: Option<Selection>,
+
+    /// How the inline markup of EPUB paragraphs is sent for translation: `html` for translation
+    /// APIs with an HTML mode, `tokens` for language models
+    #[arg(long, value_enum, default_value_t = InlineMarkup::Html)]
+    pub markup: InlineMarkup,
+
+    /// CSS selectors of EPUB content to leave untranslated, such as `.verse, #colophon`, on top of
+    /// the elements, attributes and classes below
+    #[arg(long, value_name = "SELECTORS", value_parser = SkipList::parse_selector)]
+    pub skip: Vec<String>,
+
+    /// Elements of EPUB content whose text is left untranslated; an empty list translates them all
+    #[arg(
+        long,
+        value_name = "NAMES",
+        value_delimiter = ',',
+        default_value = "script,style,pre,code,kbd,samp,math"
+    )]
+    pub skip_elements: Vec<String>,
+
+    /// Attributes that mark EPUB content as untranslatable, with the value they need to have, such
+    /// as `translate=no`
+    #[arg(
+        long,
+        value_name = "NAME=VALUE",
+        value_delimiter = ',',
+        default_value = "translate=no",
+        value_parser = SkipList::parse_attribute
+    )]
+    pub skip_attributes: Vec<String>,
+
+    /// Classes that mark EPUB content as untranslatable
+    #[arg(
+        long,
+        value_name = "CLASSES",
+        value_delimiter = ',',
+        default_value = "notranslate"
+    )]
+    pub skip_classes: Vec<String>,
+
+    /// Kinds of EPUB markup kept out of the text sent for translation and put back in place
+    /// afterwards
+    #[arg(
+        long,
+        value_name = "KINDS",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "page-breaks,note-refs,math,svg"
+    )]
+    pub protect: Vec<ProtectedKind>,
+
+    /// Regular expression matching further spans of EPUB content to keep out of translation, such
+    /// as `\{\{[^}]*\}\}`
+    #[arg(long, value_name = "REGEX", value_parser = ProtectList::parse_pattern)]
+    pub protect_pattern: Vec<String>,
+
+    /// Attributes of EPUB content whose values are translated, such as `alt,title,aria-label`
+    #[arg(
+        long,
+        value_name = "NAMES",
+        value_delimiter = ',',
+        default_value = "alt,title,aria-label"
+    )]
+    pub attributes: Vec<String>,
+
+    /// Add styles to translated EPUB documents that align their text to the right when the target
+    /// language is written from right to left
+    #[arg(long)]
+    pub rtl_style: bool,
+
+    /// Translate the title and description of EPUBs
+    #[arg(long)]
+    pub translate_metadata: bool,
+
+    /// Translation engine recorded as a translator of translated EPUBs
+    #[arg(long, value_name = "NAME", default_value = "Google Translate")]
+    pub translator: String,
+
+    /// Fail if the output EPUB doesn't pass validation, rather than only reporting the problems
+    /// found
+    #[arg(long)]
+    pub fail_on_invalid: bool,
+
+    /// Leave the pages or chapters that are not selected out of the output instead of copying them
+    /// untranslated
+    #[arg(long)]
+    pub omit_unselected: bool,
+
+    /// Write a copy of the input PDF with the detected text blocks outlined to this path
+    #[arg(long)]
+    pub layout_debug: Option<PathBuf>,
+
+    /// Justify the paragraphs of reflowed PDFs instead of setting them ragged right
+    #[arg(long)]
+    pub justify: bool,
+
+    /// Header of every reflowed PDF page, such as `{title}`, with `{title}`, `{chapter}`, `{page}`,
+    /// `{pages}`, `{running_header}` and `{running_footer}` filled in
+    #[arg(long, value_name = "TEMPLATE")]
+    pub header: Option<String>,
+
+    /// Footer of every reflowed PDF page, such as `{page} of {pages}`, with the same placeholders
+    /// as the header
+    #[arg(long, value_name = "TEMPLATE")]
+    pub footer: Option<String>,
+
+    /// Recognize the text of scanned PDF pages with Tesseract
+    #[arg(long)]
+    pub ocr: bool,
+
+    /// Languages of the scanned text, in Tesseract's notation, such as `eng` or `deu+fra`
+    #[arg(long, default_value = "eng")]
+    pub ocr_languages: String,
+
+    /// Path to the Tesseract program
+    #[arg(long, default_value = "tesseract")]
+    pub tesseract: PathBuf,
+
+    /// Password of an encrypted input PDF
+    #[arg(long)]
+    pub password: Option<String>,
+
+    /// Encrypt the output PDF with AES-256, with the input's password and permissions unless they
+    /// are given below
+    #[arg(long)]
+    pub encrypt: bool,
+
+    /// Password needed to open the encrypted output PDF
+    #[arg(long, requires = "encrypt")]
+    pub user_password: Option<String>,
+
+    /// Password needed to change the permissions of the encrypted output PDF
+    #[arg(long, requires = "encrypt")]
+    pub owner_password: Option<String>,
+
+    /// What readers of the encrypted output PDF may do, such as `print,copy`
+    #[arg(long, requires = "encrypt", value_parser = parse_permissions)]
+    pub permissions: Option<Permissions>,
+
     /// Enable verbose mode
     #[arg(short, long)]
     pub verbose: bool,
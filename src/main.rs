@@ -4,14 +4,14 @@ mod options;
 mod providers;
 
 use filetypes::{
-    epub::{edit_epub, read_epub, write_epub},
-    pdf::{edit_pdf, read_pdf, write_pdf},
+    epub::{edit_epub, read_epub, validate_epub, write_epub},
+    pdf::{edit_pdf, read_pdf, write_pdf, OcrBackend, PdfEncryption, TesseractCommand},
 };
 use frontend::{
     cli::Args,
     tui::{handle_event, render_app_state, AppState},
 };
-use options::{PdfOptions, RequestOptions};
+use options::{EpubOptions, PdfOptions, ProtectList, RequestOptions, SkipList};
 use providers::{google::translate_text, llm::translate};
 
 use std::{
@@ -100,23 +100,88 @@ async fn main() -> Result<()> {
 
     match file_type {
         FileType::PDF => {
-            let doc = read_pdf(&args.input)?;
-            let pdf_options = PdfOptions::default();
+            let doc = read_pdf(&args.input, args.password.as_deref())?;
+            let encryption = args.encrypt.then(|| {
+                let mut encryption = PdfEncryption::like_source(&doc, args.password.as_deref());
+                if let Some(password) = &args.user_password {
+                    encryption.user_password = password.clone();
+                }
+                if let Some(password) = &args.owner_password {
+                    encryption.owner_password = password.clone();
+                }
+                if let Some(permissions) = args.permissions {
+                    encryption.permissions = permissions;
+                }
+                encryption
+            });
+            let pdf_options = PdfOptions {
+                mode: args.pdf_mode,
+                language: Some(args.to.clone()),
+                pages: args.pages.clone(),
+                omit_unselected: args.omit_unselected,
+                layout_debug: args.layout_debug.clone(),
+                justify: args.justify,
+                header: args.header.clone(),
+                footer: args.footer.clone(),
+                ocr: args.ocr.then(|| {
+                    Box::new(TesseractCommand::new(&args.tesseract, &args.ocr_languages))
+                        as Box<dyn OcrBackend>
+                }),
+                ..Default::default()
+            };
             let edited = edit_pdf(doc, request_options, pdf_options, |snippets| {
                 // translate_text(snippets, args.to.clone(), api_key.clone())
                 std::future::ready(Ok(snippets))
             })
             .await?;
-            write_pdf(edited, &args.output)?;
+            write_pdf(edited, &args.output, encryption.as_ref())?;
         }
         FileType::EPUB => {
             let doc = read_epub(&args.input)?;
-            let edited = edit_epub(doc, request_options, |snippets| {
+            let epub_options = EpubOptions {
+                chapters: args.chapters.clone(),
+                omit_unselected: args.omit_unselected,
+                markup: args.markup,
+                skip: SkipList {
+                    elements: non_empty(&args.skip_elements)
+                        .map(|name| name.to_ascii_lowercase())
+                        .collect(),
+                    attributes: non_empty(&args.skip_attributes)
+                        .filter_map(|attribute| {
+                            let (name, value) = attribute.split_once('=')?;
+                            Some((name.trim().to_string(), value.trim().to_string()))
+                        })
+                        .collect(),
+                    classes: non_empty(&args.skip_classes).map(str::to_string).collect(),
+                    selectors: args.skip.clone(),
+                },
+                protect: ProtectList {
+                    kinds: args.protect.clone(),
+                    patterns: args.protect_pattern.clone(),
+                },
+                attributes: args.attributes.clone(),
+                language: Some(args.to.clone()),
+                rtl_style: args.rtl_style,
+                translate_metadata: args.translate_metadata,
+                translator: Some(args.translator.clone()),
+            };
+            let edited = edit_epub(doc, request_options, epub_options, |snippets| {
                 // translate_text(snippets, args.to.clone(), api_key.clone())
                 std::future::ready(Ok(snippets))
             })
             .await?;
-            write_epub(edited, &args.output)?;
+            write_epub(edited, &args.input, &args.output)?;
+
+            let issues = validate_epub(&args.output)?;
+            for issue in &issues {
+                tracing::warn!("{issue}");
+            }
+            if args.fail_on_invalid && !issues.is_empty() {
+                return Err(eyre!(
+                    "The output EPUB has {} problems that reading systems may reject it for",
+                    issues.len()
+                ));
+            }
         }
         FileType::Unsupported => tracing::info!("File type not currently supported"),
     }
@@ -124,6 +189,14 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// The items of a list given on the command line, where `--option ""` stands for an empty list.
+fn non_empty(items: &[String]) -> impl Iterator<Item = &str> {
+    items
+        .iter()
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+}
+
 fn get_file_type(path: &str) -> Result<FileType> {
     let mut file = File::open(path)?;
     let mut buffer = [0; 4];
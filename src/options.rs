@@ -1,3 +1,9 @@
+use std::{ops::RangeInclusive, path::PathBuf};
+
+use clap::ValueEnum;
+
+use crate::filetypes::pdf::OcrBackend;
+
 pub struct RequestOptions {
     pub batch_size: usize,
     pub max_concurrency: usize,
@@ -12,7 +18,85 @@ impl Default for RequestOptions {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PdfMode {
+    /// Extract the text and lay it out again on new pages
+    Reflow,
+    /// Keep the source pages and replace their text in place
+    Preserve,
+    /// Leave the source pages untouched and add the translation of each block as an annotation
+    Annotate,
+}
+
+/// Pages or chapters to translate, given as 1-based numbers and ranges such as `1-10,45`, and for
+/// EPUB chapters also as spine item ids
+#[derive(Clone, Debug, Default)]
+pub struct Selection {
+    ranges: Vec<RangeInclusive<usize>>,
+    ids: Vec<String>,
+}
+
+impl Selection {
+    pub fn parse_pages(value: &str) -> Result<Self, String> {
+        Self::parse(value, false)
+    }
+
+    pub fn parse_chapters(value: &str) -> Result<Self, String> {
+        Self::parse(value, true)
+    }
+
+    fn parse(value: &str, allow_ids: bool) -> Result<Self, String> {
+        let mut selection = Self::default();
+
+        for item in value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            let (start, end) = item.split_once('-').unwrap_or((item, item));
+            match (start.trim().parse::<usize>(), end.trim().parse::<usize>()) {
+                (Ok(start), Ok(end)) if start >= 1 && start <= end => {
+                    selection.ranges.push(start..=end)
+                }
+                (Ok(_), Ok(_)) => return Err(format!("invalid range `{item}`")),
+                _ if allow_ids => selection.ids.push(item.to_string()),
+                _ => return Err(format!("`{item}` is not a page number or range")),
+            }
+        }
+
+        if selection.ranges.is_empty() && selection.ids.is_empty() {
+            return Err("nothing selected".to_string());
+        }
+
+        Ok(selection)
+    }
+
+    /// Whether the page or chapter at 1-based position `number`, with the id `id` if it has one,
+    /// is selected.
+    pub fn contains(&self, number: usize, id: Option<&str>) -> bool {
+        self.ranges.iter().any(|range| range.contains(&number))
+            || id.is_some_and(|id| self.ids.iter().any(|selected| selected == id))
+    }
+}
+
 pub struct PdfOptions {
+    pub mode: PdfMode,
+    pub language: Option<String>,
+    /// Pages to translate, or all of them if `None`
+    pub pages: Option<Selection>,
+    /// Leave the pages that are not selected out of the output rather than copying them
+    /// untranslated
+    pub omit_unselected: bool,
+    /// Where to write a copy of the source with the detected text blocks drawn over it
+    pub layout_debug: Option<PathBuf>,
+    /// Recognizes the text of pages that only hold images, such as scans
+    pub ocr: Option<Box<dyn OcrBackend>>,
+    /// Justify reflowed paragraphs instead of setting them ragged right
+    pub justify: bool,
+    /// Templates of the text set at the top and bottom of every reflowed page, in which `{title}`,
+    /// `{chapter}`, `{page}`, `{pages}`, `{running_header}` and `{running_footer}` are filled in
+    pub header: Option<String>,
+    pub footer: Option<String>,
     pub max_width: f64,
     pub line_height: f64,
     pub paragraph_spacing: f64,
@@ -25,6 +109,15 @@ pub struct PdfOptions {
 impl Default for PdfOptions {
     fn default() -> Self {
         Self {
+            mode: PdfMode::Reflow,
+            language: None,
+            pages: None,
+            omit_unselected: false,
+            layout_debug: None,
+            ocr: None,
+            justify: false,
+            header: None,
+            footer: None,
             max_width: 500.0,
             line_height: 14.0,
             paragraph_spacing: 20.0,
@@ -35,3 +128,159 @@ impl Default for PdfOptions {
         }
     }
 }
+
+impl PdfOptions {
+    pub fn is_page_selected(&self, page_num: u32) -> bool {
+        self.pages
+            .as_ref()
+            .is_none_or(|pages| pages.contains(page_num as usize, None))
+    }
+}
+
+/// How the inline markup within EPUB paragraphs is written in the text sent to the provider
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum InlineMarkup {
+    /// As placeholder tags, which providers with an HTML mode such as Google Translate and DeepL
+    /// move along with the words they enclose
+    #[default]
+    Html,
+    /// As numbered tokens such as `[[1]]...[[/1]]`, for language models
+    Tokens,
+}
+
+/// EPUB content that is kept as it is rather than translated
+#[derive(Clone, Debug)]
+pub struct SkipList {
+    /// Elements such as `code` whose content is never translated
+    pub elements: Vec<String>,
+    /// Attributes that mark an element as untranslatable, with the value they need to have, such
+    /// as `translate="no"`
+    pub attributes: Vec<(String, String)>,
+    /// Classes that mark an element as untranslatable
+    pub classes: Vec<String>,
+    /// CSS selectors of further elements to leave untranslated
+    pub selectors: Vec<String>,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self {
+            elements: ["script", "style", "pre", "code", "kbd", "samp", "math"]
+                .map(String::from)
+                .to_vec(),
+            attributes: vec![("translate".to_string(), "no".to_string())],
+            classes: vec!["notranslate".to_string()],
+            selectors: vec![],
+        }
+    }
+}
+
+impl SkipList {
+    /// Checks that `value` is an attribute that marks content as untranslatable, given as
+    /// `name=value`, or empty.
+    pub fn parse_attribute(value: &str) -> Result<String, String> {
+        match value.split_once('=') {
+            Some((name, _)) if !name.trim().is_empty() => Ok(value.to_string()),
+            None if value.trim().is_empty() => Ok(String::new()),
+            _ => Err(format!(
+                "`{value}` is not an attribute given as `name=value`"
+            )),
+        }
+    }
+
+    /// Checks that `value` is a CSS selector list that can be matched against EPUB content.
+    pub fn parse_selector(value: &str) -> Result<String, String> {
+        match tl::parse_query_selector(value) {
+            Some(_) => Ok(value.to_string()),
+            None => Err(format!("`{value}` is not a supported CSS selector")),
+        }
+    }
+}
+
+/// Kinds of markup that are swapped for opaque tokens while the text around them is translated, and
+/// put back in place afterwards
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ProtectedKind {
+    /// Page break markers, such as `<span epub:type="pagebreak" title="12"/>`
+    PageBreaks,
+    /// References to footnotes and endnotes, such as `<a epub:type="noteref" href="#n1">1</a>`
+    NoteRefs,
+    /// MathML formulas
+    Math,
+    /// Inline SVG images
+    Svg,
+}
+
+/// Spans of EPUB content that are kept out of the text sent for translation, so that the provider
+/// can't alter them
+#[derive(Clone, Debug)]
+pub struct ProtectList {
+    pub kinds: Vec<ProtectedKind>,
+    /// Regular expressions matching further spans to protect
+    pub patterns: Vec<String>,
+}
+
+impl Default for ProtectList {
+    fn default() -> Self {
+        Self {
+            kinds: vec![
+                ProtectedKind::PageBreaks,
+                ProtectedKind::NoteRefs,
+                ProtectedKind::Math,
+                ProtectedKind::Svg,
+            ],
+            patterns: vec![],
+        }
+    }
+}
+
+impl ProtectList {
+    /// Checks that `value` is a regular expression that spans can be matched with.
+    pub fn parse_pattern(value: &str) -> Result<String, String> {
+        match regex::Regex::new(value) {
+            Ok(_) => Ok(value.to_string()),
+            Err(error) => Err(format!(
+                "`{value}` is not a valid regular expression: {error}"
+            )),
+        }
+    }
+}
+
+pub struct EpubOptions {
+    /// Chapters to translate, or all of them if `None`
+    pub chapters: Option<Selection>,
+    /// Leave the chapters that are not selected out of the output rather than copying them
+    /// untranslated. The navigation document, the cover and the chapters that the kept ones link
+    /// to are kept all the same.
+    pub omit_unselected: bool,
+    pub markup: InlineMarkup,
+    pub skip: SkipList,
+    pub protect: ProtectList,
+    /// Attributes whose values are text to translate, such as the `alt` text of images
+    pub attributes: Vec<String>,
+    pub language: Option<String>,
+    /// Add styles that align the text of translated documents to the right when `language` is
+    /// written from right to left
+    pub rtl_style: bool,
+    /// Translate the title and description of the book along with its content
+    pub translate_metadata: bool,
+    /// Name of the translation engine, recorded as a translator of the book
+    pub translator: Option<String>,
+}
+
+impl Default for EpubOptions {
+    fn default() -> Self {
+        Self {
+            chapters: None,
+            omit_unselected: false,
+            markup: InlineMarkup::default(),
+            skip: SkipList::default(),
+            protect: ProtectList::default(),
+            attributes: ["alt", "title", "aria-label"].map(String::from).to_vec(),
+            language: None,
+            rtl_style: false,
+            translate_metadata: false,
+            translator: None,
+        }
+    }
+}